@@ -0,0 +1,164 @@
+// Archives are written to a temporary file and renamed over the destination once complete,
+// so a failed or interrupted encode never leaves a truncated archive behind.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::log;
+
+// Numbers the temporary files made by this process, so that no two of them collide.
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+/// The directory the temporary files for `dest` go in: the one `dest` is in, or `temp_dir`.
+fn temp_dir_of(dest: &Path, temp_dir: Option<&Path>) -> PathBuf {
+    match (temp_dir, dest.parent()) {
+        (Some(dir), _) => dir.to_path_buf(),
+        (None, Some(dir)) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        (None, _) => PathBuf::from("."),
+    }
+}
+
+fn temp_prefix(dest: &Path) -> OsString {
+    let mut prefix = dest.file_name().unwrap_or_default().to_os_string();
+    prefix.push(".");
+    prefix
+}
+
+/// Whether `path` is a temporary file for `dest`, `<dest>.<pid>.<n>.tmp` in the directory
+/// the temporary files go in, which encode leaves out of the archive like `dest` itself.
+pub fn is_temp_of(dest: &Path, temp_dir: Option<&Path>, path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let numbers = name
+        .strip_prefix(&*temp_prefix(dest).to_string_lossy())
+        .and_then(|rest| rest.strip_suffix(".tmp"))
+        .and_then(|rest| rest.split_once('.'));
+    let numeric = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !numbers.is_some_and(|(pid, n)| numeric(pid) && numeric(n)) {
+        return false;
+    }
+    let dir = temp_dir_of(dest, temp_dir);
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    match (fs::canonicalize(parent), fs::canonicalize(dir)) {
+        (Ok(parent), Ok(dir)) => parent == dir,
+        _ => false,
+    }
+}
+
+/// Creates a new temporary file for `dest`, `<dest>.<pid>.<n>.tmp` next to it or in
+/// `temp_dir`. The name is never one that exists already, so neither a file of the user's
+/// nor another writer's temporary file is overwritten.
+fn create_temp(dest: &Path, temp_dir: Option<&Path>) -> io::Result<(File, PathBuf)> {
+    let dir = temp_dir_of(dest, temp_dir);
+    loop {
+        let mut name = temp_prefix(dest);
+        let n = CREATED.fetch_add(1, Ordering::SeqCst);
+        name.push(format!("{}.{}.tmp", process::id(), n));
+        let temp = dir.join(name);
+        match OpenOptions::new().write(true).create_new(true).open(&temp) {
+            Ok(file) => return Ok((file, temp)),
+            // Left by an earlier process with the same pid; the next number is free to take.
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A file that shows up at `dest` only once `commit` succeeds. Dropping it uncommitted
+/// removes the temporary file.
+pub struct AtomicFile {
+    // Closed before the rename, which Windows refuses on an open file.
+    file: Option<File>,
+    temp: PathBuf,
+    dest: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn create(dest: &Path, temp_dir: Option<&Path>) -> io::Result<AtomicFile> {
+        let (file, temp) = create_temp(dest, temp_dir)?;
+        Ok(AtomicFile {
+            file: Some(file),
+            temp,
+            dest: dest.to_path_buf(),
+            committed: false,
+        })
+    }
+
+    /// Moves the finished file to `dest`. A rename can't cross filesystems, so a temporary
+    /// file on another one is copied instead, which isn't atomic.
+    pub fn commit(mut self) -> io::Result<()> {
+        if let Some(file) = self.file.take() {
+            file.sync_all()?;
+        }
+        match fs::rename(&self.temp, &self.dest) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                log::warn(format!(
+                    "{} is on another filesystem than {}, copying it into place instead of renaming, which isn't atomic",
+                    self.temp.display(),
+                    self.dest.display()
+                ));
+                fs::copy(&self.temp, &self.dest)?;
+                fs::remove_file(&self.temp)?;
+            }
+            Err(e) => return Err(e),
+        }
+        self.committed = true;
+        Ok(())
+    }
+
+    fn file(&mut self) -> &mut File {
+        self.file.as_mut().expect("written after commit")
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file().flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.file.take();
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::Scratch;
+
+    #[test]
+    fn temporary_files_are_new_and_leave_others_alone() {
+        let scratch = Scratch::create("atomic").unwrap();
+        let dest = scratch.path.join("out.out");
+        let theirs = scratch.path.join("out.out.tmp");
+        fs::write(&theirs, "the user's\n").unwrap();
+        let mut first = AtomicFile::create(&dest, None).unwrap();
+        let second = AtomicFile::create(&dest, None).unwrap();
+        assert_ne!(first.temp, second.temp);
+        assert!(is_temp_of(&dest, None, &first.temp));
+        assert!(!is_temp_of(&dest, None, &theirs));
+        first.write_all(b"archive\n").unwrap();
+        first.commit().unwrap();
+        drop(second);
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "archive\n");
+        assert_eq!(fs::read_to_string(&theirs).unwrap(), "the user's\n");
+        assert_eq!(fs::read_dir(&scratch.path).unwrap().count(), 2);
+    }
+}
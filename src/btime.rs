@@ -0,0 +1,59 @@
+// Birth times, when a file was created, recorded under `--preserve-btime` and restored on
+// decode. Support is uneven: macOS, Windows and Linux (through `statx`, on filesystems that
+// keep one) can tell a file's birth time, but only macOS and Windows can set it. Where it
+// can't be read or set, that is warned about once and nothing more happens.
+
+use std::fs::{File, Metadata};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::UNIX_EPOCH;
+
+static WARNED_READ: AtomicBool = AtomicBool::new(false);
+#[cfg(not(any(target_os = "macos", windows)))]
+static WARNED_RESTORE: AtomicBool = AtomicBool::new(false);
+
+/// The birth time of a file in whole seconds since the epoch, where the platform and the
+/// filesystem have one.
+pub fn of(metadata: &Metadata) -> Option<u64> {
+    let created = metadata.created().ok()?;
+    Some(
+        created
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+    )
+}
+
+/// Warns, the first time only, that `path` has no birth time to record.
+pub fn warn_unreadable(path: &std::path::Path) {
+    if !WARNED_READ.swap(true, Ordering::Relaxed) {
+        crate::log::warn(format!(
+            "can't read the birth time of {} here, so --preserve-btime records none for it or any other file without one",
+            path.display()
+        ));
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn restore(file: &File, btime: u64) -> io::Result<()> {
+    use std::fs::FileTimes;
+    use std::os::macos::fs::FileTimesExt;
+    let created = UNIX_EPOCH + std::time::Duration::from_secs(btime);
+    file.set_times(FileTimes::new().set_created(created))
+}
+
+#[cfg(windows)]
+pub fn restore(file: &File, btime: u64) -> io::Result<()> {
+    use std::fs::FileTimes;
+    use std::os::windows::fs::FileTimesExt;
+    let created = UNIX_EPOCH + std::time::Duration::from_secs(btime);
+    file.set_times(FileTimes::new().set_created(created))
+}
+
+/// Birth times can't be set here, which is warned about the first time only.
+#[cfg(not(any(target_os = "macos", windows)))]
+pub fn restore(_: &File, _: u64) -> io::Result<()> {
+    if !WARNED_RESTORE.swap(true, Ordering::Relaxed) {
+        crate::log::warn("this platform can't set birth times, so the archive's aren't restored");
+    }
+    Ok(())
+}
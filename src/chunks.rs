@@ -0,0 +1,250 @@
+// `encode --chunk-manifest` and `mito assemble`: an archive cut into fixed-size chunks, each
+// stored under its hash, and a JSON manifest listing them in order, so that a transfer only
+// has to send the chunks the other side doesn't have yet. Chunks are cut at fixed offsets,
+// whatever the archive's mode; assemble puts the bytes back together and checks every chunk,
+// and then the whole archive, against the manifest.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::atomic::AtomicFile;
+use crate::{interrupt, json};
+
+/// The chunk size when `--chunk-size` isn't given.
+pub const DEFAULT_CHUNK_SIZE: u64 = 4 << 20;
+
+/// The layout of the manifest, its `version` member.
+const MANIFEST_VERSION: u64 = 1;
+
+/// One chunk of the archive, stored in the chunk directory under its hash.
+pub struct Chunk {
+    pub len: u64,
+    pub hash: String,
+}
+
+/// What `--chunk-manifest` writes: the archive's size and hash, and its chunks in order.
+pub struct Manifest {
+    pub size: u64,
+    pub chunk_size: u64,
+    pub hash: String,
+    pub chunks: Vec<Chunk>,
+}
+
+fn hash(data: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(data);
+    hasher.finish().to_string()
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Manifest {
+    /// `{"version": 1, "size": ..., "chunk_size": ..., "hash": "...", "chunks": [{"len": ...,
+    /// "hash": "..."}]}`, hashes in decimal like the entries' own.
+    pub fn to_json(&self) -> String {
+        let chunks: Vec<_> = self
+            .chunks
+            .iter()
+            .map(|chunk| {
+                format!(
+                    "{{\"len\":{},\"hash\":{}}}",
+                    chunk.len,
+                    json::string(&chunk.hash)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"version\":{},\"size\":{},\"chunk_size\":{},\"hash\":{},\"chunks\":[{}]}}\n",
+            MANIFEST_VERSION,
+            self.size,
+            self.chunk_size,
+            json::string(&self.hash),
+            chunks.join(",")
+        )
+    }
+
+    /// Parses what `to_json` writes. Members it doesn't know are ignored.
+    pub fn parse(text: &str) -> io::Result<Manifest> {
+        let value = json::parse(text).map_err(|e| invalid(format!("malformed manifest: {}", e)))?;
+        let missing = |what: &str| invalid(format!("manifest has no valid `{}`", what));
+        let version = value
+            .get("version")
+            .and_then(json::Value::as_u64)
+            .ok_or_else(|| missing("version"))?;
+        if version != MANIFEST_VERSION {
+            return Err(invalid(format!(
+                "manifest version {} isn't supported, only {}",
+                version, MANIFEST_VERSION
+            )));
+        }
+        let number = |key: &str| {
+            value
+                .get(key)
+                .and_then(json::Value::as_u64)
+                .ok_or_else(|| missing(key))
+        };
+        let string = |key: &str| {
+            value
+                .get(key)
+                .and_then(json::Value::as_str)
+                .map(String::from)
+                .ok_or_else(|| missing(key))
+        };
+        let chunks = value
+            .get("chunks")
+            .and_then(json::Value::as_array)
+            .ok_or_else(|| missing("chunks"))?
+            .iter()
+            .map(|chunk| {
+                let len = chunk.get("len").and_then(json::Value::as_u64);
+                let hash = chunk.get("hash").and_then(json::Value::as_str);
+                match (len, hash) {
+                    // The hash names a file, so it must be a plain number.
+                    (Some(len), Some(hash))
+                        if !hash.is_empty() && hash.bytes().all(|b| b.is_ascii_digit()) =>
+                    {
+                        Ok(Chunk {
+                            len,
+                            hash: hash.to_string(),
+                        })
+                    }
+                    _ => Err(missing("chunks[].len` and `chunks[].hash")),
+                }
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let manifest = Manifest {
+            size: number("size")?,
+            chunk_size: number("chunk_size")?,
+            hash: string("hash")?,
+            chunks,
+        };
+        if manifest.chunks.iter().map(|chunk| chunk.len).sum::<u64>() != manifest.size {
+            return Err(invalid(format!(
+                "manifest chunks don't add up to its size of {} bytes",
+                manifest.size
+            )));
+        }
+        Ok(manifest)
+    }
+}
+
+/// Where the chunks of `manifest` go without `--chunks`: a `chunks` directory next to it.
+pub fn default_dir(manifest: &Path) -> PathBuf {
+    match manifest.parent() {
+        Some(dir) => dir.join("chunks"),
+        None => PathBuf::from("chunks"),
+    }
+}
+
+/// Cuts the file at `archive` into chunks of `chunk_size` bytes, the last one shorter,
+/// writes each to `dir` under its hash unless it is there already, and the manifest to
+/// `manifest`. Returns the manifest and how many chunks were written rather than found.
+pub fn write_chunks(
+    archive: &Path,
+    manifest: &Path,
+    dir: &Path,
+    chunk_size: u64,
+) -> io::Result<(Manifest, usize)> {
+    fs::create_dir_all(dir)?;
+    let mut src = BufReader::new(File::open(archive)?);
+    let mut whole = DefaultHasher::new();
+    let mut chunks = Vec::new();
+    let mut written = HashSet::new();
+    let mut data = Vec::new();
+    loop {
+        interrupt::check()?;
+        data.clear();
+        (&mut src).take(chunk_size).read_to_end(&mut data)?;
+        if data.is_empty() {
+            break;
+        }
+        whole.write(&data);
+        let chunk = Chunk {
+            len: data.len() as u64,
+            hash: hash(&data),
+        };
+        let path = dir.join(&chunk.hash);
+        // A chunk is only ever complete under its name, so one of the right length is kept.
+        let present = fs::metadata(&path).is_ok_and(|m| m.len() == chunk.len);
+        if !present {
+            let mut file = AtomicFile::create(&path, None)?;
+            file.write_all(&data)?;
+            file.commit()?;
+            written.insert(chunk.hash.clone());
+        }
+        chunks.push(chunk);
+    }
+    let manifest_data = Manifest {
+        size: chunks.iter().map(|chunk| chunk.len).sum(),
+        chunk_size,
+        hash: whole.finish().to_string(),
+        chunks,
+    };
+    let mut file = AtomicFile::create(manifest, None)?;
+    file.write_all(manifest_data.to_json().as_bytes())?;
+    file.commit()?;
+    Ok((manifest_data, written.len()))
+}
+
+/// Puts the archive `manifest` describes back together at `output` from the chunks in
+/// `dir`, checking each one's length and hash and then the archive's. Fails before writing
+/// anything if chunks are missing, naming them.
+pub fn assemble(manifest: &Path, dir: &Path, output: &Path) -> io::Result<Manifest> {
+    let text = fs::read_to_string(manifest)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", manifest.display(), e)))?;
+    let manifest = Manifest::parse(&text)?;
+    let mut seen = HashSet::new();
+    let missing: Vec<&str> = manifest
+        .chunks
+        .iter()
+        .filter(|chunk| seen.insert(&chunk.hash) && !dir.join(&chunk.hash).is_file())
+        .map(|chunk| chunk.hash.as_str())
+        .collect();
+    if !missing.is_empty() {
+        const SHOWN: usize = 10;
+        let mut names = missing[..missing.len().min(SHOWN)].join(", ");
+        if missing.len() > SHOWN {
+            names.push_str(&format!(" and {} more", missing.len() - SHOWN));
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "{} of the {} chunks are missing from {}: {}",
+                missing.len(),
+                seen.len(),
+                dir.display(),
+                names
+            ),
+        ));
+    }
+    let mut out = AtomicFile::create(output, None)?;
+    let mut whole = DefaultHasher::new();
+    for chunk in &manifest.chunks {
+        interrupt::check()?;
+        let path = dir.join(&chunk.hash);
+        let data = fs::read(&path)?;
+        if data.len() as u64 != chunk.len || hash(&data) != chunk.hash {
+            return Err(invalid(format!(
+                "chunk {} is damaged: it doesn't match its length of {} bytes and hash",
+                path.display(),
+                chunk.len
+            )));
+        }
+        whole.write(&data);
+        out.write_all(&data)?;
+    }
+    if whole.finish().to_string() != manifest.hash {
+        return Err(invalid(format!(
+            "the chunks of {} put together don't match its hash",
+            output.display()
+        )));
+    }
+    out.commit()?;
+    Ok(manifest)
+}
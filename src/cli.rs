@@ -0,0 +1,782 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+pub struct Command {
+    pub name: &'static str,
+    pub help: &'static str,
+    // Hidden commands work but aren't advertised in completions.
+    pub hidden: bool,
+}
+
+pub struct Flag {
+    pub name: &'static str,
+    // Placeholder for the flag's value, or `None` for a switch.
+    pub value: Option<&'static str>,
+    pub help: &'static str,
+    pub commands: &'static [&'static str],
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "encode",
+        help: "archive the current directory, or the directories given",
+        hidden: false,
+    },
+    Command {
+        name: "decode",
+        help: "extract an archive",
+        hidden: false,
+    },
+    Command {
+        name: "list",
+        help: "print the paths stored in an archive",
+        hidden: false,
+    },
+    Command {
+        name: "diff",
+        help: "compare the entries of two archives",
+        hidden: false,
+    },
+    Command {
+        name: "verify",
+        help: "check every entry of an archive against its hash",
+        hidden: false,
+    },
+    Command {
+        name: "info",
+        help: "show what an archive's header records",
+        hidden: false,
+    },
+    Command {
+        name: "analyze",
+        help: "show what an encode would archive, by size",
+        hidden: false,
+    },
+    Command {
+        name: "assemble",
+        help: "put an archive back together from its --chunk-manifest chunks",
+        hidden: false,
+    },
+    Command {
+        name: "cat-archive",
+        help: "join plain, base64 or auto archives of one mode without re-encoding",
+        hidden: false,
+    },
+    Command {
+        name: "selftest",
+        help: "round-trip a built-in fixture in every mode",
+        hidden: false,
+    },
+    Command {
+        name: "completions",
+        help: "print a shell completion script",
+        hidden: true,
+    },
+];
+
+const ALL: &[&str] = &[
+    "encode",
+    "decode",
+    "list",
+    "diff",
+    "verify",
+    "info",
+    "analyze",
+    "assemble",
+    "cat-archive",
+    "selftest",
+];
+const CODING: &[&str] = &["encode", "decode", "list", "diff", "verify", "info"];
+// `verify --against` walks the tree it compares with like encode does.
+const WALK: &[&str] = &["encode", "analyze", "verify"];
+const ENCODE: &[&str] = &["encode"];
+const DECODE: &[&str] = &["decode"];
+
+// Every flag, and the commands it applies to. Parsing, usage and completions all come from
+// this table, so they can't drift apart.
+pub const FLAGS: &[Flag] = &[
+    Flag {
+        name: "-v",
+        value: None,
+        help: "log every archived or extracted file",
+        commands: ALL,
+    },
+    Flag {
+        name: "-vv",
+        value: None,
+        help: "also log every ignore decision",
+        commands: ALL,
+    },
+    Flag {
+        name: "--log-file",
+        value: Some("FILE"),
+        help: "also write warnings and verbose messages to FILE as JSON lines",
+        commands: ALL,
+    },
+    Flag {
+        name: "--plain",
+        value: None,
+        help: "store contents as-is",
+        commands: CODING,
+    },
+    Flag {
+        name: "--base64",
+        value: None,
+        help: "store contents base64 encoded",
+        commands: CODING,
+    },
+    Flag {
+        name: "--auto",
+        value: None,
+        help: "store text as-is and binaries base64 encoded",
+        commands: CODING,
+    },
+    Flag {
+        name: "--auto-sample",
+        value: Some("SIZE"),
+        help: "bytes --auto inspects per file",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--binary",
+        value: None,
+        help: "zlib compress the archive",
+        commands: CODING,
+    },
+    Flag {
+        name: "--text",
+        value: None,
+        help: "zlib compress the archive, then base64 encode it",
+        commands: CODING,
+    },
+    Flag {
+        name: "--output",
+        value: Some("PATH"),
+        help: "archive to write, - for stdout",
+        commands: &["encode", "assemble", "cat-archive"],
+    },
+    Flag {
+        name: "--level",
+        value: Some("0-9"),
+        help: "zlib compression level of the compressed modes",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--index",
+        value: None,
+        help: "end plain and base64 archives with an index of entry offsets",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--output-format",
+        value: Some("mito|tar|tar.gz"),
+        help: "format of the archive to write",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--dest",
+        value: Some("DIR"),
+        help: "directory to extract into",
+        commands: DECODE,
+    },
+    Flag {
+        name: "--relative-to",
+        value: Some("DIR"),
+        help: "store paths relative to DIR instead of under each source's name",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--order-from",
+        value: Some("FILE"),
+        help: "archive files in the order listed in FILE",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--hash-scope",
+        value: Some("content|path-content"),
+        help: "what each entry's hash covers",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--case-collision",
+        value: Some("error|rename|keep"),
+        help: "check for paths that differ only by case",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--threads",
+        value: Some("N"),
+        help: "maximum number of worker threads",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--preserve-mtime",
+        value: None,
+        help: "record modification times, restored on decode",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--preserve-btime",
+        value: None,
+        help: "record birth times, restored on decode where the platform can set them",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--preserve-ownership",
+        value: None,
+        help: "record numeric uid and gid, restored on decode as root",
+        commands: &["encode", "decode"],
+    },
+    Flag {
+        name: "--stored-hash",
+        value: None,
+        help: "also record a hash of each entry as stored, for verify --fast",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--base64-url",
+        value: None,
+        help: "write base64 in the URL and filename safe alphabet",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--sparse",
+        value: None,
+        help: "leave the holes of sparse files out of the archive",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--max-entries",
+        value: Some("N"),
+        help: "fail if there are more than N entries",
+        commands: &["encode", "decode"],
+    },
+    Flag {
+        name: "--ignore-errors",
+        value: None,
+        help: "skip directories that can't be listed and files that can't be read, with a warning",
+        commands: WALK,
+    },
+    Flag {
+        name: "--min-ratio",
+        value: Some("PERCENT"),
+        help: "rewrite binary as plain and text as base64 when compression saves less",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--chunk-manifest",
+        value: Some("FILE"),
+        help: "cut the archive into chunks stored by hash and list them in FILE, or read them back",
+        commands: &["encode", "assemble"],
+    },
+    Flag {
+        name: "--chunks",
+        value: Some("DIR"),
+        help: "where the --chunk-manifest chunks are, chunks/ next to the manifest by default",
+        commands: &["encode", "assemble"],
+    },
+    Flag {
+        name: "--chunk-size",
+        value: Some("SIZE"),
+        help: "length of each --chunk-manifest chunk, 4M by default",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--files-from",
+        value: Some("FILE"),
+        help: "archive the paths listed in FILE, - for stdin, with per-path directives",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--wrap",
+        value: Some("COLUMNS"),
+        help: "break base64 into lines of COLUMNS characters, a multiple of 4",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--record-root",
+        value: None,
+        help: "record the encoded directory in the header, for decode --expect-root",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--fast",
+        value: None,
+        help: "check entries by their recorded --stored-hash, without decoding them",
+        commands: &["verify"],
+    },
+    Flag {
+        name: "--stop-on-first",
+        value: None,
+        help: "fail on an entry as soon as it runs past its recorded size",
+        commands: &["verify"],
+    },
+    Flag {
+        name: "--keep-going",
+        value: None,
+        help: "check every entry and report all that fail, not just the first",
+        commands: &["verify"],
+    },
+    Flag {
+        name: "--against",
+        value: Some("DIR"),
+        help: "compare the entries' hashes with the files under DIR instead",
+        commands: &["verify"],
+    },
+    Flag {
+        name: "--progress",
+        value: None,
+        help: "show how much of the archive has been read",
+        commands: &["verify"],
+    },
+    Flag {
+        name: "--list-only",
+        value: None,
+        help: "print each entry's recorded hash and size without checking it",
+        commands: &["verify"],
+    },
+    Flag {
+        name: "--preserve-perms",
+        value: None,
+        help: "record permission bits, restored on decode",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--restore",
+        value: Some("LIST"),
+        help: "restore only these recorded attributes: perms,mtime,btime,owner or none",
+        commands: DECODE,
+    },
+    Flag {
+        name: "--clamp-mtime",
+        value: Some("TIMESTAMP"),
+        help: "record mtimes newer than TIMESTAMP as TIMESTAMP",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--temp-dir",
+        value: Some("DIR"),
+        help: "write the archive in DIR before moving it into place",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--symlinks",
+        value: Some("follow|store"),
+        help: "archive what symlinks point to, or the links themselves",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--codec-for",
+        value: Some("PATTERN=store|deflate"),
+        help: "pack matching entries with a codec, repeatable",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--max-memory",
+        value: Some("SIZE"),
+        help: "spill entry buffers to a temporary file past SIZE in memory",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--no-compress-ext",
+        value: Some("LIST"),
+        help: "store files with these extensions uncompressed, in binary and text archives",
+        commands: ENCODE,
+    },
+    Flag {
+        name: "--exclude",
+        value: Some("PATTERN"),
+        help: "skip paths matching PATTERN, repeatable",
+        commands: WALK,
+    },
+    Flag {
+        name: "--exclude-from",
+        value: Some("FILE"),
+        help: "skip paths matching the patterns in FILE, repeatable",
+        commands: WALK,
+    },
+    Flag {
+        name: "--exclude-vcs",
+        value: None,
+        help: "skip version control metadata like .git, .hg and .svn",
+        commands: WALK,
+    },
+    Flag {
+        name: "--modified-since",
+        value: Some("DATE|@SECONDS"),
+        help: "skip files last modified before DATE",
+        commands: WALK,
+    },
+    Flag {
+        name: "--exclude-larger-than",
+        value: Some("SIZE"),
+        help: "skip files larger than SIZE",
+        commands: WALK,
+    },
+    Flag {
+        name: "--exclude-smaller-than",
+        value: Some("SIZE"),
+        help: "skip files smaller than SIZE",
+        commands: WALK,
+    },
+    Flag {
+        name: "--include-archives",
+        value: None,
+        help: "archive earlier mito archives found in the tree",
+        commands: WALK,
+    },
+    Flag {
+        name: "--allow-external-symlinks",
+        value: None,
+        help: "create symlinks that point outside the destination",
+        commands: DECODE,
+    },
+    Flag {
+        name: "--touch-only",
+        value: None,
+        help: "create every entry as an empty file, without the contents",
+        commands: DECODE,
+    },
+    Flag {
+        name: "--dedup-links",
+        value: None,
+        help: "hard-link entries with the same content to the first one extracted",
+        commands: DECODE,
+    },
+    Flag {
+        name: "--expect-root",
+        value: Some("DIR"),
+        help: "refuse an archive unless it was encoded from DIR",
+        commands: DECODE,
+    },
+    Flag {
+        name: "--to-zip",
+        value: Some("FILE"),
+        help: "write the entries into a zip archive instead of the destination",
+        commands: DECODE,
+    },
+    Flag {
+        name: "--only",
+        value: Some("PATTERN"),
+        help: "extract only paths matching PATTERN, repeatable",
+        commands: DECODE,
+    },
+    Flag {
+        name: "--strip-components",
+        value: Some("N"),
+        help: "drop the first N directories of every stored path",
+        commands: DECODE,
+    },
+    Flag {
+        name: "--flatten",
+        value: None,
+        help: "extract every entry into the destination under its file name alone",
+        commands: DECODE,
+    },
+    Flag {
+        name: "--reserved-names",
+        value: Some("escape|error|keep"),
+        help: "handling of names Windows can't represent",
+        commands: DECODE,
+    },
+    Flag {
+        name: "--best-effort",
+        value: None,
+        help: "recover what can be read from a damaged archive",
+        commands: DECODE,
+    },
+    Flag {
+        name: "--strict",
+        value: None,
+        help: "fail on mixed modes, entries not matching their hash, or binary files in --plain",
+        commands: &["encode", "decode"],
+    },
+    Flag {
+        name: "--no-space-check",
+        value: None,
+        help: "extract even if the entries look too big for the free space",
+        commands: DECODE,
+    },
+    Flag {
+        name: "--compat",
+        value: Some("VERSION"),
+        help: "format version of an archive without a header",
+        commands: &["decode", "verify", "diff"],
+    },
+    Flag {
+        name: "--conflict",
+        value: Some("rename|skip|error"),
+        help: "handling of file/directory conflicts",
+        commands: DECODE,
+    },
+    Flag {
+        name: "--on-duplicate",
+        value: Some("error|last-wins|first-wins"),
+        help: "handling of a path stored more than once",
+        commands: &["decode", "verify"],
+    },
+    Flag {
+        name: "--hash-prefix",
+        value: Some("HEX"),
+        help: "select only entries whose hash starts with HEX",
+        commands: &["decode", "list"],
+    },
+    Flag {
+        name: "--volumes",
+        value: None,
+        help: "read NAME.NNN as the first volume of a split archive, with the ones after it",
+        commands: &["decode", "list", "verify"],
+    },
+    Flag {
+        name: "--null",
+        value: None,
+        help: "end each path with NUL instead of a newline, in list and --files-from",
+        commands: &["list", "encode"],
+    },
+    Flag {
+        name: "--json",
+        value: None,
+        help: "print the changes, or the encode or verify summary, as JSON",
+        commands: &["diff", "encode", "verify"],
+    },
+    Flag {
+        name: "--detect-renames",
+        value: None,
+        help: "report a removed and an added entry with the same hash as a rename",
+        commands: &["diff"],
+    },
+    Flag {
+        name: "--top",
+        value: Some("N"),
+        help: "number of directories and files to show",
+        commands: &["analyze"],
+    },
+];
+
+/// Exit code for a missing or unknown command, and for flags that can't be parsed.
+pub const USAGE_EXIT_CODE: i32 = 2;
+
+/// The commands `mito` advertises and their one-line help, from `COMMANDS`.
+pub fn usage() -> String {
+    let shown = || COMMANDS.iter().filter(|c| !c.hidden);
+    let width = shown().map(|c| c.name.len()).max().unwrap_or(0);
+    let mut usage = "usage: mito <command> [flags] [args]\n\ncommands:\n".to_string();
+    for command in shown() {
+        usage += &format!(
+            "  {:width$}  {}\n",
+            command.name,
+            command.help,
+            width = width
+        );
+    }
+    usage
+}
+
+// Short spellings, normalized to their long form while parsing.
+pub const ALIASES: &[(&str, &str)] = &[("-o", "--output")];
+
+pub struct Args {
+    pub command: Option<String>,
+    pub positional: Vec<String>,
+    flags: Vec<(String, Option<String>)>,
+}
+
+/// A command line that can't be parsed, told apart from other invalid input so it exits with
+/// `USAGE_EXIT_CODE`.
+#[derive(Debug)]
+struct UsageError(String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for UsageError {}
+
+pub fn usage_error(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, UsageError(msg.into()))
+}
+
+/// Whether `e` says the command line couldn't be parsed, rather than that running it failed.
+pub fn is_usage_error(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<UsageError>())
+}
+
+impl Args {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> io::Result<Args> {
+        let command = args.next();
+        let mut flags = Vec::new();
+        let mut positional = Vec::new();
+
+        while let Some(arg) = args.next() {
+            if !arg.starts_with('-') {
+                positional.push(arg);
+                continue;
+            }
+            let (name, inline) = match arg.find('=') {
+                Some(i) if arg.starts_with("--") => {
+                    (arg[..i].to_string(), Some(arg[i + 1..].to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let name = match ALIASES.iter().find(|(short, _)| *short == name) {
+                Some((_, long)) => long.to_string(),
+                None => name,
+            };
+            let flag = match FLAGS.iter().find(|flag| flag.name == name) {
+                Some(flag) => flag,
+                None => return Err(usage_error(format!("unknown option `{}`", arg))),
+            };
+            if let Some(command) = command.as_deref() {
+                let known = COMMANDS.iter().any(|c| c.name == command);
+                if known && !flag.commands.contains(&command) {
+                    return Err(usage_error(format!(
+                        "`{}` is not an option of `{}`",
+                        name, command
+                    )));
+                }
+            }
+            let takes_value = flag.value.is_some();
+            let value = if takes_value {
+                match inline.or_else(|| args.next()) {
+                    Some(value) => Some(value),
+                    None => return Err(usage_error(format!("`{}` requires a value", name))),
+                }
+            } else if inline.is_some() {
+                return Err(usage_error(format!("`{}` does not take a value", name)));
+            } else {
+                None
+            };
+            flags.push((name, value));
+        }
+
+        Ok(Args {
+            command,
+            positional,
+            flags,
+        })
+    }
+
+    /// Puts `defaults` before the flags given on the command line, so those override them.
+    pub fn with_defaults(mut self, mut defaults: Vec<(String, Option<String>)>) -> Args {
+        defaults.append(&mut self.flags);
+        self.flags = defaults;
+        self
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.flags.iter().any(|(flag, _)| flag == name)
+    }
+
+    pub fn count(&self, name: &str) -> usize {
+        self.flags.iter().filter(|(flag, _)| flag == name).count()
+    }
+
+    /// The value of the last occurrence of `name`, so later flags override earlier ones.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.flags
+            .iter()
+            .rev()
+            .find(|(flag, _)| flag == name)
+            .and_then(|(_, value)| value.as_deref())
+    }
+
+    /// The values of every occurrence of `name`, for flags that accumulate.
+    pub fn values(&self, name: &str) -> Vec<&str> {
+        self.flags
+            .iter()
+            .filter(|(flag, _)| flag == name)
+            .filter_map(|(_, value)| value.as_deref())
+            .collect()
+    }
+
+    /// The last of `names` given on the command line.
+    pub fn last_of<'a>(&self, names: &[&'a str]) -> Option<&'a str> {
+        self.flags
+            .iter()
+            .rev()
+            .find_map(|(flag, _)| names.iter().find(|name| *name == flag).copied())
+    }
+}
+
+/// Parses a byte count with an optional binary `K`/`M`/`G` suffix, e.g. `10M`.
+pub fn parse_size(s: &str) -> io::Result<u64> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| {
+            usage_error(format!(
+                "invalid size `{}`, expected e.g. 512, 10K, 10M or 1G",
+                s
+            ))
+        })
+}
+
+/// Parses a point in time as Unix seconds: `@SECONDS`, or a `YYYY-MM-DD` date taken as
+/// midnight UTC.
+pub fn parse_time(s: &str) -> io::Result<u64> {
+    let s = s.trim();
+    let invalid = || {
+        usage_error(format!(
+            "invalid time `{}`, expected a date like 2024-01-01 or @SECONDS",
+            s
+        ))
+    };
+    if let Some(seconds) = s.strip_prefix('@') {
+        return seconds.parse().map_err(|_| invalid());
+    }
+    let parts: Vec<u32> = s
+        .split('-')
+        .map(|part| part.parse().map_err(|_| invalid()))
+        .collect::<io::Result<_>>()?;
+    let (year, month, day) = match parts[..] {
+        [year, month, day]
+            if year >= 1970 && (1..=12).contains(&month) && (1..=31).contains(&day) =>
+        {
+            (year, month, day)
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(days_from_civil(year, month, day) * 86_400)
+}
+
+/// Days from 1970-01-01 to the given date in the proleptic Gregorian calendar, from Howard
+/// Hinnant's `days_from_civil`.
+fn days_from_civil(year: u32, month: u32, day: u32) -> u64 {
+    let year = u64::from(if month <= 2 { year - 1 } else { year });
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month = u64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + u64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Formats a byte count for messages, to one decimal place of a binary `K`/`M`/`G` suffix,
+/// e.g. `1.5M`. It is rounded, so `parse_size`, which takes whole numbers only, can't read it
+/// back.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [(&str, u64); 3] = [("G", 1 << 30), ("M", 1 << 20), ("K", 1 << 10)];
+    for (suffix, unit) in UNITS.iter() {
+        if bytes >= *unit {
+            return format!("{:.1}{}", bytes as f64 / *unit as f64, suffix);
+        }
+    }
+    bytes.to_string()
+}
+
+/// `n` followed by `one` or `many`, whichever fits it, e.g. `1 entry` or `2 entries`.
+pub fn counted(n: usize, one: &str, many: &str) -> String {
+    format!("{} {}", n, if n == 1 { one } else { many })
+}
@@ -0,0 +1,148 @@
+use std::io;
+
+use crate::cli::{Command, Flag, ALIASES, COMMANDS, FLAGS};
+
+fn visible() -> impl Iterator<Item = &'static Command> {
+    COMMANDS.iter().filter(|command| !command.hidden)
+}
+
+/// Every spelling of the flags `command` accepts, aliases included.
+fn names(command: &str) -> Vec<(&'static str, &'static Flag)> {
+    let mut names = Vec::new();
+    for flag in FLAGS.iter().filter(|flag| flag.commands.contains(&command)) {
+        names.push((flag.name, flag));
+        for (short, _) in ALIASES.iter().filter(|(_, long)| *long == flag.name) {
+            names.push((*short, flag));
+        }
+    }
+    names
+}
+
+// Help texts go inside single quotes.
+fn sh_escape(help: &str) -> String {
+    help.replace('\'', "'\\''")
+}
+
+fn fish_escape(help: &str) -> String {
+    help.replace('\'', "\\'")
+}
+
+fn bash() -> String {
+    let mut script = String::from(
+        "_mito() {\n    local cur=\"${COMP_WORDS[COMP_CWORD]}\" opts\n    if [ \"$COMP_CWORD\" -eq 1 ]; then\n",
+    );
+    let commands: Vec<_> = visible().map(|command| command.name).collect();
+    script += &format!(
+        "        COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n        return\n    fi\n",
+        commands.join(" ")
+    );
+    script += "    case \"${COMP_WORDS[1]}\" in\n";
+    for command in visible() {
+        let flags: Vec<_> = names(command.name).iter().map(|(name, _)| *name).collect();
+        script += &format!(
+            "        {}) opts=\"{}\" ;;\n",
+            command.name,
+            flags.join(" ")
+        );
+    }
+    script += "    esac\n    if [[ \"$cur\" == -* ]]; then\n        COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))\n    else\n        COMPREPLY=($(compgen -f -- \"$cur\"))\n    fi\n}\ncomplete -F _mito mito\n";
+    script
+}
+
+fn zsh() -> String {
+    let mut script =
+        String::from("#compdef mito\n\n_mito() {\n    local -a commands\n    commands=(\n");
+    for command in visible() {
+        script += &format!("        '{}:{}'\n", command.name, sh_escape(command.help));
+    }
+    script += "    )\n    if (( CURRENT == 2 )); then\n        _describe 'command' commands\n        return\n    fi\n    case $words[2] in\n";
+    for command in visible() {
+        script += &format!("        {})\n            _arguments \\\n", command.name);
+        for (name, flag) in names(command.name) {
+            match flag.value {
+                Some(value) => {
+                    script += &format!(
+                        "                '{}=[{}]:{}:_files' \\\n",
+                        name,
+                        sh_escape(flag.help),
+                        value
+                    )
+                }
+                None => {
+                    script += &format!("                '{}[{}]' \\\n", name, sh_escape(flag.help))
+                }
+            }
+        }
+        script += "                '*:file:_files'\n            ;;\n";
+    }
+    script += "    esac\n}\n\n_mito \"$@\"\n";
+    script
+}
+
+fn fish() -> String {
+    let mut script = String::new();
+    for command in visible() {
+        script += &format!(
+            "complete -c mito -n '__fish_use_subcommand' -a {} -d '{}'\n",
+            command.name,
+            fish_escape(command.help)
+        );
+    }
+    for command in visible() {
+        for (name, flag) in names(command.name) {
+            // fish spells `--long` as `-l long` and single-dash names like `-vv` as `-o vv`.
+            let option = match name.strip_prefix("--") {
+                Some(long) => format!("-l {}", long),
+                None => format!("-o {}", &name[1..]),
+            };
+            let value = if flag.value.is_some() { " -r" } else { "" };
+            script += &format!(
+                "complete -c mito -n '__fish_seen_subcommand_from {}' {}{} -d '{}'\n",
+                command.name,
+                option,
+                value,
+                fish_escape(flag.help)
+            );
+        }
+    }
+    script
+}
+
+fn powershell() -> String {
+    let mut script = String::from("Register-ArgumentCompleter -Native -CommandName mito -ScriptBlock {\n    param($wordToComplete, $commandAst, $cursorPosition)\n    $words = @($commandAst.CommandElements | ForEach-Object { $_.ToString() })\n    $candidates = if ($words.Count -le 1 -or ($words.Count -eq 2 -and $wordToComplete)) {\n        @(");
+    let commands: Vec<_> = visible()
+        .map(|command| format!("'{}'", command.name))
+        .collect();
+    script += &commands.join(", ");
+    script += ")\n    } else {\n        switch ($words[1]) {\n";
+    for command in visible() {
+        let flags: Vec<_> = names(command.name)
+            .iter()
+            .map(|(name, _)| format!("'{}'", name))
+            .collect();
+        script += &format!(
+            "            '{}' {{ @({}) }}\n",
+            command.name,
+            flags.join(", ")
+        );
+    }
+    script += "            default { @() }\n        }\n    }\n    $candidates | Where-Object { $_ -like \"$wordToComplete*\" } | ForEach-Object {\n        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)\n    }\n}\n";
+    script
+}
+
+/// The completion script for `shell`, generated from the command and flag tables.
+pub fn generate(shell: &str) -> io::Result<String> {
+    match shell {
+        "bash" => Ok(bash()),
+        "zsh" => Ok(zsh()),
+        "fish" => Ok(fish()),
+        "powershell" => Ok(powershell()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "not support {}, available shells are bash|zsh|fish|powershell",
+                shell
+            ),
+        )),
+    }
+}
@@ -0,0 +1,194 @@
+// `mito cat-archive`: joins uncompressed archives by copying their bytes, without reading
+// their entries. Entries end in a newline and start with a separator, so the entries of one
+// archive can follow those of another as they are; only the header is written once, and an
+// index is rebuilt from the inputs' own, or left out if any of them has none.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::atomic::AtomicFile;
+use crate::encode::STDOUT;
+use crate::header::{self, Header};
+use crate::trailer::{self, Located};
+use crate::{interrupt, log, Mode};
+
+/// One input: its header, and where its entries start and end.
+struct Input {
+    path: PathBuf,
+    header: Header,
+    start: u64,
+    end: u64,
+    index: Option<Vec<Located>>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn open(path: &Path) -> io::Result<(File, Input)> {
+    let mut file = File::open(path)?;
+    let found = trailer::find(&mut file)?;
+    let end = match &found {
+        Some((start, _)) => *start,
+        None => file.metadata()?.len(),
+    };
+    let compressed = |mode: Mode| {
+        invalid(format!(
+            "{} is a {} archive; cat-archive only joins plain, base64 and auto archives, which are not compressed as a whole",
+            path.display(),
+            mode
+        ))
+    };
+    let mut line = Vec::new();
+    BufReader::new(&mut file).read_until(b'\n', &mut line)?;
+    if !line.starts_with(header::MAGIC.as_bytes()) {
+        // The header of a compressed archive is inside the compression.
+        file.seek(SeekFrom::Start(0))?;
+        let mut prefix = Vec::new();
+        Read::take(&mut file, header::SNIFF_LEN as u64).read_to_end(&mut prefix)?;
+        return Err(match header::detect_mode(&prefix) {
+            Some(mode) => compressed(mode),
+            None => invalid(format!(
+                "{} has no archive header, so its mode can't be checked",
+                path.display()
+            )),
+        });
+    }
+    let header = Header::parse(&line).map_err(|e| {
+        invalid(format!(
+            "{}: malformed archive header: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    if let Mode::CompressedBinary | Mode::CompressedTxt = header.mode {
+        return Err(compressed(header.mode));
+    }
+    let start = line.len() as u64;
+    file.seek(SeekFrom::Start(start))?;
+    Ok((
+        file,
+        Input {
+            path: path.to_path_buf(),
+            header,
+            start,
+            end,
+            index: found.map(|(_, index)| index),
+        },
+    ))
+}
+
+/// Why `input` can't follow `first` in one archive, if it can't.
+fn mismatch(first: &Input, input: &Input) -> Option<String> {
+    let (a, b) = (&first.header, &input.header);
+    let what = if a.mode != b.mode {
+        format!("{} but {} is {}", b.mode, first.path.display(), a.mode)
+    } else if a.version != b.version {
+        format!(
+            "format version {} but {} is {}",
+            b.version,
+            first.path.display(),
+            a.version
+        )
+    } else if a.hash_scope != b.hash_scope {
+        format!(
+            "hashed {} but {} is hashed {}",
+            b.hash_scope.name(),
+            first.path.display(),
+            a.hash_scope.name()
+        )
+    } else if a.alphabet != b.alphabet {
+        format!(
+            "in the {} base64 alphabet but {} is in {}",
+            b.alphabet.name(),
+            first.path.display(),
+            a.alphabet.name()
+        )
+    } else {
+        return None;
+    };
+    Some(format!(
+        "{} is {}; cat-archive only joins archives with the same header",
+        input.path.display(),
+        what
+    ))
+}
+
+/// Joins the archives at `inputs`, in order, into `output`, or stdout for `-`. Every input
+/// is checked before anything is written.
+pub fn cat_archives(inputs: &[PathBuf], output: &Path) -> io::Result<()> {
+    if inputs.is_empty() {
+        return Err(invalid(
+            "cat-archive joins archives: mito cat-archive A B... -o OUT".to_string(),
+        ));
+    }
+    if let Some(input) = inputs.iter().find(|input| *input == output) {
+        return Err(invalid(format!(
+            "{} is both an input and the output",
+            input.display()
+        )));
+    }
+    let mut opened = Vec::with_capacity(inputs.len());
+    for path in inputs {
+        let (file, input) = open(path)?;
+        if let Some(msg) = opened
+            .first()
+            .and_then(|(_, first)| mismatch(first, &input))
+        {
+            return Err(invalid(msg));
+        }
+        opened.push((file, input));
+    }
+    let indexed = opened.iter().all(|(_, input)| input.index.is_some());
+    if !indexed && opened.iter().any(|(_, input)| input.index.is_some()) {
+        log::warn("not every archive has an index, so the joined one has none");
+    }
+
+    let mut header = Header {
+        root: opened[0].1.header.root.clone(),
+        ..opened[0].1.header
+    };
+    if opened
+        .iter()
+        .any(|(_, input)| input.header.root != header.root)
+    {
+        log::warn("the archives were encoded from different roots, so the joined one records none");
+        header.root = None;
+    }
+    let header = header.to_line();
+    let mut write = |out: &mut dyn Write| -> io::Result<()> {
+        out.write_all(header.as_bytes())?;
+        let mut written = header.len() as u64;
+        let mut index = Vec::new();
+        for (file, input) in &mut opened {
+            interrupt::check()?;
+            if indexed {
+                // Each entry moves by as much as the archive's own entries moved.
+                let shift = |offset: u64| offset - input.start + written;
+                index.extend(input.index.iter().flatten().map(|entry| Located {
+                    path: entry.path.clone(),
+                    offset: shift(entry.offset),
+                    len: entry.len,
+                }));
+            }
+            written += io::copy(&mut Read::take(file, input.end - input.start), out)?;
+        }
+        if indexed {
+            out.write_all(&trailer::to_bytes(&index))?;
+        }
+        Ok(())
+    };
+    if output == Path::new(STDOUT) {
+        let stdout = io::stdout();
+        let mut out = io::BufWriter::new(stdout.lock());
+        write(&mut out)?;
+        out.flush()
+    } else {
+        let mut out = io::BufWriter::new(AtomicFile::create(output, None)?);
+        write(&mut out)?;
+        out.into_inner().map_err(|e| e.into_error())?.commit()?;
+        eprintln!("joined {} archives into {}", inputs.len(), output.display());
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{Flag, FLAGS};
+use crate::Mode;
+
+/// The config file looked for in the current directory.
+pub const LOCAL_CONFIG: &str = "mito.toml";
+
+/// Prefix of the environment variables that set a flag, e.g. `MITO_LEVEL=9`.
+const ENV_PREFIX: &str = "MITO_";
+
+/// A value in the supported subset of TOML: strings, integers, booleans and arrays of those.
+enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    List(Vec<Value>),
+}
+
+fn config_error(path: &Path, line: usize, msg: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}:{}: {}", path.display(), line, msg),
+    )
+}
+
+/// Parses `key = value` lines, skipping blank lines and `#` comments.
+fn parse_toml(path: &Path, text: &str) -> io::Result<Vec<(String, Value, usize)>> {
+    let mut settings = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(config_error(
+                path,
+                line_no,
+                "tables aren't supported, settings go at the top level".to_string(),
+            ));
+        }
+        let (key, value) = match line.find('=') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => {
+                return Err(config_error(
+                    path,
+                    line_no,
+                    format!("expected `key = value`, found `{}`", line),
+                ))
+            }
+        };
+        let (value, rest) = parse_value(value).map_err(|msg| config_error(path, line_no, msg))?;
+        let rest = rest.trim();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err(config_error(
+                path,
+                line_no,
+                format!("unexpected `{}` after the value", rest),
+            ));
+        }
+        settings.push((key.to_string(), value, line_no));
+    }
+    Ok(settings)
+}
+
+/// Parses the value at the start of `s`, returning it and whatever follows.
+fn parse_value(s: &str) -> Result<(Value, &str), String> {
+    if let Some(rest) = s.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::Str(value), &rest[i + 1..])),
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    other => {
+                        return Err(format!("unsupported escape `\\{}`", other.unwrap_or(' ')))
+                    }
+                },
+                c => value.push(c),
+            }
+        }
+        return Err("unterminated string".to_string());
+    }
+    if let Some(rest) = s.strip_prefix('\'') {
+        return match rest.find('\'') {
+            Some(end) => Ok((Value::Str(rest[..end].to_string()), &rest[end + 1..])),
+            None => Err("unterminated string".to_string()),
+        };
+    }
+    if let Some(mut rest) = s.strip_prefix('[') {
+        let mut items = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::List(items), after));
+            }
+            let (item, after) = parse_value(rest)?;
+            items.push(item);
+            rest = after.trim_start();
+            rest = match rest.strip_prefix(',') {
+                Some(after) => after,
+                None if rest.starts_with(']') => rest,
+                None => return Err("expected `,` or `]` in array".to_string()),
+            };
+        }
+    }
+    let end = s
+        .find(|c: char| c.is_whitespace() || c == '#')
+        .unwrap_or(s.len());
+    let (word, rest) = s.split_at(end);
+    match word {
+        "true" => Ok((Value::Bool(true), rest)),
+        "false" => Ok((Value::Bool(false), rest)),
+        _ => word
+            .parse()
+            .map(|n| (Value::Int(n), rest))
+            .map_err(|_| format!("unsupported value `{}`", word)),
+    }
+}
+
+fn flag_for_key(key: &str) -> Option<&'static Flag> {
+    let name = format!("--{}", key.replace('_', "-"));
+    FLAGS.iter().find(|flag| flag.name == name)
+}
+
+/// Turns one setting into the flags it stands for. `mode = "binary"` becomes `--binary`,
+/// `true` a switch and arrays one flag per item.
+fn setting_flags(
+    key: &str,
+    value: Value,
+    flags: &mut Vec<(String, Option<String>)>,
+) -> Result<(), String> {
+    if key == "mode" {
+        return match value {
+            Value::Str(name) => {
+                name.parse::<Mode>().map_err(|e| e.to_string())?;
+                flags.push((format!("--{}", name), None));
+                Ok(())
+            }
+            _ => Err("`mode` must be a string".to_string()),
+        };
+    }
+    let flag = flag_for_key(key).ok_or_else(|| format!("unknown setting `{}`", key))?;
+    match (value, flag.value) {
+        (Value::Bool(true), None) => flags.push((flag.name.to_string(), None)),
+        (Value::Bool(false), None) => {}
+        (Value::Str(s), Some(_)) => flags.push((flag.name.to_string(), Some(s))),
+        (Value::Int(n), Some(_)) => flags.push((flag.name.to_string(), Some(n.to_string()))),
+        (Value::List(items), Some(_)) => {
+            for item in items {
+                setting_flags(key, item, flags)?;
+            }
+        }
+        (_, None) => return Err(format!("`{}` is a switch, set it to true or false", key)),
+        (_, Some(_)) => return Err(format!("`{}` takes a string or a number", key)),
+    }
+    Ok(())
+}
+
+/// The global config, `$XDG_CONFIG_HOME/mito/config.toml` or `~/.config/mito/config.toml`.
+fn global_config() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(base.join("mito").join("config.toml"))
+}
+
+fn file_flags(path: &Path, flags: &mut Vec<(String, Option<String>)>) -> io::Result<()> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for (key, value, line_no) in parse_toml(path, &text)? {
+        setting_flags(&key, value, flags).map_err(|msg| config_error(path, line_no, msg))?;
+    }
+    Ok(())
+}
+
+/// `MITO_<FLAG>` for every long flag, e.g. `MITO_EXCLUDE_LARGER_THAN=10M`, plus `MITO_MODE`.
+/// Switches are on when set to anything but ``, `0` or `false`.
+fn env_flags(flags: &mut Vec<(String, Option<String>)>) -> io::Result<()> {
+    // `SOURCE_DATE_EPOCH` is read as `--clamp-mtime` later on and beats the config files.
+    if env::var_os("SOURCE_DATE_EPOCH").is_some() {
+        flags.retain(|(name, _)| name != "--clamp-mtime");
+    }
+    if let Ok(name) = env::var(format!("{}MODE", ENV_PREFIX)) {
+        setting_flags("mode", Value::Str(name), flags)
+            .map_err(|msg| io::Error::new(io::ErrorKind::InvalidInput, msg))?;
+    }
+    for flag in FLAGS {
+        let long = match flag.name.strip_prefix("--") {
+            Some(long) => long,
+            None => continue,
+        };
+        let var = format!("{}{}", ENV_PREFIX, long.to_uppercase().replace('-', "_"));
+        let value = match env::var(&var) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        match flag.value {
+            Some(_) => flags.push((flag.name.to_string(), Some(value))),
+            None if matches!(value.as_str(), "" | "0" | "false") => {}
+            None => flags.push((flag.name.to_string(), None)),
+        }
+    }
+    Ok(())
+}
+
+/// Flags that apply to `command` before the command line: the global config, then
+/// `mito.toml` in the current directory, then `MITO_*` environment variables. Later ones
+/// override earlier ones, and the command line overrides them all.
+pub fn defaults(command: &str) -> io::Result<Vec<(String, Option<String>)>> {
+    let mut flags = Vec::new();
+    if let Some(global) = global_config() {
+        file_flags(&global, &mut flags)?;
+    }
+    file_flags(LOCAL_CONFIG.as_ref(), &mut flags)?;
+    env_flags(&mut flags)?;
+    flags.retain(|(name, _)| {
+        // `diff` detects each archive's mode unless one is given on the command line.
+        let is_mode = name[2..].parse::<Mode>().is_ok();
+        FLAGS
+            .iter()
+            .any(|flag| flag.name == name && flag.commands.contains(&command))
+            && !(is_mode && command == "diff")
+    });
+    Ok(flags)
+}
@@ -0,0 +1,2570 @@
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, create_dir_all, File};
+use std::hash::Hasher;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use flate2::write::ZlibDecoder as ZlibInflater;
+use flate2::Compression;
+
+use crate::atomic::AtomicFile;
+use crate::cli::{counted, format_size};
+use crate::encode::absolute;
+use crate::glob::Glob;
+use crate::header::{self, Header};
+use crate::lines::LineReader;
+use crate::native::{
+    link_leaves, native_path, numbered, split_stored, symlink, symlink_ancestor, Remap,
+    ReservedNames,
+};
+use crate::stack::{Base64Reader, SectionReader};
+use crate::trailer::{self, Ranges};
+use crate::volumes::{self, Volumes};
+use crate::writer::{Codec, COMPRESSION_LEVEL};
+use crate::zip::ZipWriter;
+use crate::{
+    btime, interrupt, log, owner, perms, space, sparse, Alphabet, Encoding, HashScope, Mode,
+};
+use crate::{json, separator};
+
+// How much of the archive decode reads at a time.
+const DECODE_CHUNK: usize = 64 * 1024;
+// Records stored paths that had to be renamed on decode, one `stored<TAB>native` per line,
+// next to the destination directory.
+const REMAPS_SUFFIX: &str = ".remaps";
+
+/// What to do when an entry needs a directory where an earlier entry wrote a file, or
+/// the other way around.
+#[derive(Clone, Copy)]
+pub enum Conflict {
+    Rename,
+    Skip,
+    Error,
+}
+
+impl Conflict {
+    pub fn parse(s: &str) -> io::Result<Conflict> {
+        match s {
+            "rename" => Ok(Conflict::Rename),
+            "skip" => Ok(Conflict::Skip),
+            "error" => Ok(Conflict::Error),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "not support {}, available strategies are rename|skip|error",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
+/// What to do with an entry whose stored path an earlier entry in the same archive already
+/// had.
+#[derive(Clone, Copy, PartialEq)]
+pub enum OnDuplicate {
+    Error,
+    // The later entry replaces the earlier one, as it always did.
+    LastWins,
+    FirstWins,
+}
+
+impl OnDuplicate {
+    pub fn parse(s: &str) -> io::Result<OnDuplicate> {
+        match s {
+            "error" => Ok(OnDuplicate::Error),
+            "last-wins" => Ok(OnDuplicate::LastWins),
+            "first-wins" => Ok(OnDuplicate::FirstWins),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "not support {}, available strategies are error|last-wins|first-wins",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
+/// Which recorded attributes decode applies to the files it extracts, see `--restore`.
+#[derive(Clone, Copy)]
+pub struct Restore {
+    pub perms: bool,
+    pub mtime: bool,
+    pub btime: bool,
+    pub owner: bool,
+}
+
+impl Restore {
+    pub fn all() -> Restore {
+        Restore {
+            perms: true,
+            mtime: true,
+            btime: true,
+            owner: true,
+        }
+    }
+
+    /// Parses a comma-separated list of `perms`, `mtime`, `btime` and `owner`; `none`
+    /// restores nothing.
+    pub fn parse(s: &str) -> io::Result<Restore> {
+        let mut restore = Restore {
+            perms: false,
+            mtime: false,
+            btime: false,
+            owner: false,
+        };
+        for name in s.split(',') {
+            match name {
+                "perms" => restore.perms = true,
+                "mtime" => restore.mtime = true,
+                "btime" => restore.btime = true,
+                "owner" => restore.owner = true,
+                "none" => {}
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "not support {}, available attributes are perms|mtime|btime|owner|none",
+                            name
+                        ),
+                    ))
+                }
+            }
+        }
+        Ok(restore)
+    }
+}
+
+/// Settings for `decode_dir`.
+pub struct DecodeOptions {
+    pub dest: PathBuf,
+    pub strip_components: usize,
+    // Extract every entry straight into `dest` under its file name alone, see `--flatten`.
+    pub flatten: bool,
+    pub reserved_names: ReservedNames,
+    pub conflict: Conflict,
+    pub on_duplicate: OnDuplicate,
+    pub best_effort: bool,
+    pub strict: bool,
+    // Extract only entries matching one of these patterns, see `Glob`. Empty extracts all.
+    pub only: Vec<String>,
+    // Extract only entries whose recorded hash starts with these hex digits, see
+    // `hash_has_prefix`.
+    pub hash_prefix: Option<String>,
+    // Format version of archives without a header, which otherwise read as version 0.
+    pub compat: Option<u32>,
+    // The recorded attributes to restore. `None` restores whatever the archive recorded that
+    // this user can set, which leaves out ownership unless running as root.
+    pub restore: Option<Restore>,
+    // Compare the recorded entry sizes with the free space at `dest` before extracting.
+    pub space_check: bool,
+    // Create symlinks that point outside `dest`, which are refused otherwise.
+    pub allow_external_symlinks: bool,
+    // Create every entry as an empty file, without decoding or checking bodies.
+    pub touch_only: bool,
+    // Refuse an archive unless it records this as the directory it was encoded from.
+    pub expect_root: Option<PathBuf>,
+    // Hard-link an entry to an earlier one with the same content instead of writing it again.
+    pub dedup_links: bool,
+    // Fail at the first entry past this many.
+    pub max_entries: Option<usize>,
+    // Read `NAME.NNN` as the first of the numbered volumes of a split archive.
+    pub volumes: bool,
+}
+
+/// How decode reacts to an archive that isn't what it claims to be.
+#[derive(Clone, Copy)]
+struct Checks {
+    best_effort: bool,
+    strict: bool,
+    // Fail on a damaged entry, or one that doesn't match its hash, even without `strict`.
+    verify: bool,
+    // Fail as soon as an entry's body runs past its recorded size, before it ends.
+    stop_on_first: bool,
+    // Collect what `verify` fails an entry for and carry on, rather than stop at the first.
+    keep_going: bool,
+    // More entries than this fail the decode, see `--max-entries`.
+    max_entries: Option<usize>,
+}
+
+impl Checks {
+    /// Warns about an inconsistency in the archive, or fails with it under `--strict`.
+    fn report(self, msg: String) -> io::Result<()> {
+        if self.strict {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        log::warn(msg);
+        Ok(())
+    }
+
+    /// Like `report`, for an entry that can't be extracted as it was archived.
+    fn report_entry(self, entry: &mut Entry, msg: String) -> io::Result<()> {
+        if self.verify && self.keep_going {
+            entry.failures.push(msg);
+            return Ok(());
+        }
+        if self.verify {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        self.report(msg)
+    }
+
+    /// Marks `entry` corrupt, reporting `msg` unless it already was or `--best-effort` will
+    /// list it as unverified anyway.
+    fn damaged(self, entry: &mut Entry, msg: String) -> io::Result<()> {
+        if !(self.best_effort || entry.corrupt) {
+            self.report_entry(entry, msg)?;
+        }
+        entry.corrupt = true;
+        Ok(())
+    }
+}
+
+struct Entry {
+    path: String,
+    hash: String,
+    encoding: Encoding,
+    mtime: Option<u64>,
+    btime: Option<u64>,
+    perms: Option<u32>,
+    owner: Option<(u32, u32)>,
+    // Bodies are streamed straight to `file`, except under `--best-effort` or when decoding
+    // into memory, where they are held in `body` until the entry is known to be complete.
+    file: Option<File>,
+    body: Vec<u8>,
+    // Hashes the content as it is decoded, so streamed entries can be verified too.
+    hasher: DefaultHasher,
+    newline_pending: bool,
+    // The `holes=` still to come, and how far into the content decoding has got.
+    holes: VecDeque<(u64, u64)>,
+    offset: u64,
+    sparse: bool,
+    // Unpacks `codec=deflate` bodies as they are decoded; `None` once that failed.
+    inflater: Option<ZlibInflater<Vec<u8>>>,
+    codec: Codec,
+    // Where a `symlink=` entry points; it has no body.
+    link: Option<String>,
+    // The recorded `bodyhash=`, and the hash of the body lines as read, less the newline
+    // that ends the entry.
+    body_hash: Option<String>,
+    body_hasher: DefaultHasher,
+    body_newline_pending: bool,
+    // Only the stored bytes are checked, under `verify --fast`; the body isn't decoded.
+    stored_only: bool,
+    // Bytes extracted so far, as reported to the progress callback, and the recorded size.
+    written: u64,
+    size: Option<u64>,
+    corrupt: bool,
+    // Set when a conflict made `--conflict skip` drop the entry.
+    skipped: bool,
+    // The file extracted earlier with the same content, which `--dedup-links` links this
+    // entry to once it is verified; the body is decoded only to check it.
+    duplicate_of: Option<PathBuf>,
+    // Why `verify --keep-going` failed the entry.
+    failures: Vec<String>,
+}
+
+impl Entry {
+    fn verified(&self) -> bool {
+        !self.corrupt && self.hasher.finish().to_string() == self.hash
+    }
+
+    /// Whether decoded content is dropped rather than written or buffered.
+    fn discards(&self) -> bool {
+        self.skipped || self.duplicate_of.is_some()
+    }
+
+    /// Hashes a body line as it is in the archive.
+    fn push_stored(&mut self, line: &[u8]) {
+        if self.body_newline_pending {
+            self.body_hasher.write(b"\n");
+        }
+        let stored = line.strip_suffix(b"\n");
+        self.body_newline_pending = stored.is_some();
+        self.body_hasher.write(stored.unwrap_or(line));
+    }
+
+    /// Whether `read` bytes of body lines hold all of the entry, so a header line after them
+    /// starts another archive rather than being content. Only a plain, stored body of a
+    /// recorded size can tell; any other is taken as complete.
+    fn body_done(&self, read: u64) -> bool {
+        match self.size {
+            Some(size) if self.encoding == Encoding::Plain && self.codec == Codec::Store => {
+                self.sparse || read > size
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether the body as read matches `bodyhash=`, or there is none to compare with.
+    fn stored_verified(&self) -> bool {
+        self.body_hash
+            .as_ref()
+            .is_none_or(|hash| self.body_hasher.finish().to_string() == *hash)
+    }
+
+    fn push_plain(&mut self, line: &[u8]) -> io::Result<()> {
+        // The newline the encoder appends after every file isn't part of the content, so a
+        // line's newline is only hashed and written once another line follows it.
+        if self.newline_pending {
+            self.hasher.write(b"\n");
+            self.write(b"\n")?;
+        }
+        let content = line.strip_suffix(b"\n");
+        self.newline_pending = content.is_some();
+        self.hasher.write(content.unwrap_or(line));
+        self.write(content.unwrap_or(line))
+    }
+
+    /// Unpacks decoded `bytes` with the entry's codec.
+    fn unpack<'b>(&mut self, bytes: &'b [u8]) -> io::Result<Cow<'b, [u8]>> {
+        match (self.codec, self.inflater.as_mut()) {
+            (Codec::Store, _) => Ok(Cow::Borrowed(bytes)),
+            (_, Some(inflater)) => {
+                inflater.write_all(bytes)?;
+                Ok(Cow::Owned(std::mem::take(inflater.get_mut())))
+            }
+            // Unpacking already failed, so the rest can't be recovered.
+            (_, None) => Ok(Cow::Borrowed(&[])),
+        }
+    }
+
+    fn push_decoded(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        while !bytes.is_empty() {
+            self.fill_holes()?;
+            let until_hole = self
+                .holes
+                .front()
+                .map_or(bytes.len() as u64, |&(start, _)| start - self.offset);
+            let (data, rest) = bytes.split_at((until_hole as usize).min(bytes.len()));
+            self.hasher.write(data);
+            self.write(data)?;
+            self.offset += data.len() as u64;
+            bytes = rest;
+        }
+        Ok(())
+    }
+
+    /// Puts back the holes that start where decoding has got to: skipped over in a file, so
+    /// that it stays sparse, and zeros in memory.
+    fn fill_holes(&mut self) -> io::Result<()> {
+        while let Some(&(start, len)) = self.holes.front() {
+            if start > self.offset {
+                break;
+            }
+            self.holes.pop_front();
+            let hasher = &mut self.hasher;
+            sparse::for_each_zeros(len, |zeros| hasher.write(zeros));
+            self.written += len;
+            self.offset = start + len;
+            let discards = self.discards();
+            match self.file.as_mut() {
+                Some(file) => {
+                    file.seek(SeekFrom::Current(len as i64))?;
+                }
+                None if discards => {}
+                None => self.body.resize(self.body.len() + len as usize, 0),
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts back a hole that ends the content, which seeking alone doesn't extend the file
+    /// by.
+    fn finish_holes(&mut self) -> io::Result<()> {
+        self.fill_holes()?;
+        match self.file.as_mut() {
+            Some(file) if self.sparse => file.set_len(self.offset),
+            _ => Ok(()),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.written += bytes.len() as u64;
+        let discards = self.discards();
+        match self.file.as_mut() {
+            Some(file) => file.write_all(bytes),
+            None if discards => Ok(()),
+            None => {
+                self.body.extend_from_slice(bytes);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The stored paths entries had so far, to catch an archive that stores one twice.
+struct Seen {
+    paths: HashSet<String>,
+    on_duplicate: OnDuplicate,
+    duplicates: usize,
+}
+
+impl Seen {
+    fn new(on_duplicate: OnDuplicate) -> Seen {
+        Seen {
+            paths: HashSet::new(),
+            on_duplicate,
+            duplicates: 0,
+        }
+    }
+
+    /// Whether the entry `stored` is taken, which a repeated path isn't under `first-wins`.
+    /// Fails on a repeated path under `error`.
+    fn admit(&mut self, stored: &str) -> io::Result<bool> {
+        // `./a` and `a` are the same file once extracted.
+        if self
+            .paths
+            .insert(split_stored(stored).collect::<Vec<_>>().join("/"))
+        {
+            return Ok(true);
+        }
+        self.duplicates += 1;
+        match self.on_duplicate {
+            OnDuplicate::Error => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "`{}` is stored more than once in the archive (--on-duplicate last-wins or first-wins to read it anyway)",
+                    stored
+                ),
+            )),
+            OnDuplicate::LastWins => {
+                log::verbose(log::FILES, || {
+                    format!("duplicate entry {}, the last one wins", stored)
+                });
+                Ok(true)
+            }
+            OnDuplicate::FirstWins => {
+                log::verbose(log::FILES, || {
+                    format!("skipped duplicate entry {}, the first one wins", stored)
+                });
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Recovery {
+    seen: usize,
+    recovered: usize,
+    // Entries checked by their `bodyhash=` alone.
+    stored_only: usize,
+    unverified: Vec<String>,
+    failures: Vec<Failure>,
+}
+
+/// Creates the files entries are extracted to, under the destination directory.
+struct Extractor<'a> {
+    options: &'a DecodeOptions,
+    remaps: Vec<Remap>,
+    // Which entry created each file and directory, to name both sides of a conflict.
+    files: HashMap<PathBuf, String>,
+    dirs: HashMap<PathBuf, String>,
+    only: Vec<Glob>,
+    // Entries that passed `--only` and `--hash-prefix`, to report when none did.
+    matched: usize,
+    // What is restored: `--restore`, less ownership without root.
+    restore: Restore,
+    seen: Seen,
+    // The file last created, removed if an interrupt leaves it half-written.
+    current: Option<PathBuf>,
+    // Verified files by recorded hash, size, permissions and owner, for `--dedup-links`.
+    originals: HashMap<DuplicateKey, PathBuf>,
+    linked: usize,
+    // Whether `--dedup-links` was already warned about for an archive it can't work on.
+    warned_scope: bool,
+    // Symlinks created inside the destination, checked again once decoding is done.
+    links: Vec<(PathBuf, String, String)>,
+}
+
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink())
+}
+
+/// What two entries need to have in common to share a file under `--dedup-links`.
+type DuplicateKey = (String, u64, Option<u32>, Option<(u32, u32)>);
+
+fn duplicate_key(
+    hash: &str,
+    size: Option<u64>,
+    perms: Option<u32>,
+    owner: Option<(u32, u32)>,
+) -> Option<DuplicateKey> {
+    // Empty files gain nothing from sharing one.
+    size.filter(|&size| size > 0)
+        .map(|size| (hash.to_string(), size, perms, owner))
+}
+
+impl<'a> Extractor<'a> {
+    fn new(options: &'a DecodeOptions) -> Extractor<'a> {
+        let mut restore = options.restore.unwrap_or_else(Restore::all);
+        if restore.owner && !owner::can_restore() {
+            if options.restore.is_some() {
+                log::warn("restoring owners needs root on unix, ownership is not restored");
+            }
+            restore.owner = false;
+        }
+        Extractor {
+            options,
+            remaps: Vec::new(),
+            files: HashMap::new(),
+            dirs: HashMap::new(),
+            only: options.only.iter().map(|p| Glob::new(p)).collect(),
+            matched: 0,
+            restore,
+            seen: Seen::new(options.on_duplicate),
+            current: None,
+            originals: HashMap::new(),
+            linked: 0,
+            warned_scope: false,
+            links: Vec::new(),
+        }
+    }
+
+    fn wanted(&self, stored: &str) -> bool {
+        self.only.is_empty() || self.only.iter().any(|glob| glob.matches_path(stored))
+    }
+
+    fn wanted_hash(&self, hash: &str) -> bool {
+        match &self.options.hash_prefix {
+            Some(prefix) => hash_has_prefix(hash, prefix),
+            None => true,
+        }
+    }
+
+    fn describe(&self, path: &Path) -> String {
+        match self.files.get(path).or_else(|| self.dirs.get(path)) {
+            Some(stored) => format!("entry `{}`", stored),
+            None => format!("existing `{}`", path.display()),
+        }
+    }
+
+    /// Walks `target` down from the destination, resolving every component that is a file
+    /// where a directory is needed, or a directory where the file itself should go.
+    fn resolve(&mut self, stored: &str, target: &Path) -> io::Result<Option<PathBuf>> {
+        let dest = &self.options.dest;
+        let components: Vec<_> = target
+            .strip_prefix(dest)
+            .unwrap_or(target)
+            .components()
+            .collect();
+        let mut resolved = dest.to_path_buf();
+        for (i, component) in components.iter().enumerate() {
+            let mut next = resolved.join(component);
+            let is_last = i + 1 == components.len();
+            let clash = if is_last {
+                next.is_dir()
+            } else {
+                next.is_file()
+            };
+            if clash {
+                let message = if is_last {
+                    format!(
+                        "`{}` is a file, but {} made `{}` a directory",
+                        stored,
+                        self.describe(&next),
+                        next.display()
+                    )
+                } else {
+                    format!(
+                        "`{}` needs `{}` to be a directory, but {} is a file there",
+                        stored,
+                        next.display(),
+                        self.describe(&next)
+                    )
+                };
+                match self.options.conflict {
+                    Conflict::Error => {
+                        return Err(io::Error::new(io::ErrorKind::AlreadyExists, message))
+                    }
+                    Conflict::Skip => {
+                        log::warn(format!("skipped {}", message));
+                        return Ok(None);
+                    }
+                    Conflict::Rename => {
+                        let free = (1..)
+                            .map(|n| numbered(&next, n))
+                            .find(|candidate| {
+                                !candidate.exists() || (!is_last && candidate.is_dir())
+                            })
+                            .unwrap();
+                        log::warn(format!("{}, using `{}`", message, free.display()));
+                        next = free;
+                    }
+                }
+            }
+            if !is_last {
+                self.dirs
+                    .entry(next.clone())
+                    .or_insert_with(|| stored.to_string());
+            }
+            resolved = next;
+        }
+        if resolved != target {
+            match self.remaps.last_mut() {
+                Some(remap) if remap.stored == stored => remap.native = resolved.clone(),
+                _ => self.remaps.push(Remap {
+                    stored: stored.to_string(),
+                    native: resolved.clone(),
+                }),
+            }
+        }
+        Ok(Some(resolved))
+    }
+
+    /// Creates the file for `stored`, or returns `None` if `--only`, `--hash-prefix`,
+    /// `--on-duplicate`, a conflict or `--strip-components` made it skip the entry.
+    fn create(&mut self, stored: &str, hash: &str) -> io::Result<Option<File>> {
+        let target = match self.place(stored, hash)? {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+        if self.options.dedup_links {
+            // Truncating would change every file linked to this one as well.
+            self.originals.retain(|_, original| *original != target);
+            if target.is_file() {
+                fs::remove_file(&target)?;
+            }
+        }
+        // `File::create` would write wherever a symlink left there points.
+        if is_symlink(&target) {
+            fs::remove_file(&target)?;
+        }
+        let file = File::create(&target)?;
+        self.current = Some(target.clone());
+        self.files.insert(target, stored.to_string());
+        Ok(Some(file))
+    }
+
+    /// Creates the symlink `stored` to `link`, unless it would point outside the destination
+    /// and `--allow-external-symlinks` isn't set.
+    fn symlink(&mut self, stored: &str, hash: &str, link: &str) -> io::Result<()> {
+        let target = match self.place(stored, hash)? {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        // Resolved on disk, so symlinks extracted earlier count too.
+        let dest = fs::canonicalize(&self.options.dest)?;
+        let dir = fs::canonicalize(target.parent().unwrap())?;
+        let escapes = link_leaves(&dest, &dir, Path::new(link));
+        if escapes && !self.options.allow_external_symlinks {
+            log::warn(format!(
+                "refused symlink {} -> `{}`, it points outside {} (--allow-external-symlinks to create it)",
+                stored,
+                link,
+                self.options.dest.display()
+            ));
+            return Ok(());
+        }
+        if escapes {
+            log::warn(format!(
+                "symlink {} -> `{}` points outside {}",
+                stored,
+                link,
+                self.options.dest.display()
+            ));
+        }
+        // Replaced like `File::create` replaces a file.
+        if fs::symlink_metadata(&target).is_ok_and(|m| !m.is_dir()) {
+            fs::remove_file(&target)?;
+        }
+        symlink(Path::new(link), &target)?;
+        if !escapes {
+            self.links
+                .push((target.clone(), stored.to_string(), link.to_string()));
+        }
+        self.files.insert(target, stored.to_string());
+        Ok(())
+    }
+
+    /// Removes the symlinks that point outside the destination now that every entry is
+    /// extracted: one can pass through a link created after it, which `symlink` couldn't see.
+    fn recheck_links(&mut self) -> io::Result<()> {
+        if self.options.allow_external_symlinks || self.links.is_empty() {
+            return Ok(());
+        }
+        let dest = fs::canonicalize(&self.options.dest)?;
+        for (path, stored, link) in std::mem::take(&mut self.links) {
+            let dir = fs::canonicalize(path.parent().unwrap())?;
+            if is_symlink(&path) && link_leaves(&dest, &dir, Path::new(&link)) {
+                log::warn(format!(
+                    "removed symlink {} -> `{}`, through symlinks extracted after it it leads outside {}",
+                    stored,
+                    link,
+                    self.options.dest.display()
+                ));
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the file for a verified duplicate entry as a hard link to `original`, or as a
+    /// copy of it where the filesystem can't link the two.
+    fn hard_link(&mut self, entry: &Entry, original: &Path) -> io::Result<()> {
+        let target = match self.place(&entry.path, &entry.hash)? {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        self.files.insert(target.clone(), entry.path.clone());
+        // An entry stored twice over is already in place.
+        if target == original {
+            return Ok(());
+        }
+        if target.is_file() || is_symlink(&target) {
+            fs::remove_file(&target)?;
+        }
+        match fs::hard_link(original, &target) {
+            Ok(()) => {
+                log::verbose(log::FILES, || {
+                    format!("linked {} to {}", entry.path, original.display())
+                });
+                self.linked += 1;
+                Ok(())
+            }
+            Err(e) => {
+                log::warn(format!(
+                    "can't hard-link {} to {} ({}), copying it instead",
+                    entry.path,
+                    original.display(),
+                    e
+                ));
+                fs::copy(original, &target)?;
+                let file = fs::OpenOptions::new().write(true).open(&target)?;
+                self.restore(&file, entry)
+            }
+        }
+    }
+
+    /// `target`, or the first free `name-N.ext` next to it if an entry with another stored
+    /// path was already flattened to it. A rename is recorded as a remap.
+    fn decollide(&mut self, stored: &str, target: PathBuf) -> PathBuf {
+        let taken = |path: &PathBuf| {
+            self.files
+                .get(path)
+                .is_some_and(|earlier| earlier != stored)
+        };
+        if !taken(&target) {
+            return target;
+        }
+        let free = (1..)
+            .map(|n| numbered(&target, n))
+            .find(|candidate| !taken(candidate))
+            .unwrap();
+        log::verbose(log::FILES, || {
+            format!("renamed {} to {}", stored, free.display())
+        });
+        self.remaps.push(Remap {
+            stored: stored.to_string(),
+            native: free.clone(),
+        });
+        free
+    }
+
+    /// Where the file for `stored` goes, with its parent directories created, or `None` if
+    /// the entry is skipped.
+    fn place(&mut self, stored: &str, hash: &str) -> io::Result<Option<PathBuf>> {
+        let options = self.options;
+        if !self.wanted(stored) {
+            log::verbose(log::DECISIONS, || {
+                format!("skipped {} (not matched by --only)", stored)
+            });
+            return Ok(None);
+        }
+        if !self.wanted_hash(hash) {
+            log::verbose(log::DECISIONS, || {
+                format!("skipped {} (hash not matched by --hash-prefix)", stored)
+            });
+            return Ok(None);
+        }
+        self.matched += 1;
+        if !self.seen.admit(stored)? {
+            return Ok(None);
+        }
+        // Flattening strips every directory, which keeps the usual checks on the name.
+        let strip = if options.flatten {
+            split_stored(stored).count().saturating_sub(1)
+        } else {
+            options.strip_components
+        };
+        let target = match native_path(
+            &options.dest,
+            stored,
+            strip,
+            options.reserved_names,
+            &mut self.remaps,
+        )? {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+        let target = if options.flatten {
+            self.decollide(stored, target)
+        } else {
+            target
+        };
+        let target = match self.resolve(stored, &target)? {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+        // A symlink extracted earlier could lead anywhere, whatever its own target looked like.
+        if let Some(link) = symlink_ancestor(&options.dest, &target) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "`{}` would be extracted through the symlink `{}`, which isn't followed",
+                    stored,
+                    link.display()
+                ),
+            ));
+        }
+        create_dir_all(target.parent().unwrap())?;
+        Ok(Some(target))
+    }
+}
+
+/// Where decoded entries go.
+trait Target {
+    /// Opens the file `stored`, recorded with `hash`, is streamed into, or `None` to skip
+    /// the entry.
+    fn open(&mut self, stored: &str, hash: &str) -> io::Result<Option<File>>;
+
+    /// Takes an entry whose body was buffered in memory instead.
+    fn store(&mut self, entry: Entry) -> io::Result<()>;
+
+    /// Whether bodies are buffered and handed to `store` rather than streamed to `open`.
+    fn buffers(&self) -> bool;
+
+    /// Whether bodies are skipped unread, leaving every entry empty and unverified.
+    fn skips_bodies(&self) -> bool;
+
+    /// Whether entries with a `bodyhash=` are checked by it alone, without being decoded.
+    fn checks_stored_only(&self) -> bool;
+
+    /// Applies the entry's recorded metadata to the file it was streamed into.
+    fn restore(&self, file: &File, entry: &Entry) -> io::Result<()>;
+
+    /// Takes a `symlink=` entry pointing to `link`.
+    fn link(&mut self, entry: &Entry, link: &str) -> io::Result<()>;
+
+    /// Removes the file last opened, when decoding stops partway through its entry.
+    fn abandon(&mut self) {}
+
+    /// An earlier file with the content `separator` records, which the entry can be linked
+    /// to instead of written out again, see `--dedup-links`.
+    fn duplicate_of(&mut self, _: &separator::Separator, _: HashScope) -> Option<PathBuf> {
+        None
+    }
+
+    /// Creates a verified entry that `duplicate_of` matched with the file `original`.
+    fn duplicate(&mut self, _: &Entry, _: &Path) -> io::Result<()> {
+        unreachable!("only targets that find duplicates take them")
+    }
+
+    /// Notes that the entry last opened was written out and matches its hash.
+    fn extracted(&mut self, _: &Entry) {}
+}
+
+impl<'a> Target for Extractor<'a> {
+    fn open(&mut self, stored: &str, hash: &str) -> io::Result<Option<File>> {
+        self.create(stored, hash)
+    }
+
+    fn abandon(&mut self) {
+        if let Some(path) = self.current.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Only content-scoped hashes say two entries hold the same bytes, whatever their paths.
+    fn duplicate_of(
+        &mut self,
+        separator: &separator::Separator,
+        scope: HashScope,
+    ) -> Option<PathBuf> {
+        if !self.options.dedup_links || separator.link.is_some() {
+            return None;
+        }
+        if scope != HashScope::Content {
+            if !std::mem::replace(&mut self.warned_scope, true) {
+                log::warn(
+                    "--dedup-links has no effect, the archive's hashes cover the paths too (--hash-scope path-content)",
+                );
+            }
+            return None;
+        }
+        if !(self.wanted(&separator.path) && self.wanted_hash(&separator.hash)) {
+            return None;
+        }
+        let key = duplicate_key(
+            &separator.hash,
+            separator.size,
+            separator.perms,
+            separator.owner,
+        )?;
+        self.originals.get(&key).cloned()
+    }
+
+    fn duplicate(&mut self, entry: &Entry, original: &Path) -> io::Result<()> {
+        self.hard_link(entry, original)
+    }
+
+    fn extracted(&mut self, entry: &Entry) {
+        if !self.options.dedup_links {
+            return;
+        }
+        let key = duplicate_key(&entry.hash, entry.size, entry.perms, entry.owner);
+        if let (Some(key), Some(path)) = (key, self.current.clone()) {
+            self.originals.entry(key).or_insert(path);
+        }
+    }
+
+    /// Writes out a buffered `--best-effort` entry.
+    fn store(&mut self, entry: Entry) -> io::Result<()> {
+        if let Some(mut file) = self.create(&entry.path, &entry.hash)? {
+            file.write_all(&entry.body)?;
+            self.restore(&file, &entry)?;
+        }
+        Ok(())
+    }
+
+    fn buffers(&self) -> bool {
+        self.options.best_effort
+    }
+
+    fn skips_bodies(&self) -> bool {
+        self.options.touch_only
+    }
+
+    fn checks_stored_only(&self) -> bool {
+        false
+    }
+
+    /// Sets what `--restore` asks for. An attribute that can't be set is warned about and
+    /// left as it is, keeping the entry. Ownership goes first, as a chown clears setuid bits.
+    fn restore(&self, file: &File, entry: &Entry) -> io::Result<()> {
+        let warn = |attribute: &str, e: io::Error| {
+            log::warn(format!(
+                "can't restore the {} of {}: {}",
+                attribute, entry.path, e
+            ));
+        };
+        match entry.owner {
+            Some(ids) if self.restore.owner => {
+                owner::restore(file, ids).unwrap_or_else(|e| warn("owner", e))
+            }
+            _ => {}
+        }
+        match entry.perms {
+            Some(perms) if self.restore.perms => {
+                perms::restore(file, perms).unwrap_or_else(|e| warn("permissions", e))
+            }
+            _ => {}
+        }
+        match entry.mtime {
+            Some(mtime) if self.restore.mtime => file
+                .set_modified(UNIX_EPOCH + Duration::from_secs(mtime))
+                .unwrap_or_else(|e| warn("mtime", e)),
+            _ => {}
+        }
+        match entry.btime {
+            Some(btime) if self.restore.btime => {
+                btime::restore(file, btime).unwrap_or_else(|e| warn("birth time", e))
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn link(&mut self, entry: &Entry, link: &str) -> io::Result<()> {
+        self.symlink(&entry.path, &entry.hash, link)
+    }
+}
+
+/// Collects every entry's content in memory, keyed by its stored path.
+struct MapTarget {
+    entries: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+impl Target for MapTarget {
+    fn open(&mut self, _: &str, _: &str) -> io::Result<Option<File>> {
+        unreachable!("in-memory entries are always buffered")
+    }
+
+    fn store(&mut self, entry: Entry) -> io::Result<()> {
+        let path: PathBuf = split_stored(&entry.path).collect();
+        self.entries.insert(path, entry.body);
+        Ok(())
+    }
+
+    fn buffers(&self) -> bool {
+        true
+    }
+
+    fn skips_bodies(&self) -> bool {
+        false
+    }
+
+    fn checks_stored_only(&self) -> bool {
+        false
+    }
+
+    fn restore(&self, _: &File, _: &Entry) -> io::Result<()> {
+        unreachable!("in-memory entries have no file")
+    }
+
+    /// Symlinks have no content, so they are left out.
+    fn link(&mut self, _: &Entry, _: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Records every entry's stored path and discards its body.
+struct ListTarget<'a> {
+    paths: Vec<String>,
+    hash_prefix: Option<&'a str>,
+}
+
+impl<'a> Target for ListTarget<'a> {
+    fn open(&mut self, stored: &str, hash: &str) -> io::Result<Option<File>> {
+        if self
+            .hash_prefix
+            .is_none_or(|prefix| hash_has_prefix(hash, prefix))
+        {
+            self.paths.push(stored.to_string());
+        }
+        Ok(None)
+    }
+
+    fn store(&mut self, _: Entry) -> io::Result<()> {
+        unreachable!("listed entries are never buffered")
+    }
+
+    fn buffers(&self) -> bool {
+        false
+    }
+
+    fn skips_bodies(&self) -> bool {
+        false
+    }
+
+    fn checks_stored_only(&self) -> bool {
+        false
+    }
+
+    fn restore(&self, _: &File, _: &Entry) -> io::Result<()> {
+        unreachable!("listed entries have no file")
+    }
+
+    fn link(&mut self, entry: &Entry, _: &str) -> io::Result<()> {
+        self.open(&entry.path, &entry.hash)?;
+        Ok(())
+    }
+}
+
+/// Checks every entry against its recorded hash and keeps nothing.
+struct VerifyTarget {
+    seen: Seen,
+    entries: usize,
+    // `--fast`: check the stored bytes of entries that have a `bodyhash=`.
+    fast: bool,
+}
+
+impl Target for VerifyTarget {
+    fn open(&mut self, stored: &str, _: &str) -> io::Result<Option<File>> {
+        self.seen.admit(stored)?;
+        self.entries += 1;
+        Ok(None)
+    }
+
+    fn store(&mut self, _: Entry) -> io::Result<()> {
+        unreachable!("verified entries are never buffered")
+    }
+
+    fn buffers(&self) -> bool {
+        false
+    }
+
+    fn skips_bodies(&self) -> bool {
+        false
+    }
+
+    fn checks_stored_only(&self) -> bool {
+        self.fast
+    }
+
+    fn restore(&self, _: &File, _: &Entry) -> io::Result<()> {
+        unreachable!("verified entries have no file")
+    }
+
+    fn link(&mut self, entry: &Entry, _: &str) -> io::Result<()> {
+        self.open(&entry.path, &entry.hash)?;
+        Ok(())
+    }
+}
+
+/// Writes every entry into a zip archive instead of the filesystem, see `--to-zip`.
+struct ZipTarget<'a> {
+    options: &'a DecodeOptions,
+    zip: ZipWriter<io::BufWriter<AtomicFile>>,
+    only: Vec<Glob>,
+    matched: usize,
+    seen: Seen,
+    // The stored path each name in the zip was written for, to keep `--flatten` apart.
+    names: HashMap<String, String>,
+    restore: Restore,
+    written: usize,
+}
+
+impl<'a> ZipTarget<'a> {
+    /// The name `stored` has in the zip, or `None` if the entry is skipped. Paths are
+    /// stripped and flattened as on disk; reserved names are left alone, as a zip has no
+    /// filesystem to trip over them.
+    fn name(&mut self, stored: &str, hash: &str) -> io::Result<Option<String>> {
+        let options = self.options;
+        if !(self.only.is_empty() || self.only.iter().any(|glob| glob.matches_path(stored))) {
+            log::verbose(log::DECISIONS, || {
+                format!("skipped {} (not matched by --only)", stored)
+            });
+            return Ok(None);
+        }
+        if let Some(prefix) = options.hash_prefix.as_deref() {
+            if !hash_has_prefix(hash, prefix) {
+                log::verbose(log::DECISIONS, || {
+                    format!("skipped {} (hash not matched by --hash-prefix)", stored)
+                });
+                return Ok(None);
+            }
+        }
+        self.matched += 1;
+        if !self.seen.admit(stored)? {
+            return Ok(None);
+        }
+        let components: Vec<_> = split_stored(stored).collect();
+        let strip = if options.flatten {
+            components.len().saturating_sub(1)
+        } else {
+            options.strip_components
+        };
+        let components = components.get(strip..).unwrap_or_default();
+        if components.is_empty() {
+            log::warn(format!(
+                "skipped `{}`, nothing is left after stripping {} components",
+                stored, strip
+            ));
+            return Ok(None);
+        }
+        if components.contains(&"..") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("`{}` points outside the archive root", stored),
+            ));
+        }
+        let name = components.join("/");
+        let taken = |name: &str| {
+            self.names
+                .get(name)
+                .is_some_and(|earlier| earlier != stored)
+        };
+        let name = if taken(&name) {
+            let free = (1..)
+                .map(|n| numbered(Path::new(&name), n).to_string_lossy().into_owned())
+                .find(|candidate| !taken(candidate))
+                .unwrap();
+            log::verbose(log::FILES, || format!("renamed {} to {}", stored, free));
+            free
+        } else {
+            name
+        };
+        self.names.insert(name.clone(), stored.to_string());
+        Ok(Some(name))
+    }
+
+    fn mtime(&self, entry: &Entry) -> Option<u64> {
+        entry.mtime.filter(|_| self.restore.mtime)
+    }
+}
+
+impl<'a> Target for ZipTarget<'a> {
+    fn open(&mut self, _: &str, _: &str) -> io::Result<Option<File>> {
+        unreachable!("zipped entries are always buffered")
+    }
+
+    fn store(&mut self, entry: Entry) -> io::Result<()> {
+        if let Some(name) = self.name(&entry.path, &entry.hash)? {
+            let perms = entry.perms.filter(|_| self.restore.perms).unwrap_or(0o644);
+            let mtime = self.mtime(&entry);
+            self.zip.append(&name, &entry.body, perms, mtime)?;
+            self.written += 1;
+        }
+        Ok(())
+    }
+
+    fn buffers(&self) -> bool {
+        true
+    }
+
+    fn skips_bodies(&self) -> bool {
+        false
+    }
+
+    fn checks_stored_only(&self) -> bool {
+        false
+    }
+
+    fn restore(&self, _: &File, _: &Entry) -> io::Result<()> {
+        unreachable!("zipped entries have no file")
+    }
+
+    /// Zip has no external-symlink check to make; the link is stored as recorded.
+    fn link(&mut self, entry: &Entry, link: &str) -> io::Result<()> {
+        if let Some(name) = self.name(&entry.path, &entry.hash)? {
+            let mtime = self.mtime(entry);
+            self.zip.append_symlink(&name, link, mtime)?;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Called with each extracted entry's stored path and size. `Break` stops decoding before
+/// the next entry.
+pub type Progress<'a> = &'a mut dyn FnMut(&Path, u64) -> ControlFlow<()>;
+
+fn finish_entry(
+    entry: Entry,
+    target: &mut dyn Target,
+    recovery: &mut Recovery,
+    checks: Checks,
+    progress: Progress,
+) -> io::Result<ControlFlow<()>> {
+    let mut entry = entry;
+    // Skipped bodies leave nothing to unpack or verify, and bodies only checked as stored
+    // leave nothing to unpack.
+    let unread = target.skips_bodies();
+    let decoded = !unread && !entry.stored_only;
+    if !unread && !entry.stored_verified() {
+        let msg = format!(
+            "`{}` doesn't match its recorded bodyhash as stored; the archive is corrupt",
+            entry.path
+        );
+        checks.damaged(&mut entry, msg)?;
+    }
+    if entry.stored_only {
+        recovery.stored_only += 1;
+    }
+    if let Some(inflater) = entry.inflater.take().filter(|_| decoded) {
+        match inflater.finish() {
+            Ok(rest) => entry.push_decoded(&rest)?,
+            Err(e) => {
+                let msg = format!("`{}` failed to inflate ({})", entry.path, e);
+                checks.damaged(&mut entry, msg)?;
+            }
+        }
+    }
+    if decoded {
+        entry.finish_holes()?;
+    }
+    recovery.recovered += 1;
+    if entry.corrupt || (decoded && !entry.verified()) {
+        if checks.best_effort {
+            recovery.unverified.push(entry.path.clone());
+        } else if !entry.corrupt {
+            let msg = match entry.size.filter(|&size| !entry.sparse && size != entry.written) {
+                Some(size) => format!(
+                    "`{}` decodes to {} bytes as {} but its recorded size is {}, so it doesn't match its hash",
+                    entry.path,
+                    entry.written,
+                    entry.encoding.name(),
+                    size
+                ),
+                None => format!(
+                    "`{}` doesn't match its recorded hash when decoded as {}; the archive may mix modes or be corrupt",
+                    entry.path,
+                    entry.encoding.name()
+                ),
+            };
+            checks.report_entry(&mut entry, msg)?;
+        }
+    }
+    let failed = std::mem::take(&mut entry.failures);
+    recovery
+        .failures
+        .extend(failed.into_iter().map(|reason| Failure {
+            path: entry.path.clone(),
+            reason,
+        }));
+    let (path, written) = (entry.path.clone(), entry.written);
+    if let Some(link) = &entry.link {
+        target.link(&entry, link)?;
+        return Ok(progress(Path::new(&path), 0));
+    }
+    if let Some(original) = entry.duplicate_of.take() {
+        // A damaged copy still gets the content its hash promises, from the verified original.
+        target.duplicate(&entry, &original)?;
+        return Ok(progress(Path::new(&path), written));
+    }
+    match &entry.file {
+        Some(file) => {
+            target.restore(file, &entry)?;
+            if decoded && entry.verified() {
+                target.extracted(&entry);
+            }
+        }
+        None if entry.skipped => return Ok(ControlFlow::Continue(())),
+        None => target.store(entry)?,
+    }
+    Ok(progress(Path::new(&path), written))
+}
+
+/// A read error from one layer of the archive's encoding.
+#[derive(Debug)]
+struct LayerError {
+    layer: &'static str,
+    source: io::Error,
+}
+
+impl fmt::Display for LayerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.layer, self.source)
+    }
+}
+
+impl Error for LayerError {}
+
+/// Names the layer read errors come from, so a damaged or wrong-mode compressed stream
+/// reports as such instead of as a bad entry.
+struct Layer<R> {
+    inner: R,
+    layer: &'static str,
+}
+
+impl<R: Read> Read for Layer<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(|e| {
+            // Errors from a layer further in are already named.
+            let named = e.get_ref().is_some_and(|inner| inner.is::<LayerError>());
+            if named || e.kind() == io::ErrorKind::Interrupted {
+                return e;
+            }
+            let layer = self.layer;
+            io::Error::new(io::ErrorKind::InvalidData, LayerError { layer, source: e })
+        })
+    }
+}
+
+/// Undoes the archive-level encoding of `mode`, leaving the separators and entry bodies.
+fn archive_reader<'a, R: Read>(src: &'a mut R, mode: &Mode) -> Box<dyn Read + 'a> {
+    let inflate = |compressed: Box<dyn Read + 'a>| Layer {
+        inner: SectionReader::new(BufReader::new(compressed)),
+        layer: "archive failed to decompress (damaged, or not encoded with this mode)",
+    };
+    match mode {
+        Mode::CompressedBinary => Box::new(inflate(Box::new(src))),
+        Mode::CompressedTxt => Box::new(inflate(Box::new(Layer {
+            // The alphabet is in the header, inside this layer, so it comes from the data.
+            inner: Base64Reader::with_alphabet(src, None),
+            layer: "archive is not valid base64 (damaged, or not encoded with --text)",
+        }))),
+        _ => Box::new(src),
+    }
+}
+
+/// Decodes one base64 body line in `alphabet`.
+fn decode_body(line: &[u8], alphabet: Alphabet) -> Result<Vec<u8>, base64::DecodeError> {
+    base64::decode_config(line.strip_suffix(b"\n").unwrap_or(line), alphabet.config())
+}
+
+/// Reads every entry from `reader` into `target`. With `best_effort`, a truncated or corrupt
+/// archive yields the entries before the damage instead of an error.
+/// Archives without a header are read as format `compat`, or 0.
+fn decode_entries(
+    reader: impl Read,
+    mode: &Mode,
+    compat: Option<u32>,
+    checks: Checks,
+    target: &mut dyn Target,
+    progress: Progress,
+) -> io::Result<Recovery> {
+    let best_effort = checks.best_effort;
+    let mut lines = LineReader::with_chunk_size(reader, DECODE_CHUNK);
+    let mut line = Vec::new();
+
+    let mut entry: Option<Entry> = None;
+    let mut recovery = Recovery::default();
+    // Every entry ends with a newline, so anything else means the last one was cut short.
+    let mut complete = true;
+    // Entries without an `enc` field use the mode the archive header declares.
+    let mut default_encoding = Encoding::for_mode(mode);
+    let mut hash_scope = HashScope::Content;
+    let mut alphabet = Alphabet::Standard;
+    let mut version = compat.unwrap_or(0);
+    let mut first = true;
+    // What is left of a `section=raw` body, which is read by length rather than by line.
+    let mut raw_left = 0;
+    // The bytes of the current entry's body lines read so far.
+    let mut body_read = 0;
+    loop {
+        if let Err(e) = interrupt::check() {
+            // Closed first, which Windows needs to remove it.
+            if entry.take().is_some_and(|entry| entry.file.is_some()) {
+                target.abandon();
+            }
+            return Err(e);
+        }
+        let raw = raw_left > 0;
+        let read = if raw {
+            lines.next_bytes(raw_left, &mut line)
+        } else {
+            lines.next_line(&mut line)
+        };
+        match read {
+            Ok(true) => {}
+            Ok(false) => break,
+            // A truncated compressed stream fails to decompress at the cut.
+            Err(e) if best_effort => {
+                log::warn(format!("archive is truncated or corrupt: {}", e));
+                complete = false;
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+        if raw {
+            raw_left -= line.len() as u64;
+            // The newline after the body is still to come.
+            complete = false;
+        } else {
+            complete = line.ends_with(b"\n");
+        }
+        let first_line = std::mem::replace(&mut first, false);
+        if !raw && line == trailer::MARKER.as_bytes() {
+            break;
+        }
+
+        // A header line inside a plain body is content, not a concatenated archive.
+        let between_entries = entry
+            .as_ref()
+            .is_none_or(|entry| entry.body_done(body_read));
+        if !raw && between_entries && line.starts_with(header::MAGIC.as_bytes()) {
+            match Header::parse(&line) {
+                Ok(header) => {
+                    if let Some(entry) = entry.take() {
+                        if finish_entry(entry, target, &mut recovery, checks, progress)?.is_break()
+                        {
+                            return Ok(recovery);
+                        }
+                    }
+                    if !first_line {
+                        checks.report(format!(
+                            "another archive header after {}; the archive looks like several concatenated",
+                            counted(recovery.seen, "entry", "entries")
+                        ))?;
+                    }
+                    if header.mode != *mode {
+                        checks.report(format!(
+                            "archive was encoded as {} but is decoded as {}; using {} for its entries",
+                            header.mode, mode, header.mode
+                        ))?;
+                    }
+                    if header.version > header::VERSION {
+                        checks.report(format!(
+                            "archive is format version {}, newer than the {} this mito reads; fields it added are ignored",
+                            header.version,
+                            header::VERSION
+                        ))?;
+                    }
+                    default_encoding = Encoding::for_mode(&header.mode);
+                    hash_scope = header.hash_scope;
+                    alphabet = header.alphabet;
+                    version = header.version;
+                    continue;
+                }
+                Err(e) if first_line => {
+                    checks.report(format!("malformed archive header: {}", e))?;
+                    continue;
+                }
+                // Past the first line this is just content that looks like a header.
+                Err(_) => {}
+            }
+        }
+
+        let separator = if raw {
+            None
+        } else {
+            separator::from_line(&line, version)?
+        };
+        body_read += line.len() as u64;
+        if let Some(separator) = separator {
+            body_read = 0;
+            let path = &separator.path[..];
+            let hash = &separator.hash[..];
+            // A raw body is the content as it is, followed by a newline like a plain one.
+            let encoding = if separator.raw {
+                Encoding::Plain
+            } else {
+                separator.encoding.unwrap_or(default_encoding)
+            };
+            let mtime = separator.mtime;
+            if let Some(entry) = entry.take() {
+                if finish_entry(entry, target, &mut recovery, checks, progress)?.is_break() {
+                    return Ok(recovery);
+                }
+            }
+            recovery.seen += 1;
+            if let Some(max) = checks.max_entries.filter(|&max| recovery.seen > max) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "the archive has more than {}, the --max-entries limit (stopped at {})",
+                        counted(max, "entry", "entries"),
+                        path
+                    ),
+                ));
+            }
+            log::verbose(log::FILES, || format!("extracted {}", path));
+            let duplicate_of = if target.buffers() {
+                None
+            } else {
+                target.duplicate_of(&separator, hash_scope)
+            };
+            let (file, skipped) =
+                if target.buffers() || separator.link.is_some() || duplicate_of.is_some() {
+                    (None, false)
+                } else {
+                    let file = target.open(path, hash)?;
+                    let skipped = file.is_none();
+                    (file, skipped)
+                };
+            entry = Some(Entry {
+                path: path.to_string(),
+                hash: hash.to_string(),
+                encoding,
+                mtime,
+                btime: separator.btime,
+                perms: separator.perms,
+                owner: separator.owner,
+                file,
+                body: Vec::new(),
+                hasher: hash_scope.hasher(path),
+                newline_pending: false,
+                holes: separator.holes.iter().copied().collect(),
+                offset: 0,
+                sparse: !separator.holes.is_empty(),
+                inflater: match separator.codec {
+                    Codec::Store => None,
+                    Codec::Deflate => Some(ZlibInflater::new(Vec::new())),
+                },
+                codec: separator.codec,
+                link: separator.link.clone(),
+                stored_only: target.checks_stored_only() && separator.body_hash.is_some(),
+                body_hash: separator.body_hash.clone(),
+                body_hasher: DefaultHasher::new(),
+                body_newline_pending: false,
+                written: 0,
+                size: separator.size,
+                corrupt: false,
+                skipped,
+                duplicate_of,
+                failures: Vec::new(),
+            });
+            if separator.raw {
+                raw_left = separator.size.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("`{}` is in a raw section but has no size", path),
+                    )
+                })?;
+            }
+        } else if target.skips_bodies() {
+            // Body lines are read past without being decoded.
+        } else if let Some(entry) = entry.as_mut() {
+            entry.push_stored(&line);
+            if entry.stored_only {
+                continue;
+            }
+            match entry.encoding {
+                Encoding::Plain => entry.push_plain(&line)?,
+                Encoding::Base64 => match decode_body(&line, alphabet) {
+                    Ok(decoded) => match entry.unpack(&decoded) {
+                        Ok(content) => entry.push_decoded(&content)?,
+                        Err(e) => {
+                            entry.inflater = None;
+                            let msg = format!("`{}` failed to inflate ({})", entry.path, e);
+                            checks.damaged(entry, msg)?;
+                        }
+                    },
+                    Err(e) => {
+                        let msg = match Alphabet::of(&line) {
+                            Some(other) if other != alphabet => format!(
+                                "`{}` is base64 in the {} alphabet, but the archive declares {}",
+                                entry.path,
+                                other.name(),
+                                alphabet.name()
+                            ),
+                            _ => format!(
+                                "`{}` has an invalid base64 body ({}); the archive may mix modes or be corrupt",
+                                entry.path, e
+                            ),
+                        };
+                        checks.damaged(entry, msg)?;
+                    }
+                },
+            }
+            // The hash can only be compared once the entry ends, but a body longer than its
+            // recorded size already can't match it.
+            let overrun = entry.size.filter(|&size| entry.written > size);
+            if let Some(size) = overrun.filter(|_| checks.stop_on_first && !entry.corrupt) {
+                let msg = format!(
+                    "`{}` runs past its recorded size of {} bytes, so it can't match its hash",
+                    entry.path, size
+                );
+                checks.damaged(entry, msg)?;
+            }
+        }
+    }
+    if let Some(entry) = entry {
+        if best_effort && !complete {
+            log::warn(format!("skipped incomplete entry {}", entry.path));
+        } else {
+            // The last entry, so there is nothing left to cancel.
+            let _ = finish_entry(entry, target, &mut recovery, checks, progress)?;
+        }
+    }
+    Ok(recovery)
+}
+
+/// Extracts `archive` into `options.dest`.
+pub fn decode_dir(archive: &Path, mode: Mode, options: &DecodeOptions) -> io::Result<()> {
+    decode_dir_with_progress(
+        archive,
+        mode,
+        options,
+        &mut |_, _| ControlFlow::Continue(()),
+    )
+}
+
+/// Like [`decode_dir`], calling `progress` after every extracted entry with its stored path
+/// and size, e.g. to drive a progress bar. Returning `Break` stops cleanly after that entry:
+/// the files extracted so far are left in place, and no partial file is written.
+pub fn decode_dir_with_progress(
+    archive: &Path,
+    mode: Mode,
+    options: &DecodeOptions,
+    progress: Progress,
+) -> io::Result<()> {
+    let mut file = volumes::open(archive, options.volumes)?;
+    let mode = recorded_mode(&mut file, mode, archive)?;
+    if let Some(expected) = &options.expect_root {
+        check_root(archive, mode, options, expected)?;
+    }
+    let mut extractor = Extractor::new(options);
+    // Reading the archive twice needs a regular file rather than a pipe.
+    // `--touch-only` writes no content, so it needs no space for it either.
+    if options.space_check && !options.touch_only && file.is_file()? {
+        match read_index(
+            volumes::open(archive, options.volumes)?,
+            mode,
+            options.compat,
+        ) {
+            Ok(index) => check_space(&index, &extractor)?,
+            Err(e) if options.best_effort => {
+                log::warn(format!("skipped the free space check: {}", e))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    // With `--only`, an indexed archive is read at just the wanted entries. Archives without
+    // an index, compressed ones and inputs that can't seek are scanned instead.
+    let uncompressed = matches!(mode, Mode::Plain | Mode::Base64 | Mode::Auto);
+    let index = if options.only.is_empty() || !uncompressed {
+        None
+    } else {
+        trailer::read(&mut file).unwrap_or(None)
+    };
+    let reader: Box<dyn Read + '_> = match index {
+        Some(index) => {
+            // The header reaches up to the first entry and sets the mode and hash scope.
+            let header = index.first().map_or(0, |entry| entry.offset);
+            let wanted = index
+                .iter()
+                .filter(|entry| extractor.wanted(&entry.path))
+                .map(|entry| (entry.offset, entry.len));
+            let ranges: Vec<_> = std::iter::once((0, header)).chain(wanted).collect();
+            log::verbose(log::DECISIONS, || {
+                format!(
+                    "read {} through the index",
+                    counted(ranges.len() - 1, "entry", "entries")
+                )
+            });
+            Box::new(Ranges::new(&mut file, ranges))
+        }
+        None => archive_reader(&mut file, &mode),
+    };
+    let recovery = decode_entries(
+        reader,
+        &mode,
+        options.compat,
+        Checks {
+            best_effort: options.best_effort,
+            strict: options.strict,
+            verify: false,
+            stop_on_first: false,
+            keep_going: false,
+            max_entries: options.max_entries,
+        },
+        &mut extractor,
+        progress,
+    );
+    // Also after a failed decode, so no link it left behind leads out of the destination.
+    extractor.recheck_links()?;
+    let recovery = recovery?;
+
+    if let Some(prefix) = &options.hash_prefix {
+        if extractor.matched == 0 {
+            return Err(no_hash_match(prefix));
+        }
+    }
+
+    if options.best_effort {
+        eprintln!(
+            "recovered {} of {}",
+            recovery.recovered,
+            counted(recovery.seen, "entry", "entries")
+        );
+        for path in &recovery.unverified {
+            eprintln!("unverified: {}", path);
+        }
+    }
+
+    if options.touch_only {
+        eprintln!(
+            "touched {} in {}",
+            counted(extractor.files.len(), "entry", "entries"),
+            counted(extractor.dirs.len(), "directory", "directories")
+        );
+    }
+
+    if let Some(max) = options.max_entries {
+        eprintln!(
+            "read {}, within --max-entries {}",
+            counted(recovery.seen, "entry", "entries"),
+            max
+        );
+    }
+
+    if extractor.linked > 0 {
+        eprintln!(
+            "hard-linked {} to earlier ones with the same content",
+            counted(extractor.linked, "entry", "entries")
+        );
+    }
+
+    let remaps = extractor.remaps;
+    if !remaps.is_empty() {
+        let mut log_path = options.dest.clone().into_os_string();
+        log_path.push(REMAPS_SUFFIX);
+        let log_path = PathBuf::from(log_path);
+        let mut log = File::create(&log_path)?;
+        for remap in &remaps {
+            writeln!(log, "{}\t{}", remap.stored, remap.native.display())?;
+        }
+        eprintln!(
+            "{} paths remapped, see {}",
+            remaps.len(),
+            log_path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Decodes `archive` into a zip archive at `zip` rather than onto the filesystem, with
+/// the same selection, stripping and checks as [`decode_dir`]. The recorded permissions and
+/// mtimes go into the zip as far as `options.restore` asks for them; ownership has no place
+/// there. `options.dest` and the options about writing files are not used.
+pub fn decode_to_zip(
+    archive: &Path,
+    mode: Mode,
+    options: &DecodeOptions,
+    zip: &Path,
+) -> io::Result<()> {
+    let mut file = volumes::open(archive, options.volumes)?;
+    let mode = recorded_mode(&mut file, mode, archive)?;
+    if let Some(expected) = &options.expect_root {
+        check_root(archive, mode, options, expected)?;
+    }
+    let out = io::BufWriter::new(AtomicFile::create(zip, None)?);
+    let mut target = ZipTarget {
+        options,
+        zip: ZipWriter::new(out, Compression::new(COMPRESSION_LEVEL)),
+        only: options.only.iter().map(|p| Glob::new(p)).collect(),
+        matched: 0,
+        seen: Seen::new(options.on_duplicate),
+        names: HashMap::new(),
+        restore: options.restore.unwrap_or_else(Restore::all),
+        written: 0,
+    };
+    let recovery = decode_entries(
+        archive_reader(&mut file, &mode),
+        &mode,
+        options.compat,
+        Checks {
+            best_effort: options.best_effort,
+            strict: options.strict,
+            verify: false,
+            stop_on_first: false,
+            keep_going: false,
+            max_entries: options.max_entries,
+        },
+        &mut target,
+        &mut |_, _| ControlFlow::Continue(()),
+    )?;
+    if let Some(prefix) = &options.hash_prefix {
+        if target.matched == 0 {
+            return Err(no_hash_match(prefix));
+        }
+    }
+    if options.best_effort {
+        eprintln!(
+            "recovered {} of {}",
+            recovery.recovered,
+            counted(recovery.seen, "entry", "entries")
+        );
+        for path in &recovery.unverified {
+            eprintln!("unverified: {}", path);
+        }
+    }
+    let written = target.written;
+    let out = target.zip.finish()?;
+    out.into_inner().map_err(|e| e.into_error())?.commit()?;
+    eprintln!(
+        "wrote {} to {}",
+        counted(written, "entry", "entries"),
+        zip.display()
+    );
+    Ok(())
+}
+
+/// Fails with "need X, have Y" if the entries `extractor` wants don't fit in the free space
+/// at the destination.
+fn check_space(index: &Index, extractor: &Extractor) -> io::Result<()> {
+    let wanted = |path: &&String| {
+        extractor.wanted(path)
+            && index
+                .entries
+                .get(*path)
+                .is_some_and(|hash| extractor.wanted_hash(hash))
+    };
+    let without_size =
+        index.entries.keys().filter(wanted).count() - index.sizes.keys().filter(wanted).count();
+    if without_size > 0 {
+        log::warn(if without_size == 1 {
+            "1 entry has no recorded size, it isn't counted in the free space check".to_string()
+        } else {
+            format!(
+                "{} entries have no recorded size, they aren't counted in the free space check",
+                without_size
+            )
+        });
+    }
+    let need: u64 = index
+        .sizes
+        .iter()
+        .filter(|(path, _)| wanted(path))
+        .map(|(_, size)| size)
+        .sum();
+    let have = match space::available(&extractor.options.dest)? {
+        Some(have) => have,
+        None => return Ok(()),
+    };
+    log::verbose(log::DECISIONS, || {
+        format!("need {} bytes, {} free", need, have)
+    });
+    if need > have {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "not enough space to extract into {}: need {}, have {} (--no-space-check to try anyway)",
+                extractor.options.dest.display(),
+                format_size(need),
+                format_size(have)
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Decodes an archive read from `src` without touching the filesystem, returning every
+/// entry's content keyed by its stored path (`./` and empty components removed).
+///
+/// The whole decoded archive is held in memory at once, so this suits archives that fit
+/// comfortably in RAM; use [`decode_dir`] to stream larger ones to disk. An entry stored
+/// more than once keeps its last content, as it would on disk. Entries that don't match the
+/// archive's declared mode or their recorded hash are errors, as with `--strict`.
+pub fn decode_to_map(mut src: impl Read, mode: Mode) -> io::Result<BTreeMap<PathBuf, Vec<u8>>> {
+    let mut target = MapTarget {
+        entries: BTreeMap::new(),
+    };
+    let checks = Checks {
+        best_effort: false,
+        strict: true,
+        verify: false,
+        stop_on_first: false,
+        keep_going: false,
+        max_entries: None,
+    };
+    decode_entries(
+        archive_reader(&mut src, &mode),
+        &mode,
+        None,
+        checks,
+        &mut target,
+        &mut |_, _| ControlFlow::Continue(()),
+    )?;
+    Ok(target.entries)
+}
+
+/// The stored path of every entry in an archive read from `src`, in archive order. Bodies
+/// are decoded to be checked against their hashes, but not kept.
+pub fn list_entries(src: impl Read, mode: Mode) -> io::Result<Vec<String>> {
+    list_matching(src, mode, None)
+}
+
+/// Like [`list_entries`], keeping only the entries whose recorded hash starts with
+/// `hash_prefix`, see [`hash_has_prefix`]. Fails if a prefix is given and nothing matches.
+pub fn list_matching(
+    mut src: impl Read,
+    mode: Mode,
+    hash_prefix: Option<&str>,
+) -> io::Result<Vec<String>> {
+    let mut target = ListTarget {
+        paths: Vec::new(),
+        hash_prefix,
+    };
+    let checks = Checks {
+        best_effort: false,
+        strict: false,
+        verify: false,
+        stop_on_first: false,
+        keep_going: false,
+        max_entries: None,
+    };
+    decode_entries(
+        archive_reader(&mut src, &mode),
+        &mode,
+        None,
+        checks,
+        &mut target,
+        &mut |_, _| ControlFlow::Continue(()),
+    )?;
+    if let Some(prefix) = hash_prefix {
+        if target.paths.is_empty() {
+            return Err(no_hash_match(prefix));
+        }
+    }
+    Ok(target.paths)
+}
+
+/// Settings for [`verify`].
+pub struct VerifyOptions {
+    // Format version of archives without a header, which otherwise read as version 0.
+    pub compat: Option<u32>,
+    // `Error` fails on a stored path repeated in the archive; the others count it.
+    pub on_duplicate: OnDuplicate,
+    // Check entries with a `bodyhash=` by their stored bytes alone, without decoding them.
+    pub fast: bool,
+    // Fail on an entry as soon as it runs past its recorded size, instead of once it ends.
+    pub stop_on_first: bool,
+    // Check every entry, collecting the ones that fail in `Verified::failures` instead of
+    // stopping at the first.
+    pub keep_going: bool,
+}
+
+/// An entry `verify` failed, and why.
+pub struct Failure {
+    pub path: String,
+    pub reason: String,
+}
+
+/// What [`verify`] checked.
+pub struct Verified {
+    pub entries: usize,
+    // Entries `fast` checked by their stored bytes alone.
+    pub stored_only: usize,
+    // Entries whose stored path an earlier entry already had.
+    pub duplicates: usize,
+    // Entries that failed, in archive order; only ever non-empty under `keep_going`.
+    pub failures: Vec<Failure>,
+}
+
+impl Verified {
+    /// The counts and failures as a JSON object, for `verify --json`.
+    pub fn to_json(&self) -> String {
+        let failures: Vec<_> = self
+            .failures
+            .iter()
+            .map(|failure| {
+                format!(
+                    "{{\"path\":{},\"reason\":{}}}",
+                    json::string(&failure.path),
+                    json::string(&failure.reason)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"entries\":{},\"stored_only\":{},\"duplicates\":{},\"failures\":[{}]}}",
+            self.entries,
+            self.stored_only,
+            self.duplicates,
+            failures.join(",")
+        )
+    }
+}
+
+/// Decodes every entry of an archive read from `src` and checks it against its recorded
+/// hash, without extracting anything. The first entry that doesn't match is an error, unless
+/// `options.keep_going` collects them all; other inconsistencies, like several concatenated
+/// archives, are warnings. Damage that stops the archive from being read at all is an error
+/// either way.
+pub fn verify(src: impl Read, mode: Mode, options: &VerifyOptions) -> io::Result<Verified> {
+    verify_with_progress(src, mode, options, &mut |_| {})
+}
+
+/// Counts the bytes read through it for a [`ByteProgress`].
+struct Counted<'p, R> {
+    inner: R,
+    read: u64,
+    progress: ByteProgress<'p>,
+}
+
+impl<R: Read> Read for Counted<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        (self.progress)(self.read);
+        Ok(n)
+    }
+}
+
+/// Called with how many bytes of the archive have been read so far, after each chunk.
+pub type ByteProgress<'a> = &'a mut dyn FnMut(u64);
+
+/// Like [`verify`], calling `progress` as the archive is read, e.g. to drive a progress bar
+/// by bytes rather than by entries, which may be few and large.
+pub fn verify_with_progress(
+    src: impl Read,
+    mode: Mode,
+    options: &VerifyOptions,
+    progress: ByteProgress,
+) -> io::Result<Verified> {
+    let mut target = VerifyTarget {
+        seen: Seen::new(options.on_duplicate),
+        entries: 0,
+        fast: options.fast,
+    };
+    let checks = Checks {
+        best_effort: false,
+        strict: false,
+        verify: true,
+        stop_on_first: options.stop_on_first,
+        keep_going: options.keep_going,
+        max_entries: None,
+    };
+    let mut counted = Counted {
+        inner: src,
+        read: 0,
+        progress,
+    };
+    let recovery = decode_entries(
+        archive_reader(&mut counted, &mode),
+        &mode,
+        options.compat,
+        checks,
+        &mut target,
+        &mut |_, _| ControlFlow::Continue(()),
+    )?;
+    Ok(Verified {
+        entries: target.entries,
+        stored_only: recovery.stored_only,
+        duplicates: target.seen.duplicates,
+        failures: recovery.failures,
+    })
+}
+
+/// Whether a `hash` as recorded in a separator, a decimal number, starts with `prefix` when
+/// written in lowercase hex without leading zeros, as `printf '%x'` does.
+pub fn hash_has_prefix(hash: &str, prefix: &str) -> bool {
+    hash.parse::<u64>()
+        .is_ok_and(|hash| format!("{:x}", hash).starts_with(&prefix.to_ascii_lowercase()))
+}
+
+fn no_hash_match(prefix: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no entry's hash starts with `{}`", prefix),
+    )
+}
+
+/// What an archive holds, read from its header and separators alone.
+pub struct Index {
+    pub hash_scope: HashScope,
+    // Stored path, `./` and empty components removed so archives from before paths were
+    // normalized compare equal, to recorded hash. A path stored twice keeps its last hash.
+    pub entries: BTreeMap<String, String>,
+    // Recorded size by stored path, for the entries that have one.
+    pub sizes: BTreeMap<String, u64>,
+}
+
+/// Reads the index of an archive from `src` without decoding any entry body. Archives
+/// without a header are read as format `compat`, or 0.
+pub fn read_index(mut src: impl Read, mode: Mode, compat: Option<u32>) -> io::Result<Index> {
+    let mut lines = LineReader::with_chunk_size(archive_reader(&mut src, &mode), DECODE_CHUNK);
+    let mut line = Vec::new();
+    let mut index = Index {
+        hash_scope: HashScope::Content,
+        entries: BTreeMap::new(),
+        sizes: BTreeMap::new(),
+    };
+    let mut version = compat.unwrap_or(0);
+    let mut first = true;
+    while lines.next_line(&mut line)? {
+        if line == trailer::MARKER.as_bytes() {
+            break;
+        }
+        if std::mem::replace(&mut first, false) && line.starts_with(header::MAGIC.as_bytes()) {
+            if let Ok(header) = Header::parse(&line) {
+                index.hash_scope = header.hash_scope;
+                version = header.version;
+            }
+        } else if let Some(separator) = separator::from_line(&line, version)? {
+            // A raw body is skipped by its length, as its bytes aren't separators.
+            if separator.raw {
+                let mut left = separator.size.ok_or_else(|| {
+                    invalid_data(format!(
+                        "`{}` is in a raw section but has no size",
+                        separator.path
+                    ))
+                })?;
+                while left > 0 && lines.next_bytes(left, &mut line)? {
+                    left -= line.len() as u64;
+                }
+            }
+            let path = split_stored(&separator.path).collect::<Vec<_>>().join("/");
+            if let Some(size) = separator.size {
+                index.sizes.insert(path.clone(), size);
+            }
+            index.entries.insert(path, separator.hash);
+        }
+    }
+    Ok(index)
+}
+
+/// What a separator records about an entry, see [`read_recorded`].
+pub struct Recorded {
+    pub path: String,
+    pub hash: String,
+    pub size: Option<u64>,
+}
+
+/// The recorded path, hash and size of every entry in an archive read from `src`, in archive
+/// order, for `verify --list-only`. Only the header and separators are parsed, and a malformed
+/// one is an error; bodies are read past without being decoded or hashed, so nothing says they
+/// match. Archives without a header are read as format `compat`, or 0.
+pub fn read_recorded(
+    mut src: impl Read,
+    mode: Mode,
+    compat: Option<u32>,
+) -> io::Result<Vec<Recorded>> {
+    let mut lines = LineReader::with_chunk_size(archive_reader(&mut src, &mode), DECODE_CHUNK);
+    let mut line = Vec::new();
+    let mut recorded = Vec::new();
+    let mut version = compat.unwrap_or(0);
+    let mut first = true;
+    loop {
+        interrupt::check()?;
+        if !lines.next_line(&mut line)? || line == trailer::MARKER.as_bytes() {
+            break;
+        }
+        if std::mem::replace(&mut first, false) && line.starts_with(header::MAGIC.as_bytes()) {
+            let header = Header::parse(&line)
+                .map_err(|e| invalid_data(format!("malformed archive header: {}", e)))?;
+            if header.mode != mode {
+                log::warn(format!(
+                    "archive was encoded as {} but is read as {}",
+                    header.mode, mode
+                ));
+            }
+            version = header.version;
+            continue;
+        }
+        let separator = match separator::from_line(&line, version)? {
+            Some(separator) => separator,
+            None => continue,
+        };
+        // A raw body is read by its length, as it needn't be lines at all.
+        if separator.raw {
+            let mut left = separator.size.ok_or_else(|| {
+                invalid_data(format!(
+                    "`{}` is in a raw section but has no size",
+                    separator.path
+                ))
+            })?;
+            while left > 0 && lines.next_bytes(left, &mut line)? {
+                left -= line.len() as u64;
+            }
+        }
+        recorded.push(Recorded {
+            path: separator.path,
+            hash: separator.hash,
+            size: separator.size,
+        });
+    }
+    Ok(recorded)
+}
+
+/// `{"hashed": false, "entries": [{"path": "...", "hash": "...", "size": ...}]}`, with a
+/// `null` size where none is recorded.
+pub fn recorded_to_json(recorded: &[Recorded]) -> String {
+    let entries: Vec<_> = recorded
+        .iter()
+        .map(|entry| {
+            format!(
+                "{{\"path\":{},\"hash\":{},\"size\":{}}}",
+                json::string(&entry.path),
+                json::string(&entry.hash),
+                entry
+                    .size
+                    .map_or_else(|| "null".to_string(), |size| size.to_string())
+            )
+        })
+        .collect();
+    format!("{{\"hashed\":false,\"entries\":[{}]}}", entries.join(","))
+}
+
+/// The header of an archive read from `src`, or `None` if it has none.
+pub fn read_header(mut src: impl Read, mode: Mode) -> io::Result<Option<Header>> {
+    let mut lines = LineReader::with_chunk_size(archive_reader(&mut src, &mode), DECODE_CHUNK);
+    let mut line = Vec::new();
+    if !lines.next_line(&mut line)? || !line.starts_with(header::MAGIC.as_bytes()) {
+        return Ok(None);
+    }
+    Header::parse(&line)
+        .map(Some)
+        .map_err(|e| invalid_data(format!("malformed archive header: {}", e)))
+}
+
+/// Like `read_header` for the archive at `path`, detecting its mode as `index_file` does.
+pub fn header_file(path: &Path, mode: Option<Mode>) -> io::Result<Option<Header>> {
+    let mut file = File::open(path)?;
+    let mode = match mode {
+        Some(mode) => mode,
+        None => detect_file_mode(&mut file)?,
+    };
+    read_header(file, mode)
+}
+
+/// The mode to read the archive in `file` in: the one its header records when that isn't
+/// `mode`, e.g. after `encode --min-ratio` fell back to an uncompressed mode, with a warning.
+/// An archive read from a pipe can't be looked at twice, so it is read in `mode`. Leaves
+/// `file` at its start.
+pub fn recorded_mode(file: &mut Volumes, mode: Mode, archive: &Path) -> io::Result<Mode> {
+    if !file.is_file()? {
+        return Ok(mode);
+    }
+    let mut prefix = Vec::with_capacity(header::SNIFF_LEN);
+    (&mut *file)
+        .take(header::SNIFF_LEN as u64)
+        .read_to_end(&mut prefix)?;
+    file.seek(SeekFrom::Start(0))?;
+    match header::detect_mode(&prefix) {
+        Some(recorded) if recorded != mode => {
+            log::warn(format!(
+                "{} was encoded as {}, not {}; decoding it as {}",
+                archive.display(),
+                recorded,
+                mode,
+                recorded
+            ));
+            Ok(recorded)
+        }
+        _ => Ok(mode),
+    }
+}
+
+/// The mode the header at the start of `file` declares, or plain without one, leaving
+/// `file` at its start.
+fn detect_file_mode(file: &mut File) -> io::Result<Mode> {
+    let mut prefix = Vec::with_capacity(header::SNIFF_LEN);
+    file.take(header::SNIFF_LEN as u64)
+        .read_to_end(&mut prefix)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(header::detect_mode(&prefix).unwrap_or(Mode::Plain))
+}
+
+/// Fails unless the archive at `archive` records `expected` as the directory it was made
+/// from, see `--expect-root`.
+fn check_root(
+    archive: &Path,
+    mode: Mode,
+    options: &DecodeOptions,
+    expected: &Path,
+) -> io::Result<()> {
+    let expected = absolute(expected)?;
+    let recorded =
+        read_header(volumes::open(archive, options.volumes)?, mode)?.and_then(|header| header.root);
+    match recorded {
+        Some(root) if Path::new(&root) == expected => Ok(()),
+        Some(root) => Err(invalid_data(format!(
+            "{} was encoded from {}, not {}",
+            archive.display(),
+            root,
+            expected.display()
+        ))),
+        None => Err(invalid_data(format!(
+            "{} records no source root (encode --record-root), so --expect-root can't be checked",
+            archive.display()
+        ))),
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Like `read_index` for the archive at `path`, detecting its mode from the header when
+/// `mode` is `None`. Headerless archives are read as plain, in format `compat`.
+pub fn index_file(path: &Path, mode: Option<Mode>, compat: Option<u32>) -> io::Result<Index> {
+    let mut file = File::open(path)?;
+    let mode = match mode {
+        Some(mode) => mode,
+        None => detect_file_mode(&mut file)?,
+    };
+    read_index(file, mode, compat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::{decode_options, Scratch};
+
+    /// Decodes the plain archive `archive` into `dest/inner` with the options `adjust` makes,
+    /// returning the scratch directory and whether the decode succeeded.
+    fn decode_archive(
+        name: &str,
+        archive: &str,
+        adjust: impl FnOnce(&mut DecodeOptions),
+    ) -> (Scratch, io::Result<()>) {
+        let scratch = Scratch::create(name).unwrap();
+        let path = scratch.path.join("archive.out");
+        fs::write(&path, archive).unwrap();
+        let dest = scratch.path.join("dest");
+        fs::create_dir(&dest).unwrap();
+        let mut options = decode_options(dest.join("inner"));
+        adjust(&mut options);
+        let result = decode_dir(&path, Mode::Plain, &options);
+        (scratch, result)
+    }
+
+    fn decode_into_inner(name: &str, archive: &str) -> (Scratch, io::Result<()>) {
+        decode_archive(name, archive, |_| {})
+    }
+
+    fn hash_of(path: &str, content: &str) -> u64 {
+        let mut hasher = HashScope::Content.hasher(path);
+        hasher.write(content.as_bytes());
+        hasher.finish()
+    }
+
+    /// A plain entry for `path` holding `content`, separator and body.
+    fn plain_entry(path: &str, content: &str) -> String {
+        format!(
+            "===={}|{}|size={}====\n{}\n",
+            path,
+            hash_of(path, content),
+            content.len(),
+            content
+        )
+    }
+
+    /// Decodes `archive` with `--compat compat` and checks it gives the files `expected`.
+    fn assert_decodes(name: &str, archive: &str, compat: Option<u32>, expected: &[(&str, &str)]) {
+        let (scratch, result) = decode_archive(name, archive, |options| options.compat = compat);
+        result.unwrap();
+        for (path, content) in expected {
+            let decoded = fs::read_to_string(scratch.path.join("dest/inner").join(path));
+            assert_eq!(decoded.unwrap(), *content, "{}", path);
+        }
+    }
+
+    #[test]
+    fn headerless_version_0_archive() {
+        let archive = format!(
+            "===={}|{}====\nplain\n\n===={}|{}|enc=base64|mtime=0====\n{}\n",
+            "a.txt",
+            hash_of("a.txt", "plain\n"),
+            "b.bin",
+            hash_of("b.bin", "base64\n"),
+            base64::encode("base64\n")
+        );
+        let expected = [("a.txt", "plain\n"), ("b.bin", "base64\n")];
+        assert_decodes("decode-version-0", &archive, None, &expected);
+    }
+
+    #[test]
+    fn headerless_archive_read_with_compat() {
+        let archive = format!(
+            "====a.txt|{}|size=6|perms=644====\nplain\n\n",
+            hash_of("a.txt", "plain\n")
+        );
+        let (_, result) = decode_into_inner("decode-compat-missing", &archive);
+        assert!(result.unwrap_err().to_string().contains("--compat"));
+        // The space check reads the separators too, and must do so by the same version.
+        let (_, result) = decode_archive("decode-compat-space", &archive, |options| {
+            options.compat = Some(1);
+            options.space_check = true;
+        });
+        result.unwrap();
+        assert_decodes(
+            "decode-compat-1",
+            &archive,
+            Some(1),
+            &[("a.txt", "plain\n")],
+        );
+    }
+
+    #[test]
+    fn version_1_archive_is_not_unescaped() {
+        let archive = format!(
+            "MITO/1 mode=plain\n===={}|{}|size=5|future=field====\nsale\n\n",
+            "100%25.txt",
+            hash_of("100%25.txt", "sale\n")
+        );
+        assert_decodes(
+            "decode-version-1",
+            &archive,
+            None,
+            &[("100%25.txt", "sale\n")],
+        );
+    }
+
+    #[test]
+    fn version_2_archive_is_unescaped() {
+        let archive = format!(
+            "MITO/2 mode=plain\n===={}|{}|size=5====\nsale\n\n",
+            "100%25.txt",
+            hash_of("100%.txt", "sale\n")
+        );
+        assert_decodes(
+            "decode-version-2",
+            &archive,
+            None,
+            &[("100%.txt", "sale\n")],
+        );
+    }
+
+    #[test]
+    fn file_and_directory_conflicts_follow_the_strategy() {
+        let archive = format!(
+            "MITO/2 mode=plain\n{}{}",
+            plain_entry("foo", "file\n"),
+            plain_entry("foo/bar", "below\n")
+        );
+        let read = |scratch: &Scratch, path: &str| {
+            fs::read_to_string(scratch.path.join("dest/inner").join(path)).ok()
+        };
+        let (_, result) = decode_archive("decode-conflict-error", &archive, |options| {
+            options.conflict = Conflict::Error
+        });
+        let e = result.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert!(e.to_string().contains("foo/bar"), "{}", e);
+
+        let (scratch, result) = decode_archive("decode-conflict-skip", &archive, |options| {
+            options.conflict = Conflict::Skip
+        });
+        result.unwrap();
+        assert_eq!(read(&scratch, "foo").as_deref(), Some("file\n"));
+        assert!(!scratch.path.join("dest/inner/foo-1").exists());
+
+        let (scratch, result) = decode_archive("decode-conflict-rename", &archive, |options| {
+            options.conflict = Conflict::Rename
+        });
+        result.unwrap();
+        assert_eq!(read(&scratch, "foo").as_deref(), Some("file\n"));
+        assert_eq!(read(&scratch, "foo-1/bar").as_deref(), Some("below\n"));
+    }
+
+    #[test]
+    fn header_line_in_a_plain_body_is_content() {
+        let content = "one\nMITO/2 mode=plain\ntwo\n";
+        let archive = format!("MITO/2 mode=plain\n{}", plain_entry("a", content));
+        let (scratch, result) = decode_into_inner("decode-header-body", &archive);
+        result.unwrap();
+        let decoded = fs::read(scratch.path.join("dest/inner/a")).unwrap();
+        assert_eq!(decoded, content.as_bytes());
+    }
+
+    #[test]
+    fn separator_line_in_a_raw_body_is_not_indexed() {
+        let content = "====evil|1|size=99999999999999====\n";
+        let archive = format!(
+            "MITO/2 mode=plain\n===={}|{}|size={}|section=raw====\n{}\n",
+            "a.png",
+            hash_of("a.png", content),
+            content.len(),
+            content
+        );
+        let index = read_index(archive.as_bytes(), Mode::Plain, None).unwrap();
+        assert_eq!(index.entries.keys().collect::<Vec<_>>(), ["a.png"]);
+        assert_eq!(index.sizes["a.png"], content.len() as u64);
+    }
+
+    #[test]
+    fn recorded_mode_overrides_the_one_given() {
+        let scratch = Scratch::create("decode-recorded-mode").unwrap();
+        let path = scratch.path.join("archive.out");
+        fs::write(&path, "MITO/2 mode=plain\n").unwrap();
+        let mut file = volumes::open(&path, false).unwrap();
+        let mode = recorded_mode(&mut file, Mode::CompressedBinary, &path).unwrap();
+        assert!(mode == Mode::Plain);
+        let mut rest = String::new();
+        file.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "MITO/2 mode=plain\n");
+    }
+
+    #[test]
+    fn symlink_through_an_earlier_symlink_is_refused() {
+        let (scratch, _) = decode_into_inner(
+            "decode-link-chain",
+            "MITO/2 mode=plain\n\
+             ====y|15130871412783076140|size=0|symlink=.====\n\n\
+             ====x|15130871412783076140|size=0|symlink=y/..====\n\n\
+             ====x/evil|9591839402052020448|size=4====\nevil\n",
+        );
+        let dest = scratch.path.join("dest");
+        assert!(!dest.join("evil").exists());
+        assert!(fs::symlink_metadata(dest.join("inner/x")).map_or(true, |m| m.is_dir()));
+    }
+
+    #[test]
+    fn symlink_through_a_later_symlink_is_removed() {
+        let (scratch, result) = decode_into_inner(
+            "decode-link-chain-later",
+            "MITO/2 mode=plain\n\
+             ====x|15130871412783076140|size=0|symlink=y/..====\n\n\
+             ====y|15130871412783076140|size=0|symlink=.====\n\n\
+             ====x/evil|9591839402052020448|size=4====\nevil\n",
+        );
+        let dest = scratch.path.join("dest");
+        assert!(result.is_err());
+        assert!(!dest.join("evil").exists());
+        assert!(fs::symlink_metadata(dest.join("inner/x")).is_err());
+    }
+}
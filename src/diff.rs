@@ -0,0 +1,114 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::decode::Index;
+use crate::json;
+
+/// How an entry differs between two archives.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Change {
+    Added,
+    Removed,
+    Modified,
+}
+
+impl Change {
+    /// The git-status-like letter for the change.
+    pub fn code(self) -> char {
+        match self {
+            Change::Added => 'A',
+            Change::Removed => 'D',
+            Change::Modified => 'M',
+        }
+    }
+}
+
+/// Every entry that differs from `old` to `new`, by stored path and recorded hash, in path
+/// order. The archives' modes don't matter, since hashes are taken over decoded content.
+pub fn diff<'a>(old: &'a Index, new: &'a Index) -> Vec<(Change, &'a str)> {
+    let mut changes = Vec::new();
+    for (path, hash) in &old.entries {
+        match new.entries.get(path) {
+            None => changes.push((Change::Removed, path.as_str())),
+            Some(new_hash) if new_hash != hash => changes.push((Change::Modified, path.as_str())),
+            Some(_) => {}
+        }
+    }
+    for path in new.entries.keys() {
+        if !old.entries.contains_key(path) {
+            changes.push((Change::Added, path.as_str()));
+        }
+    }
+    changes.sort_by(|a, b| a.1.cmp(b.1));
+    changes
+}
+
+/// Takes every entry `changes` removes that has the same hash as one they add out of
+/// `changes`, as an `(old path, new path)` rename, in old path order. Several removed entries
+/// with one hash pair with the added ones in path order, and what is left over stays removed
+/// or added. Hashes are matched exactly, so an edited file that also moved isn't paired.
+pub fn detect_renames<'a>(
+    old: &'a Index,
+    new: &'a Index,
+    changes: &mut Vec<(Change, &'a str)>,
+) -> Vec<(&'a str, &'a str)> {
+    let mut added: HashMap<&str, VecDeque<&str>> = HashMap::new();
+    for (change, path) in changes.iter() {
+        if *change == Change::Added {
+            added
+                .entry(new.entries[*path].as_str())
+                .or_default()
+                .push_back(path);
+        }
+    }
+    let mut renames = Vec::new();
+    for (change, path) in changes.iter() {
+        if *change != Change::Removed {
+            continue;
+        }
+        if let Some(to) = added
+            .get_mut(old.entries[*path].as_str())
+            .and_then(VecDeque::pop_front)
+        {
+            renames.push((*path, to));
+        }
+    }
+    // Removed paths aren't in `new` and added ones aren't in `old`, so one set holds both.
+    let paired: HashSet<&str> = renames.iter().flat_map(|&(from, to)| [from, to]).collect();
+    changes.retain(|(_, path)| !paired.contains(path));
+    renames
+}
+
+/// `changes` as a JSON array of `{"status": "A", "path": "..."}` objects.
+pub fn to_json(changes: &[(Change, &str)]) -> String {
+    let objects: Vec<_> = changes
+        .iter()
+        .map(|(change, path)| {
+            format!(
+                "{{\"status\":\"{}\",\"path\":{}}}",
+                change.code(),
+                json::string(path)
+            )
+        })
+        .collect();
+    format!("[{}]", objects.join(","))
+}
+
+/// `changes` and `renames` as a JSON object, `{"changes": [...], "renamed": [{"old": "...",
+/// "new": "..."}]}`, for `diff --detect-renames`.
+pub fn to_json_with_renames(changes: &[(Change, &str)], renames: &[(&str, &str)]) -> String {
+    let renamed: Vec<_> = renames
+        .iter()
+        .map(|(from, to)| {
+            format!(
+                "{{\"old\":{},\"new\":{}}}",
+                json::string(from),
+                json::string(to)
+            )
+        })
+        .collect();
+    format!(
+        "{{\"changes\":{},\"renamed\":[{}]}}",
+        to_json(changes),
+        renamed.join(",")
+    )
+}
@@ -0,0 +1,1847 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::hash::Hasher;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::atomic::{self, AtomicFile};
+use crate::cli::{counted, format_size};
+use crate::decode::Index;
+use crate::glob::{Glob, PatternFile};
+use crate::header;
+use crate::native::{link_escapes, numbered, split_stored};
+use crate::separator::Separator;
+use crate::spill::{Budget, Buffer};
+use crate::tar::TarWriter;
+use crate::vfs::{DirEntry, FileKind, Metadata, OsFs, VfsReader};
+use crate::writer::{self, ArchiveWriter, BlockingWriter, Codec, Prepared, WriterOptions};
+use crate::{
+    btime, interrupt, json, log, normalize_stored, pool, sparse, Alphabet, HashScope, Mode,
+};
+
+/// The `--output` that writes the archive to stdout.
+pub const STDOUT: &str = "-";
+
+// The archive being written is ignored by path rather than by name, so a tree with its own
+// `output/` directory, the default decode destination, is archived in full.
+const IGNORED_FILE_DIR: [&str; 4] = [".git", "Cargo.lock", "target", "node_modules"];
+
+// Pattern files read from the root of the walk, see `PatternFile`.
+const IGNORE_FILE: &str = ".mitoignore";
+const INCLUDE_FILE: &str = ".mitoinclude";
+
+// Below this saving, in percent, the summary suggests an uncompressed mode.
+const LOW_SAVING: f64 = 5.0;
+
+// Version control metadata skipped by `--exclude-vcs`, the same set as tar's.
+const VCS_NAMES: [&str; 19] = [
+    ".git",
+    ".gitignore",
+    ".gitattributes",
+    ".gitmodules",
+    ".hg",
+    ".hgignore",
+    ".hgtags",
+    ".svn",
+    ".bzr",
+    ".bzrignore",
+    "CVS",
+    ".cvsignore",
+    "RCS",
+    "SCCS",
+    "_darcs",
+    ".arch-ids",
+    "{arch}",
+    "=RELEASE-ID",
+    "=meta-update",
+];
+
+/// Calls `cb` with every file under `dir`. Symlinks to directories are walked into when
+/// `follow` is set, and passed to `cb` like files otherwise. A directory that can't be
+/// listed fails the walk, unless there is `unreadable` to count it in, see
+/// `EncodeOptions::ignore_errors`; then it is skipped with a warning.
+fn visit_dirs(
+    vfs: &dyn VfsReader,
+    dir: &Path,
+    follow: bool,
+    mut unreadable: Option<&mut usize>,
+    cb: &mut dyn FnMut(&DirEntry) -> io::Result<()>,
+) -> io::Result<()> {
+    let is_dir = |path: &Path| vfs.metadata(path).is_ok_and(|m| m.kind == FileKind::Dir);
+    if is_dir(dir) {
+        // `read_dir` order depends on the filesystem; sort so the same tree always
+        // produces the same archive.
+        let mut entries = match vfs.read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                let msg = format!("can't list {}: {}", dir.display(), e);
+                return match unreadable {
+                    Some(count) => {
+                        log::warn(format!("{}, skipped it", msg));
+                        *count += 1;
+                        Ok(())
+                    }
+                    None => Err(io::Error::new(e.kind(), msg)),
+                };
+            }
+        };
+        entries.sort_by(|a, b| a.file_name().cmp(b.file_name()));
+        for entry in entries {
+            let descend = match entry.kind {
+                FileKind::Symlink => follow && is_dir(&entry.path),
+                kind => kind == FileKind::Dir,
+            };
+            if descend {
+                visit_dirs(vfs, &entry.path, follow, unreadable.as_deref_mut(), cb)?;
+            } else {
+                cb(&entry)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The kind of archive `encode_dir` writes.
+pub enum OutputFormat {
+    Mito,
+    Tar,
+    TarGz,
+}
+
+impl OutputFormat {
+    pub fn parse(s: &str) -> io::Result<OutputFormat> {
+        match s {
+            "mito" => Ok(OutputFormat::Mito),
+            "tar" => Ok(OutputFormat::Tar),
+            "tar.gz" => Ok(OutputFormat::TarGz),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("not support {}, available formats are mito|tar|tar.gz", s),
+            )),
+        }
+    }
+}
+
+/// What to do with entries whose stored paths differ only by case, which collide when
+/// extracted onto a case-insensitive filesystem.
+#[derive(Clone, Copy)]
+pub enum CaseCollision {
+    Error,
+    Rename,
+    Keep,
+}
+
+impl CaseCollision {
+    pub fn parse(s: &str) -> io::Result<CaseCollision> {
+        match s {
+            "error" => Ok(CaseCollision::Error),
+            "rename" => Ok(CaseCollision::Rename),
+            "keep" => Ok(CaseCollision::Keep),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "not support {}, available strategies are error|rename|keep",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
+/// How `encode` treats symlinks: `Follow` archives what they point to, `Store` records the
+/// link itself as a `symlink=` entry.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Symlinks {
+    Follow,
+    Store,
+}
+
+impl Symlinks {
+    pub fn parse(s: &str) -> io::Result<Symlinks> {
+        match s {
+            "follow" => Ok(Symlinks::Follow),
+            "store" => Ok(Symlinks::Store),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("not support {}, available modes are follow|store", s),
+            )),
+        }
+    }
+}
+
+/// Settings for `encode_dir` and `analyze_dir`.
+pub struct EncodeOptions {
+    pub output: PathBuf,
+    // Where the archive is written before it is renamed to `output`, next to it by default.
+    pub temp_dir: Option<PathBuf>,
+    pub preserve_mtime: bool,
+    // Record birth times, restored on decode where the platform can set them.
+    pub preserve_btime: bool,
+    // Record numeric uid and gid, restored on decode by root.
+    pub preserve_ownership: bool,
+    // Record permission bits, restored on decode.
+    pub preserve_perms: bool,
+    // Record a hash of each entry's stored bytes, which `verify --fast` checks.
+    pub stored_hash: bool,
+    // Leave the holes of sparse files out of the archive, recreated on decode.
+    pub sparse: bool,
+    // The base64 alphabet, see `--base64-url`.
+    pub alphabet: Alphabet,
+    pub clamp_mtime: Option<u64>,
+    pub threads: usize,
+    pub order_from: Option<PathBuf>,
+    // Store paths relative to this directory instead of under each source's own name.
+    pub relative_to: Option<PathBuf>,
+    // `None` skips the check.
+    pub case_collision: Option<CaseCollision>,
+    pub output_format: OutputFormat,
+    pub auto_sample: usize,
+    pub hash_scope: HashScope,
+    // zlib level of `Mode::CompressedBinary`, `Mode::CompressedTxt` and tar.gz output.
+    pub level: u32,
+    // Append an index of entry offsets, see `ArchiveWriter`.
+    pub index: bool,
+    // Patterns matched against paths relative to the walk root, see `Glob`.
+    pub exclude: Vec<String>,
+    // Pattern files outside the tree, read like `.mitoignore`.
+    pub exclude_from: Vec<PathBuf>,
+    pub exclude_vcs: bool,
+    pub symlinks: Symlinks,
+    // Per-path codec rules, the first matching pattern wins; other entries are stored.
+    pub codec_for: Vec<(String, Codec)>,
+    // Lowercase extensions, without the dot, whose bodies go in raw sections outside the
+    // compressed stream of `Mode::CompressedBinary` and `Mode::CompressedTxt`.
+    pub no_compress_ext: Vec<String>,
+    pub exclude_larger_than: Option<u64>,
+    pub exclude_smaller_than: Option<u64>,
+    // Unix time files must have been modified at or after, for incremental snapshots.
+    pub modified_since: Option<u64>,
+    pub include_archives: bool,
+    // Refuse binary files in a `Mode::Plain` archive instead of warning about them.
+    pub strict: bool,
+    // How much the entry buffers may hold in memory at once before spilling to disk.
+    pub max_memory: Option<u64>,
+    // Record the encoded directory in the header, for `info` and `decode --expect-root`.
+    pub record_root: bool,
+    // Break base64 bodies, and `Mode::CompressedTxt` as a whole, into lines this long.
+    pub wrap: Option<usize>,
+    // Rewrite a `Mode::CompressedBinary` archive as plain, and a `Mode::CompressedTxt` one
+    // as base64, when compression made it less than this many percent smaller.
+    pub min_ratio: Option<f64>,
+    // Skip directories that can't be listed and files that can't be opened, with a warning,
+    // instead of failing the encode.
+    pub ignore_errors: bool,
+    // Fail once the walk selects more files than this.
+    pub max_entries: Option<usize>,
+    // Print the summary as JSON.
+    pub json: bool,
+}
+
+impl EncodeOptions {
+    fn writer_options(&self) -> WriterOptions {
+        WriterOptions {
+            auto_sample: self.auto_sample,
+            hash_scope: self.hash_scope,
+            level: self.level,
+            index: self.index,
+            stored_hash: self.stored_hash,
+            flush_entries: self.to_stdout(),
+            alphabet: self.alphabet,
+            wrap: self.wrap,
+        }
+    }
+
+    fn to_stdout(&self) -> bool {
+        self.output == Path::new(STDOUT)
+    }
+
+    /// The `--max-memory` budget, spilling where the archive is written before it is moved
+    /// into place, so a RAM-backed temporary directory doesn't defeat it.
+    fn budget(&self) -> Option<Arc<Budget>> {
+        let dir = match &self.temp_dir {
+            Some(dir) => dir.clone(),
+            None if self.to_stdout() => env::temp_dir(),
+            None => match self.output.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => PathBuf::from("."),
+            },
+        };
+        self.max_memory.map(|cap| Budget::new(cap, &dir))
+    }
+}
+
+/// What an encode archived and, by reason, what it skipped.
+#[derive(Default)]
+struct Summary {
+    archived: usize,
+    // The built-in ignore list.
+    skipped_builtin: usize,
+    skipped_vcs: usize,
+    // `.mitoinclude` and `.mitoignore`.
+    skipped_pattern_files: usize,
+    // `--exclude` patterns.
+    skipped_excluded: usize,
+    skipped_larger: usize,
+    skipped_smaller: usize,
+    skipped_unmodified: usize,
+    skipped_archives: usize,
+    // Directories that couldn't be listed and files that couldn't be read, under
+    // `--ignore-errors`.
+    skipped_unreadable: usize,
+    // Symlinks stored under `--symlinks store`, and how many of them point outside the tree.
+    symlinks: usize,
+    external_symlinks: usize,
+    // Stored paths of binary files in a `Mode::Plain` archive.
+    binary: Vec<String>,
+    // Roughly how large the archive would be in the uncompressed mode `--min-ratio` falls
+    // back to, and how large it is compressed.
+    compressed: Option<(u64, u64)>,
+    recommendation: Option<String>,
+}
+
+impl Summary {
+    /// How much smaller compression made the archive, in percent.
+    fn saving(&self) -> Option<f64> {
+        match self.compressed {
+            Some((uncompressed, compressed)) if uncompressed > 0 => {
+                Some(100.0 - compressed as f64 * 100.0 / uncompressed as f64)
+            }
+            _ => None,
+        }
+    }
+
+    /// Prints the summary to stderr, as a JSON object under `--json`.
+    fn print(&self, options: &EncodeOptions) {
+        if options.json {
+            eprintln!("{}", self.to_json());
+            return;
+        }
+        eprint!("archived {}", counted(self.archived, "file", "files"));
+        if self.skipped_builtin > 0 {
+            eprint!(
+                ", skipped {}",
+                counted(self.skipped_builtin, "built-in ignore", "built-in ignores")
+            );
+        }
+        if options.exclude_vcs || self.skipped_vcs > 0 {
+            eprint!(
+                ", skipped {}",
+                counted(
+                    self.skipped_vcs,
+                    "version control file",
+                    "version control files"
+                )
+            );
+        }
+        if self.skipped_pattern_files > 0 {
+            eprint!(
+                ", skipped {} by {} or {}",
+                self.skipped_pattern_files, INCLUDE_FILE, IGNORE_FILE
+            );
+        }
+        if !options.exclude.is_empty() || self.skipped_excluded > 0 {
+            eprint!(", skipped {} excluded", self.skipped_excluded);
+        }
+        if let Some(limit) = options.exclude_larger_than {
+            eprint!(
+                ", skipped {} larger than {} bytes",
+                self.skipped_larger, limit
+            );
+        }
+        if let Some(limit) = options.exclude_smaller_than {
+            eprint!(
+                ", skipped {} smaller than {} bytes",
+                self.skipped_smaller, limit
+            );
+        }
+        if let Some(since) = options.modified_since {
+            eprint!(
+                ", skipped {} not modified since @{}",
+                self.skipped_unmodified, since
+            );
+        }
+        if self.skipped_archives > 0 {
+            eprint!(
+                ", skipped {}",
+                counted(self.skipped_archives, "mito archive", "mito archives")
+            );
+        }
+        if self.skipped_unreadable > 0 {
+            eprint!(", skipped {} unreadable", self.skipped_unreadable);
+        }
+        if self.symlinks > 0 {
+            eprint!(
+                ", stored {} ({} outside the tree)",
+                counted(self.symlinks, "symlink", "symlinks"),
+                self.external_symlinks
+            );
+        }
+        if let (Some((uncompressed, compressed)), Some(saving)) = (self.compressed, self.saving()) {
+            eprint!(
+                ", compressed to {} from about {} uncompressed ({:.1}% {})",
+                format_size(compressed),
+                format_size(uncompressed),
+                saving.abs(),
+                if saving < 0.0 { "larger" } else { "smaller" }
+            );
+        }
+        eprintln!();
+        if let Some(recommendation) = &self.recommendation {
+            eprintln!("{}", recommendation);
+        }
+        if !self.binary.is_empty() {
+            eprintln!(
+                "{} binary files in the plain archive, which isn't text any more:",
+                self.binary.len()
+            );
+            for path in &self.binary {
+                eprintln!("  {}", path);
+            }
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let binary: Vec<_> = self.binary.iter().map(|path| json::string(path)).collect();
+        let compressed = match self.compressed {
+            Some((uncompressed, compressed)) => format!(
+                "{{\"uncompressed\":{},\"compressed\":{}}}",
+                uncompressed, compressed
+            ),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"archived\":{},\"skipped\":{{\"builtin\":{},\"vcs\":{},\"pattern_files\":{},\"exclude\":{},\"larger\":{},\"smaller\":{},\"unmodified\":{},\"archives\":{},\"unreadable\":{}}},\"symlinks\":{},\"external_symlinks\":{},\"binary\":[{}],\"compression\":{}}}",
+            self.archived,
+            self.skipped_builtin,
+            self.skipped_vcs,
+            self.skipped_pattern_files,
+            self.skipped_excluded,
+            self.skipped_larger,
+            self.skipped_smaller,
+            self.skipped_unmodified,
+            self.skipped_archives,
+            self.skipped_unreadable,
+            self.symlinks,
+            self.external_symlinks,
+            binary.join(","),
+            compressed
+        )
+    }
+}
+
+/// The mtime to record for `path` in whole seconds, clamped for reproducible archives.
+fn recorded_mtime(metadata: &Metadata, clamp: Option<u64>) -> u64 {
+    let mtime = metadata.mtime.unwrap_or(0);
+    clamp.map_or(mtime, |clamp| mtime.min(clamp))
+}
+
+/// Decides which walked entries go into the archive, counting what it skips.
+struct Selector<'a> {
+    vfs: &'a dyn VfsReader,
+    options: &'a EncodeOptions,
+    // The walk root, which `--exclude` patterns are relative to.
+    root: &'a Path,
+    excludes: Vec<Glob>,
+    // `--exclude-from`, each file with its path.
+    exclude_files: Vec<(PathBuf, PatternFile)>,
+    ignore_file: Option<PatternFile>,
+    // When present, only paths it matches are archived.
+    include_file: Option<PatternFile>,
+    // Canonical paths of the archive being written, its temporary file and the `--log-file`,
+    // so none of them ends up inside the archive.
+    outputs: Vec<PathBuf>,
+    summary: Summary,
+    // Files selected so far, over every source, counted against `--max-entries`.
+    selected: usize,
+}
+
+impl<'a> Selector<'a> {
+    /// Must be created after the output archive, so that it can be recognized in the walk.
+    fn new(
+        vfs: &'a dyn VfsReader,
+        options: &'a EncodeOptions,
+        root: &'a Path,
+    ) -> io::Result<Selector<'a>> {
+        Ok(Selector {
+            vfs,
+            options,
+            root,
+            excludes: options.exclude.iter().map(|p| Glob::new(p)).collect(),
+            exclude_files: options
+                .exclude_from
+                .iter()
+                .map(|path| {
+                    let text = fs::read_to_string(path).map_err(|e| {
+                        io::Error::new(
+                            e.kind(),
+                            format!("--exclude-from {}: {}", path.display(), e),
+                        )
+                    })?;
+                    Ok((path.clone(), PatternFile::parse(&text)))
+                })
+                .collect::<io::Result<_>>()?,
+            ignore_file: load_pattern_file(vfs, &root.join(IGNORE_FILE))?,
+            include_file: load_pattern_file(vfs, &root.join(INCLUDE_FILE))?,
+            outputs: [options.output.clone()]
+                .iter()
+                .chain(log::file_path().as_ref())
+                .filter_map(|path| fs::canonicalize(path).ok())
+                .collect(),
+            summary: Summary::default(),
+            selected: 0,
+        })
+    }
+
+    // Only a real file can be the output, any other tree just never matches.
+    fn is_output(&self, path: &Path) -> bool {
+        let options = self.options;
+        atomic::is_temp_of(&options.output, options.temp_dir.as_deref(), path)
+            || self.outputs.iter().any(|output| {
+                path.file_name() == output.file_name()
+                    && fs::canonicalize(path).ok().as_ref() == Some(output)
+            })
+    }
+
+    /// Applies the ignore list, `--exclude-vcs`, `.mitoinclude`, then `.mitoignore` and
+    /// `--exclude` patterns, then the size and mtime filters, which stored symlinks skip.
+    fn select(&mut self, entry: &DirEntry) -> bool {
+        let options = self.options;
+        let path = &entry.path;
+        if self.is_output(path) {
+            log::verbose(log::DECISIONS, || {
+                format!("skipped {} (output archive)", path.display())
+            });
+            return false;
+        }
+        // Within the root only, so a root under `target` or the like is still archived.
+        let relative = path.strip_prefix(self.root).unwrap_or(path);
+        let ignored = relative.components().find_map(|component| {
+            if let Component::Normal(normal) = component {
+                // return normal.to_string_lossy() == ".git"
+                return IGNORED_FILE_DIR
+                    .iter()
+                    .find(|p| **p == normal.to_string_lossy());
+            }
+            None
+        });
+        if let Some(name) = ignored {
+            log::verbose(log::DECISIONS, || {
+                format!("skipped {} (built-in ignore `{}`)", path.display(), name)
+            });
+            self.summary.skipped_builtin += 1;
+            return false;
+        }
+        if options.exclude_vcs {
+            let vcs = relative.components().find_map(|component| match component {
+                Component::Normal(normal) => VCS_NAMES
+                    .iter()
+                    .find(|name| **name == normal.to_string_lossy()),
+                _ => None,
+            });
+            if let Some(name) = vcs {
+                log::verbose(log::DECISIONS, || {
+                    format!("skipped {} (--exclude-vcs `{}`)", path.display(), name)
+                });
+                self.summary.skipped_vcs += 1;
+                return false;
+            }
+        }
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        if let Some(include_file) = &self.include_file {
+            if include_file.matched(&relative).is_none() {
+                log::verbose(log::DECISIONS, || {
+                    format!("skipped {} (not in {})", path.display(), INCLUDE_FILE)
+                });
+                self.summary.skipped_pattern_files += 1;
+                return false;
+            }
+        }
+        let ignored_by = self
+            .ignore_file
+            .as_ref()
+            .and_then(|ignore_file| ignore_file.matched(&relative));
+        if let Some(line) = ignored_by {
+            log::verbose(log::DECISIONS, || {
+                format!("skipped {} ({} `{}`)", path.display(), IGNORE_FILE, line)
+            });
+            self.summary.skipped_pattern_files += 1;
+            return false;
+        }
+        let excluded_from = self
+            .exclude_files
+            .iter()
+            .find_map(|(file, patterns)| Some((file, patterns.matched(&relative)?)));
+        if let Some((file, line)) = excluded_from {
+            log::verbose(log::DECISIONS, || {
+                format!(
+                    "skipped {} (--exclude-from {} `{}`)",
+                    path.display(),
+                    file.display(),
+                    line
+                )
+            });
+            self.summary.skipped_excluded += 1;
+            return false;
+        }
+        let excluded = self
+            .excludes
+            .iter()
+            .position(|glob| glob.matches_path(&relative));
+        if let Some(i) = excluded {
+            log::verbose(log::DECISIONS, || {
+                format!(
+                    "skipped {} (--exclude `{}`)",
+                    path.display(),
+                    options.exclude[i]
+                )
+            });
+            self.summary.skipped_excluded += 1;
+            return false;
+        }
+        if options.symlinks == Symlinks::Store && entry.kind == FileKind::Symlink {
+            return true;
+        }
+        // Check the size before reading so oversized files are never loaded into memory.
+        let metadata = self.vfs.metadata(path).ok();
+        let len = metadata.as_ref().map_or(0, |m| m.len);
+        if let Some(limit) = options.exclude_larger_than.filter(|limit| len > *limit) {
+            log::verbose(log::DECISIONS, || {
+                format!(
+                    "skipped {} ({} bytes, --exclude-larger-than {})",
+                    path.display(),
+                    len,
+                    limit
+                )
+            });
+            self.summary.skipped_larger += 1;
+            return false;
+        }
+        if let Some(limit) = options.exclude_smaller_than.filter(|limit| len < *limit) {
+            log::verbose(log::DECISIONS, || {
+                format!(
+                    "skipped {} ({} bytes, --exclude-smaller-than {})",
+                    path.display(),
+                    len,
+                    limit
+                )
+            });
+            self.summary.skipped_smaller += 1;
+            return false;
+        }
+        let mtime = metadata.and_then(|m| m.mtime).unwrap_or(0);
+        if let Some(since) = options.modified_since.filter(|since| mtime < *since) {
+            log::verbose(log::DECISIONS, || {
+                format!(
+                    "skipped {} (modified at @{}, --modified-since @{})",
+                    path.display(),
+                    mtime,
+                    since
+                )
+            });
+            self.summary.skipped_unmodified += 1;
+            return false;
+        }
+        if !options.include_archives && is_archive(self.vfs, path) {
+            log::verbose(log::DECISIONS, || {
+                format!("skipped {} (mito archive)", path.display())
+            });
+            self.summary.skipped_archives += 1;
+            return false;
+        }
+        true
+    }
+}
+
+/// Recognizes earlier archives, whatever they are named, by the header at their start.
+fn is_archive(vfs: &dyn VfsReader, path: &Path) -> bool {
+    let mut prefix = Vec::with_capacity(header::SNIFF_LEN);
+    match vfs.open(path) {
+        Ok(file) => file
+            .take(header::SNIFF_LEN as u64)
+            .read_to_end(&mut prefix)
+            .is_ok_and(|_| header::is_archive(&prefix)),
+        Err(_) => false,
+    }
+}
+
+/// `.mitoignore` or `.mitoinclude` at `path`, or `None` if there isn't one.
+fn load_pattern_file(vfs: &dyn VfsReader, path: &Path) -> io::Result<Option<PatternFile>> {
+    let mut text = String::new();
+    match vfs.open(path) {
+        Ok(mut file) => file.read_to_string(&mut text)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(Some(PatternFile::parse(&text)))
+}
+
+/// Prints bytes per top-level directory and the largest files under `path`, as `encode`
+/// would select them, without writing an archive.
+pub fn analyze_dir(path: &Path, options: &EncodeOptions, top: usize) -> io::Result<()> {
+    let vfs = &OsFs;
+    let mut selector = Selector::new(vfs, options, path)?;
+    let mut dirs: Vec<(String, u64)> = Vec::new();
+    let mut files: Vec<(String, u64)> = Vec::new();
+    let mut unreadable = 0;
+    let ignore_errors = options.ignore_errors.then_some(&mut unreadable);
+    visit_dirs(
+        vfs,
+        path,
+        options.symlinks == Symlinks::Follow,
+        ignore_errors,
+        &mut |entry| {
+            if !selector.select(entry) {
+                return Ok(());
+            }
+            let len = vfs.metadata(&entry.path).map_or(0, |m| m.len);
+            let full = &entry.path;
+            let relative = full.strip_prefix(path).unwrap_or(full);
+            let mut components = relative.components();
+            let first = components.next();
+            // Files directly under the root are grouped together.
+            let dir = match (first, components.next()) {
+                (Some(first), Some(_)) => first.as_os_str().to_string_lossy().into_owned(),
+                _ => ".".to_string(),
+            };
+            match dirs.iter_mut().find(|(name, _)| *name == dir) {
+                Some((_, total)) => *total += len,
+                None => dirs.push((dir, len)),
+            }
+            files.push((relative.to_string_lossy().into_owned(), len));
+            Ok(())
+        },
+    )?;
+
+    dirs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    files.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let total: u64 = dirs.iter().map(|(_, len)| len).sum();
+
+    println!("directories:");
+    for (dir, len) in dirs.iter().take(top) {
+        println!("{:>10}  {}", format_size(*len), dir);
+    }
+    println!("largest files:");
+    for (file, len) in files.iter().take(top) {
+        println!("{:>10}  {}", format_size(*len), file);
+    }
+    println!("{:>10}  total in {} files", format_size(total), files.len());
+    Ok(())
+}
+
+/// A file to archive, the path it is stored under and how its content is packed.
+struct Selected {
+    path: PathBuf,
+    stored: PathBuf,
+    codec: Codec,
+    // Stored in a raw section, see `EncodeOptions::no_compress_ext`.
+    raw: bool,
+    // Where a symlink stored under `--symlinks store` points.
+    link: Option<PathBuf>,
+}
+
+/// A tree to archive: where the walk starts, and the path its entries are stored under.
+/// With `listed`, only those paths are archived instead of the whole tree.
+struct Source<'p> {
+    root: &'p Path,
+    prefix: PathBuf,
+    listed: Option<&'p [Listed]>,
+}
+
+/// One record of `--files-from`: a path to archive, relative to the current directory, and
+/// the directives written after it, which override the options for it and, for a
+/// directory, for everything under it.
+pub struct Listed {
+    pub path: PathBuf,
+    // `codec=store|deflate`, over `--codec-for`.
+    pub codec: Option<Codec>,
+    // `raw=yes|no`, over `--no-compress-ext`.
+    pub raw: Option<bool>,
+    // `as=PATH`: stored under this path rather than its own.
+    pub stored: Option<PathBuf>,
+}
+
+/// Parses a `--files-from` list. Each record ends with a newline, or a NUL with `null`, and
+/// is a path followed by any number of TAB-separated `key=value` directives; a path can't
+/// contain a TAB, nor a newline without `null`. Empty records are skipped. Unknown keys,
+/// bad values and paths outside the current directory without an `as=` are errors.
+pub fn parse_files_from(list: &[u8], null: bool) -> io::Result<Vec<Listed>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let end = if null { b'\0' } else { b'\n' };
+    let mut listed = Vec::new();
+    for (n, record) in list.split(|b| *b == end).enumerate() {
+        if record.is_empty() {
+            continue;
+        }
+        let record = std::str::from_utf8(record)
+            .map_err(|_| invalid(format!("record {} of --files-from is not UTF-8", n + 1)))?;
+        let mut fields = record.split('\t');
+        let path = fields.next().unwrap_or_default();
+        let mut item = Listed {
+            path: PathBuf::from(path),
+            codec: None,
+            raw: None,
+            stored: None,
+        };
+        for directive in fields {
+            let (key, value) = directive.split_once('=').ok_or_else(|| {
+                invalid(format!(
+                    "invalid directive `{}` for {} in --files-from, expected KEY=VALUE",
+                    directive, path
+                ))
+            })?;
+            match key {
+                "codec" => item.codec = Some(Codec::parse(value)?),
+                "raw" => {
+                    item.raw = Some(match value {
+                        "yes" => true,
+                        "no" => false,
+                        _ => {
+                            return Err(invalid(format!(
+                                "invalid `raw={}` for {} in --files-from, expected yes|no",
+                                value, path
+                            )))
+                        }
+                    })
+                }
+                "as" => item.stored = Some(PathBuf::from(value)),
+                _ => {
+                    return Err(invalid(format!(
+                    "unknown directive `{}` for {} in --files-from, known ones are codec|raw|as",
+                    key, path
+                )))
+                }
+            }
+        }
+        // Stored paths are relative and stay under the destination on decode.
+        let escapes = |path: &Path| {
+            path.components()
+                .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        };
+        if let Some(stored) = item.stored.as_deref().filter(|stored| escapes(stored)) {
+            return Err(invalid(format!(
+                "`as={}` for {} in --files-from isn't a relative path under the archive",
+                stored.display(),
+                path
+            )));
+        }
+        if item.stored.is_none() && escapes(&item.path) {
+            return Err(invalid(format!(
+                "{} in --files-from is outside the current directory; give it an `as=` path to store it under",
+                path
+            )));
+        }
+        listed.push(item);
+    }
+    Ok(listed)
+}
+
+/// `path` made absolute against the current directory, with `.` and `..` resolved lexically.
+pub(crate) fn absolute(path: &Path) -> io::Result<PathBuf> {
+    let mut absolute = PathBuf::new();
+    for component in std::path::absolute(path)?.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            component => absolute.push(component),
+        }
+    }
+    Ok(absolute)
+}
+
+/// The stored prefix of `root` under `--relative-to base`: its path from `base`.
+fn relative_prefix(root: &Path, base: &Path) -> io::Result<PathBuf> {
+    let (root_abs, base_abs) = (absolute(root)?, absolute(base)?);
+    match root_abs.strip_prefix(&base_abs) {
+        Ok(prefix) => Ok(prefix.to_path_buf()),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} isn't under --relative-to {}",
+                root.display(),
+                base.display()
+            ),
+        )),
+    }
+}
+
+/// Collects the entries of every source in turn, with one summary for all of them.
+fn select_sources(
+    vfs: &dyn VfsReader,
+    sources: &[Source],
+    options: &EncodeOptions,
+) -> io::Result<(Vec<Selected>, Summary)> {
+    let mut entries = Vec::new();
+    let mut summary = Summary::default();
+    for source in sources {
+        let mut selector = Selector::new(vfs, options, source.root)?;
+        selector.summary = summary;
+        selector.selected = entries.len();
+        entries.extend(collect_entries(source, &mut selector)?);
+        summary = selector.summary;
+    }
+    Ok((entries, summary))
+}
+
+/// Walks `source` and returns the selected files, in the order they should be archived.
+fn collect_entries(source: &Source, selector: &mut Selector) -> io::Result<Vec<Selected>> {
+    let (vfs, options) = (selector.vfs, selector.options);
+    let path = source.root;
+    let follow = options.symlinks == Symlinks::Follow;
+    let mut entries = Vec::new();
+    let mut links = HashSet::new();
+    // The `--files-from` record each listed entry came from.
+    let mut records: HashMap<PathBuf, &Listed> = HashMap::new();
+    let mut unreadable = 0;
+    let mut take = |entry: &DirEntry| {
+        if !selector.select(entry) {
+            return Ok(false);
+        }
+        selector.selected += 1;
+        match options.max_entries {
+            // Stopped as soon as the limit is passed, rather than after walking all of `/`.
+            Some(max) if selector.selected > max => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "more than {} files to archive, the --max-entries limit (stopped at {})",
+                        max,
+                        entry.path.display()
+                    ),
+                ))
+            }
+            _ => {}
+        }
+        if entry.kind == FileKind::Symlink {
+            links.insert(entry.path.clone());
+        }
+        entries.push(entry.path.clone());
+        Ok(true)
+    };
+    match source.listed {
+        None => visit_dirs(
+            vfs,
+            path,
+            follow,
+            options.ignore_errors.then_some(&mut unreadable),
+            &mut |entry| {
+                take(entry)?;
+                Ok(())
+            },
+        )?,
+        Some(listed) => {
+            for record in listed {
+                let metadata = vfs.metadata(&record.path).map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("{} in --files-from: {}", record.path.display(), e),
+                    )
+                })?;
+                let link = vfs.read_link(&record.path).is_ok();
+                if metadata.kind == FileKind::Dir && (follow || !link) {
+                    visit_dirs(
+                        vfs,
+                        &record.path,
+                        follow,
+                        options.ignore_errors.then_some(&mut unreadable),
+                        &mut |entry| {
+                            if take(entry)? {
+                                records.insert(entry.path.clone(), record);
+                            }
+                            Ok(())
+                        },
+                    )?;
+                } else {
+                    let kind = if link {
+                        FileKind::Symlink
+                    } else {
+                        metadata.kind
+                    };
+                    let entry = DirEntry {
+                        path: record.path.clone(),
+                        kind,
+                    };
+                    if take(&entry)? {
+                        records.insert(entry.path, record);
+                    }
+                }
+            }
+        }
+    }
+    selector.summary.skipped_unreadable += unreadable;
+    let entries = match &options.order_from {
+        Some(manifest) => order_by_manifest(path, entries, manifest)?,
+        None => entries,
+    };
+    let codecs: Vec<_> = options
+        .codec_for
+        .iter()
+        .map(|(pattern, codec)| (Glob::new(pattern), *codec))
+        .collect();
+    let mut selected = Vec::with_capacity(entries.len());
+    for path in entries {
+        let record = records.get(&path).copied();
+        let stored = match record.and_then(|record| Some((record, record.stored.as_ref()?))) {
+            Some((record, stored)) => {
+                normalize_stored(&stored.join(path.strip_prefix(&record.path).unwrap_or(&path)))
+            }
+            None => {
+                let under_root = path.strip_prefix(source.root).unwrap_or(&path);
+                normalize_stored(&source.prefix.join(under_root))
+            }
+        };
+        let relative = stored.to_string_lossy().replace('\\', "/");
+        let codec = match record.and_then(|record| record.codec) {
+            Some(codec) => codec,
+            None => codecs
+                .iter()
+                .find(|(glob, _)| glob.matches_path(&relative))
+                .map_or(Codec::Store, |(_, codec)| *codec),
+        };
+        let link = match options.symlinks {
+            Symlinks::Store if links.contains(&path) => {
+                Some(read_link(vfs, selector.root, &path, &mut selector.summary)?)
+            }
+            _ => None,
+        };
+        let raw = link.is_none()
+            && record.and_then(|record| record.raw).unwrap_or_else(|| {
+                stored.extension().is_some_and(|ext| {
+                    let ext = ext.to_string_lossy().to_lowercase();
+                    options.no_compress_ext.contains(&ext)
+                })
+            });
+        selected.push(Selected {
+            path,
+            stored,
+            codec,
+            raw,
+            link,
+        });
+    }
+    let entries = selected;
+    match options.case_collision {
+        Some(strategy) => resolve_case_collisions(entries, strategy),
+        None => Ok(entries),
+    }
+}
+
+/// Where the symlink at `path` points, counted in `summary` and warned about if that is
+/// outside `root`, which can't be restored as it was.
+fn read_link(
+    vfs: &dyn VfsReader,
+    root: &Path,
+    path: &Path,
+    summary: &mut Summary,
+) -> io::Result<PathBuf> {
+    let target = vfs.read_link(path)?;
+    summary.symlinks += 1;
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let dir = relative.parent().unwrap_or_else(|| Path::new(""));
+    if link_escapes(dir, &target) {
+        log::warn(format!(
+            "symlink {} points outside the archived tree, to `{}`; it is stored as-is",
+            path.display(),
+            target.display()
+        ));
+        summary.external_symlinks += 1;
+    }
+    Ok(target)
+}
+
+/// Reports every pair of entries whose stored paths differ only by case, keeping the first
+/// of each under its own name and handling the others according to `strategy`.
+fn resolve_case_collisions(
+    mut entries: Vec<Selected>,
+    strategy: CaseCollision,
+) -> io::Result<Vec<Selected>> {
+    let folded = |path: &Path| path.to_string_lossy().to_lowercase();
+    let mut seen: HashMap<String, PathBuf> = HashMap::new();
+    let mut collisions = 0;
+    for entry in entries.iter_mut() {
+        let first = match seen.get(&folded(&entry.stored)) {
+            Some(first) => first.clone(),
+            None => {
+                seen.insert(folded(&entry.stored), entry.stored.clone());
+                continue;
+            }
+        };
+        collisions += 1;
+        match strategy {
+            CaseCollision::Error | CaseCollision::Keep => log::warn(format!(
+                "`{}` and `{}` differ only by case",
+                first.display(),
+                entry.stored.display()
+            )),
+            CaseCollision::Rename => {
+                let free = (1..)
+                    .map(|n| numbered(&entry.stored, n))
+                    .find(|candidate| !seen.contains_key(&folded(candidate)))
+                    .unwrap();
+                log::warn(format!(
+                    "`{}` and `{}` differ only by case, storing the latter as `{}`",
+                    first.display(),
+                    entry.stored.display(),
+                    free.display()
+                ));
+                seen.insert(folded(&free), free.clone());
+                entry.stored = free;
+            }
+        }
+    }
+    match strategy {
+        CaseCollision::Error if collisions > 0 => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "{} entries collide on a case-insensitive filesystem",
+                collisions
+            ),
+        )),
+        _ => Ok(entries),
+    }
+}
+
+/// Puts `entries` in the order listed in `manifest`, one path per line relative to `root`,
+/// followed by any unlisted entries in their walk (sorted) order.
+fn order_by_manifest(
+    root: &Path,
+    entries: Vec<PathBuf>,
+    manifest: &Path,
+) -> io::Result<Vec<PathBuf>> {
+    let listed = fs::read_to_string(manifest)?;
+    let key = |path: &str| path.trim_start_matches("./").replace('\\', "/");
+    let index: HashMap<String, usize> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let relative = entry.strip_prefix(root).unwrap_or(entry);
+            (key(&relative.to_string_lossy()), i)
+        })
+        .collect();
+
+    let mut remaining: Vec<Option<PathBuf>> = entries.into_iter().map(Some).collect();
+    let mut ordered = Vec::with_capacity(remaining.len());
+    for line in listed
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        match index.get(&key(line)) {
+            Some(&i) => ordered.extend(remaining[i].take()),
+            None => log::warn(format!(
+                "{} is listed in {} but not on disk or excluded, skipping",
+                line,
+                manifest.display()
+            )),
+        }
+    }
+    ordered.extend(remaining.into_iter().flatten());
+    Ok(ordered)
+}
+
+fn encode_tar<W: Write>(
+    vfs: &dyn VfsReader,
+    sources: &[Source],
+    options: &EncodeOptions,
+    mut tar: TarWriter<W>,
+) -> io::Result<W> {
+    let (entries, mut summary) = select_sources(vfs, sources, options)?;
+    let budget = options.budget();
+    for entry in entries {
+        interrupt::check()?;
+        let mut file = match open_entry(vfs, &entry.path, options)? {
+            Some(file) => file,
+            None => {
+                summary.skipped_unreadable += 1;
+                continue;
+            }
+        };
+        summary.archived += 1;
+        let metadata = vfs.metadata(&entry.path)?;
+        let buffer = read_file(&mut *file, &entry.path, metadata.len, &[], budget.as_ref())?;
+        let stored = entry.stored.to_string_lossy().replace('\\', "/");
+        let mtime = if options.preserve_mtime {
+            recorded_mtime(&metadata, options.clamp_mtime)
+        } else {
+            0
+        };
+        let perms = match metadata.perms {
+            Some(perms) if options.preserve_perms => perms,
+            _ => 0o644,
+        };
+        tar.append(&stored, &buffer, perms, mtime)?;
+    }
+    summary.print(options);
+    tar.finish()
+}
+
+/// Opens the file at `path` to archive it. Under `--ignore-errors` one that can't be opened
+/// is warned about and `None`.
+fn open_entry<'v>(
+    vfs: &'v dyn VfsReader,
+    path: &Path,
+    options: &EncodeOptions,
+) -> io::Result<Option<Box<dyn Read + 'v>>> {
+    match vfs.open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) => {
+            let msg = format!("can't read {}: {}", path.display(), e);
+            if !options.ignore_errors {
+                return Err(io::Error::new(e.kind(), msg));
+            }
+            log::warn(format!("{}, skipped it", msg));
+            Ok(None)
+        }
+    }
+}
+
+/// Reads `file`, the file at `path`, `len` bytes long, without the bytes in `holes`,
+/// spilling to disk past `budget`.
+fn read_file(
+    file: &mut dyn Read,
+    path: &Path,
+    len: u64,
+    holes: &[(u64, u64)],
+    budget: Option<&Arc<Budget>>,
+) -> io::Result<Buffer> {
+    let data_len = len.saturating_sub(holes.iter().map(|&(_, len)| len).sum());
+    let what = || path.display().to_string();
+    Buffer::write_with(data_len, budget, what, |out| {
+        sparse::copy_data(file, holes, out)
+    })
+}
+
+/// Reads one file and prepares it for the archive, or `None` for a file skipped under
+/// `--ignore-errors`. This is the per-file work spread across `--threads`.
+fn prepare_entry(
+    vfs: &dyn VfsReader,
+    entry: &Selected,
+    mode: Mode,
+    options: &EncodeOptions,
+    budget: Option<&Arc<Budget>>,
+) -> io::Result<Option<Prepared>> {
+    log::verbose(log::FILES, || format!("archived {}", entry.path.display()));
+    if let Some(target) = &entry.link {
+        let mut fields = Separator::new("");
+        fields.link = Some(target.to_string_lossy().into_owned());
+        return writer::prepare(
+            &entry.stored,
+            Buffer::new(Vec::new()),
+            mode,
+            options.writer_options(),
+            Codec::Store,
+            fields,
+            budget,
+        )
+        .map(Some);
+    }
+    let mut file = match open_entry(vfs, &entry.path, options)? {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+    let mut fields = Separator::new("");
+    if options.sparse {
+        fields.holes = vfs.holes(&entry.path)?;
+    }
+    if !fields.holes.is_empty() {
+        log::verbose(log::DECISIONS, || {
+            format!(
+                "left {} holes of {} out of the archive",
+                fields.holes.len(),
+                entry.path.display()
+            )
+        });
+    }
+    let metadata = vfs.metadata(&entry.path)?;
+    let buffer = read_file(&mut *file, &entry.path, metadata.len, &fields.holes, budget)?;
+    // A sparse file is better off compressed, holes left out, than raw in full.
+    fields.raw = entry.raw && fields.holes.is_empty();
+    if fields.raw {
+        log::verbose(log::DECISIONS, || {
+            format!(
+                "stored {} uncompressed, in a raw section",
+                entry.path.display()
+            )
+        });
+    }
+    if options.preserve_mtime {
+        fields.mtime = Some(recorded_mtime(&metadata, options.clamp_mtime));
+    }
+    if options.preserve_btime {
+        // Clamped like the mtime, so a reproducible archive doesn't record a later time.
+        fields.btime = match (metadata.btime, options.clamp_mtime) {
+            (Some(btime), Some(clamp)) => Some(btime.min(clamp)),
+            (btime, _) => btime,
+        };
+        if fields.btime.is_none() {
+            btime::warn_unreadable(&entry.path);
+        }
+    }
+    if options.preserve_perms {
+        fields.perms = metadata.perms;
+    }
+    if options.preserve_ownership {
+        fields.owner = metadata.owner;
+    }
+    writer::prepare(
+        &entry.stored,
+        buffer,
+        mode,
+        options.writer_options(),
+        entry.codec,
+        fields,
+        budget,
+    )
+    .map(Some)
+}
+
+/// Warns that the binary file stored as `path` makes a plain archive binary, or refuses it
+/// under `--strict`.
+fn check_binary(path: &str, options: &EncodeOptions) -> io::Result<()> {
+    let msg = format!(
+        "{} is binary, so the plain archive won't be text; use --base64 or --auto to keep it text",
+        path
+    );
+    if options.strict {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    log::warn(msg);
+    Ok(())
+}
+
+/// Where an encode writes the archive: a file that only shows up once it is complete, or
+/// stdout for `-o -`.
+enum Output {
+    File(AtomicFile),
+    Stdout(BlockingWriter<io::Stdout>),
+}
+
+impl Output {
+    fn create(options: &EncodeOptions) -> io::Result<Output> {
+        if options.to_stdout() {
+            return Ok(Output::Stdout(BlockingWriter::new(io::stdout())));
+        }
+        let file = AtomicFile::create(&options.output, options.temp_dir.as_deref())?;
+        Ok(Output::File(file))
+    }
+
+    fn commit(self) -> io::Result<()> {
+        match self {
+            Output::File(file) => file.commit(),
+            Output::Stdout(mut out) => out.flush(),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::File(file) => file.write(buf),
+            Output::Stdout(out) => out.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::File(file) => file.flush(),
+            Output::Stdout(out) => out.flush(),
+        }
+    }
+}
+
+/// Counts the bytes written through it, for the compression ratio in the summary.
+struct Counted<W: Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Archives the files under `path` into `options.output`.
+pub fn encode_dir(path: &Path, mode: Mode, options: &EncodeOptions) -> io::Result<()> {
+    encode_from(&OsFs, path, mode, options)
+}
+
+/// Like [`encode_dir`], walking and reading the tree through `vfs`, e.g. a
+/// [`MemoryFs`](crate::vfs::MemoryFs). The archive itself is still written to
+/// `options.output`.
+pub fn encode_from(
+    vfs: &dyn VfsReader,
+    path: &Path,
+    mode: Mode,
+    options: &EncodeOptions,
+) -> io::Result<()> {
+    let prefix = match &options.relative_to {
+        Some(base) => relative_prefix(path, base)?,
+        None => normalize_stored(path),
+    };
+    let source = Source {
+        root: path,
+        prefix,
+        listed: None,
+    };
+    encode_sources(vfs, &[source], mode, options)
+}
+
+/// Archives several trees into one archive. Each tree's entries are stored under its own
+/// name, so `/srv/a` and `/srv/b` give `a/...` and `b/...`, or under its path from
+/// `options.relative_to`; a tree without a name of its own, like `.`, stores them as they are.
+pub fn encode_paths(
+    vfs: &dyn VfsReader,
+    paths: &[PathBuf],
+    mode: Mode,
+    options: &EncodeOptions,
+) -> io::Result<()> {
+    let sources = paths
+        .iter()
+        .map(|root| {
+            let prefix = match &options.relative_to {
+                Some(base) => relative_prefix(root, base)?,
+                None => root.file_name().map(PathBuf::from).unwrap_or_default(),
+            };
+            Ok(Source {
+                root,
+                prefix,
+                listed: None,
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    encode_sources(vfs, &sources, mode, options)
+}
+
+/// Archives the paths of a `--files-from` list, see [`parse_files_from`], in list order and
+/// under the same filters as a walk. A listed directory is archived whole.
+pub fn encode_listed(
+    vfs: &dyn VfsReader,
+    listed: &[Listed],
+    mode: Mode,
+    options: &EncodeOptions,
+) -> io::Result<()> {
+    let compressed = matches!(mode, Mode::CompressedBinary | Mode::CompressedTxt);
+    let invalid = |msg: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    if compressed && listed.iter().any(|item| item.codec.is_some()) {
+        return invalid("`codec=` in --files-from only applies to plain, base64 and auto archives; binary and text compress every entry already");
+    }
+    if !compressed && listed.iter().any(|item| item.raw.is_some()) {
+        return invalid("`raw=` in --files-from only applies to binary and text archives");
+    }
+    let source = Source {
+        root: Path::new("."),
+        prefix: PathBuf::new(),
+        listed: Some(listed),
+    };
+    encode_sources(vfs, &[source], mode, options)
+}
+
+/// The index an archive of the tree at `path` would have, its entries stored relative to
+/// `path` and hashed as `hash_scope` says, without writing one. The walk is the one encode
+/// does, filters and all, so `verify --against` compares like with like.
+pub fn index_dir(
+    vfs: &dyn VfsReader,
+    path: &Path,
+    hash_scope: HashScope,
+    options: &EncodeOptions,
+) -> io::Result<Index> {
+    let source = Source {
+        root: path,
+        prefix: PathBuf::new(),
+        listed: None,
+    };
+    let (entries, _) = select_sources(vfs, &[source], options)?;
+    let mut index = Index {
+        hash_scope,
+        entries: BTreeMap::new(),
+        sizes: BTreeMap::new(),
+    };
+    let mut chunk = vec![0; 64 * 1024];
+    for entry in entries {
+        interrupt::check()?;
+        // Hashed as `writer::prepare` does, under the stored path and over the content.
+        let stored = normalize_stored(&entry.stored)
+            .to_string_lossy()
+            .into_owned();
+        let mut hasher = hash_scope.hasher(&stored);
+        let mut size = 0;
+        // A stored symlink has no content.
+        if entry.link.is_none() {
+            let mut file = match open_entry(vfs, &entry.path, options)? {
+                Some(file) => file,
+                None => continue,
+            };
+            loop {
+                let n = match file.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                hasher.write(&chunk[..n]);
+                size += n as u64;
+            }
+        }
+        let key = split_stored(&stored).collect::<Vec<_>>().join("/");
+        index.sizes.insert(key.clone(), size);
+        index.entries.insert(key, hasher.finish().to_string());
+    }
+    Ok(index)
+}
+
+fn encode_sources(
+    vfs: &dyn VfsReader,
+    sources: &[Source],
+    mode: Mode,
+    options: &EncodeOptions,
+) -> io::Result<()> {
+    let compressed = matches!(mode, Mode::CompressedBinary | Mode::CompressedTxt);
+    if compressed && !options.codec_for.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--codec-for only applies to plain, base64 and auto archives; binary and text compress every entry already",
+        ));
+    }
+    if !compressed && !options.no_compress_ext.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--no-compress-ext only applies to binary and text archives; the others compress no entry unless --codec-for says so",
+        ));
+    }
+    let tar = !matches!(options.output_format, OutputFormat::Mito);
+    if tar && options.symlinks == Symlinks::Store {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--symlinks store only applies to mito archives",
+        ));
+    }
+    match options.output_format {
+        OutputFormat::Mito => {}
+        OutputFormat::Tar => {
+            let out = Output::create(options)?;
+            return encode_tar(vfs, sources, options, TarWriter::new(out))?.commit();
+        }
+        OutputFormat::TarGz => {
+            let gz = GzEncoder::new(Output::create(options)?, Compression::new(options.level));
+            return encode_tar(vfs, sources, options, TarWriter::new(gz))?
+                .finish()?
+                .commit();
+        }
+    }
+
+    let mut summary = encode_mito(vfs, sources, mode, options, false)?;
+    let saving = summary.saving();
+    match (options.min_ratio, saving) {
+        (Some(min), Some(saving)) if saving < min => {
+            // Plain is as small as binary gets without compression; base64 keeps text text.
+            let fallback = match mode {
+                Mode::CompressedTxt => Mode::Base64,
+                _ => Mode::Plain,
+            };
+            if options.to_stdout() {
+                log::warn(format!(
+                    "compression {}, under --min-ratio {}%, but an archive written to stdout can't be rewritten as {}",
+                    saved(saving), min, fallback
+                ));
+            } else {
+                log::warn(format!(
+                    "compression {}, under --min-ratio {}%, so the archive is rewritten as {}; decode it with --{}",
+                    saved(saving),
+                    min,
+                    fallback,
+                    fallback
+                ));
+                summary = encode_mito(vfs, sources, fallback, options, true)?;
+            }
+        }
+        (None, Some(saving)) if saving < LOW_SAVING => {
+            let instead = match mode {
+                Mode::CompressedTxt => "--base64",
+                _ => "--plain",
+            };
+            summary.recommendation = Some(format!(
+                "compression {}, so {} would be about as small and quicker; --min-ratio {} falls back to it by itself",
+                saved(saving), instead, LOW_SAVING
+            ));
+        }
+        _ => {}
+    }
+    summary.print(options);
+    Ok(())
+}
+
+/// What compression did to the archive, `saving` percent smaller.
+fn saved(saving: f64) -> String {
+    if saving < 0.0 {
+        format!("made the archive {:.1}% larger", -saving)
+    } else {
+        format!("saved only {:.1}%", saving)
+    }
+}
+
+/// Writes the mito archive of `sources` in `mode` and returns what it archived. A plain
+/// archive written as the fallback of a binary one (`rewrite`) doesn't warn about binary
+/// files, which the binary archive held just the same.
+fn encode_mito(
+    vfs: &dyn VfsReader,
+    sources: &[Source],
+    mode: Mode,
+    options: &EncodeOptions,
+    rewrite: bool,
+) -> io::Result<Summary> {
+    // Several sources have no one root to record.
+    let root = match sources {
+        [source] if options.record_root => {
+            Some(absolute(source.root)?.to_string_lossy().into_owned())
+        }
+        [_, _, ..] if options.record_root => {
+            log::warn("--record-root needs a single directory to encode, no root is recorded");
+            None
+        }
+        _ => None,
+    };
+    let out_file = BufWriter::new(Counted {
+        inner: Output::create(options)?,
+        written: 0,
+    });
+    let mut archive = ArchiveWriter::with_root(out_file, mode, options.writer_options(), root)?;
+
+    let (entries, mut summary) = select_sources(vfs, sources, options)?;
+    summary.archived += entries.len();
+    let budget = options.budget();
+    let mut uncompressed = 0;
+    pool::for_each_ordered(
+        entries,
+        options.threads,
+        |entry| prepare_entry(vfs, &entry, mode, options, budget.as_ref()),
+        |prepared| {
+            interrupt::check()?;
+            let prepared = match prepared? {
+                Some(prepared) => prepared,
+                None => {
+                    summary.archived -= 1;
+                    summary.skipped_unreadable += 1;
+                    return Ok(());
+                }
+            };
+            if prepared.binary() && !rewrite {
+                check_binary(prepared.path(), options)?;
+                summary.binary.push(prepared.path().to_string());
+            }
+            // What the entry would take in the fallback of `--min-ratio`.
+            uncompressed += match mode {
+                Mode::CompressedBinary => prepared.plain_len(),
+                _ => prepared.len(),
+            };
+            archive.add_prepared(&prepared)
+        },
+    )?;
+    let out = archive.finish()?.into_inner().map_err(|e| e.into_error())?;
+    if matches!(mode, Mode::CompressedBinary | Mode::CompressedTxt) {
+        summary.compressed = Some((uncompressed, out.written));
+    }
+    out.inner.commit()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header;
+    use crate::selftest::{encode_options, Scratch};
+    use crate::separator::{self, Separator};
+    use crate::vfs::{DirEntry, MemoryFs, Metadata, OsFs};
+
+    /// The separators of the plain archive at `archive`, in order.
+    fn separators(archive: &Path) -> Vec<Separator> {
+        fs::read_to_string(archive)
+            .unwrap()
+            .split_inclusive('\n')
+            .filter_map(|line| separator::from_line(line.as_bytes(), header::VERSION).unwrap())
+            .collect()
+    }
+
+    /// Encodes `.` of `vfs` as a plain archive with `symlinks`, returning its separators.
+    fn encode_memory(name: &str, vfs: &MemoryFs, symlinks: Symlinks) -> Vec<Separator> {
+        let scratch = Scratch::create(name).unwrap();
+        let archive = scratch.path.join("archive.out");
+        let mut options = encode_options(archive.clone(), Path::new("."));
+        options.relative_to = None;
+        options.symlinks = symlinks;
+        encode_from(vfs, Path::new("."), Mode::Plain, &options).unwrap();
+        separators(&archive)
+    }
+
+    fn synthetic_tree() -> MemoryFs {
+        let mut vfs = MemoryFs::new();
+        vfs.add_file("b.txt", "b\n", 0)
+            .add_file("a/z.txt", "z\n", 0)
+            .add_file("a/c.txt", "c\n", 0)
+            .add_file("debug.log", "log\n", 0)
+            .add_file(IGNORE_FILE, "*.log\n", 0)
+            .add_file("target/built", "built\n", 0)
+            .add_file("src/node_modules/dep.js", "dep\n", 0)
+            .add_symlink("link.txt", "b.txt")
+            .add_dir("empty");
+        vfs
+    }
+
+    #[test]
+    fn walk_over_a_synthetic_tree_is_sorted_and_filtered() {
+        let stored: Vec<_> = encode_memory("encode-memory", &synthetic_tree(), Symlinks::Follow)
+            .into_iter()
+            .map(|separator| (separator.path, separator.link))
+            .collect();
+        let expected = [".mitoignore", "a/c.txt", "a/z.txt", "b.txt", "link.txt"];
+        assert_eq!(
+            stored,
+            expected.map(|path| (path.to_string(), None)).to_vec()
+        );
+    }
+
+    #[test]
+    fn symlinks_in_a_synthetic_tree_are_stored() {
+        let separators = encode_memory("encode-memory-links", &synthetic_tree(), Symlinks::Store);
+        let link = separators.iter().find(|s| s.path == "link.txt").unwrap();
+        assert_eq!(link.link.as_deref(), Some("b.txt"));
+    }
+
+    /// `MemoryFs` with one directory that can't be listed, as without the permission to.
+    struct Unlistable {
+        inner: MemoryFs,
+        dir: PathBuf,
+    }
+
+    impl VfsReader for Unlistable {
+        fn read_dir(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+            if normalize_stored(dir) == self.dir {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Permission denied",
+                ));
+            }
+            self.inner.read_dir(dir)
+        }
+
+        fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+            self.inner.open(path)
+        }
+
+        fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+            self.inner.metadata(path)
+        }
+
+        fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+            self.inner.read_link(path)
+        }
+    }
+
+    #[test]
+    fn unlistable_directory_is_skipped_under_ignore_errors() {
+        let mut inner = MemoryFs::new();
+        inner
+            .add_file("a.txt", "a\n", 0)
+            .add_file("locked/secret", "s\n", 0)
+            .add_file("z/b.txt", "b\n", 0);
+        let vfs = Unlistable {
+            inner,
+            dir: PathBuf::from("locked"),
+        };
+        let scratch = Scratch::create("encode-unlistable").unwrap();
+        let archive = scratch.path.join("archive.out");
+        let mut options = encode_options(archive.clone(), Path::new("."));
+        options.relative_to = None;
+
+        let e = encode_from(&vfs, Path::new("."), Mode::Plain, &options).unwrap_err();
+        assert!(e.to_string().contains("locked"), "{}", e);
+        assert!(!archive.exists());
+
+        options.ignore_errors = true;
+        encode_from(&vfs, Path::new("."), Mode::Plain, &options).unwrap();
+        let stored: Vec<_> = separators(&archive).into_iter().map(|s| s.path).collect();
+        assert_eq!(stored, ["a.txt", "z/b.txt"]);
+    }
+
+    /// The CRC-32 of the archive of a small fixed tree in `mode`.
+    fn archive_digest(name: &str, mode: Mode) -> u32 {
+        let scratch = Scratch::create(name).unwrap();
+        let root = scratch.path.join("tree");
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("a.txt"), "hello\n".repeat(100)).unwrap();
+        fs::write(root.join("dir/b.bin"), (0..=255).collect::<Vec<u8>>()).unwrap();
+        let archive = scratch.path.join("archive.out");
+        let options = encode_options(archive.clone(), &root);
+        encode_paths(&OsFs, &[root], mode, &options).unwrap();
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&fs::read(&archive).unwrap());
+        crc.finalize()
+    }
+
+    // From the pinned `miniz_oxide` backend: another backend, or a change to what an archive
+    // holds, changes them.
+    #[test]
+    fn compressed_archives_are_reproducible() {
+        assert_eq!(
+            archive_digest("encode-digest-binary", Mode::CompressedBinary),
+            2344373297
+        );
+        assert_eq!(
+            archive_digest("encode-digest-text", Mode::CompressedTxt),
+            2986530597
+        );
+    }
+
+    #[test]
+    fn stored_paths_have_no_dot_components_or_repeated_slashes() {
+        let scratch = Scratch::create("encode-clean-paths").unwrap();
+        let root = scratch.path.join("tree");
+        fs::create_dir_all(root.join("dir/sub")).unwrap();
+        fs::write(root.join("a.txt"), "a\n").unwrap();
+        fs::write(root.join("dir/sub/b.txt"), "b\n").unwrap();
+        let archive = scratch.path.join("archive.out");
+        let source = PathBuf::from(format!("{}/.//tree/.//", scratch.path.display()));
+        let mut options = encode_options(archive.clone(), &scratch.path);
+        options.relative_to = Some(PathBuf::from(format!("{}/.", scratch.path.display())));
+        encode_paths(&OsFs, &[source], Mode::Plain, &options).unwrap();
+        let archived = fs::read_to_string(&archive).unwrap();
+        let stored: Vec<_> = archived
+            .lines()
+            .filter_map(|line| {
+                separator::from_line(format!("{}\n", line).as_bytes(), header::VERSION).ok()?
+            })
+            .map(|separator| separator.path)
+            .collect();
+        assert_eq!(stored, ["tree/a.txt", "tree/dir/sub/b.txt"]);
+        for path in &stored {
+            assert!(!path.contains("./") && !path.contains("//"), "{}", path);
+        }
+    }
+
+    #[test]
+    fn root_under_an_ignored_directory_is_archived() {
+        let scratch = Scratch::create("encode-ignored-root").unwrap();
+        let root = scratch.path.join("target/proj");
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join("kept.txt"), "kept\n").unwrap();
+        fs::write(root.join("target/built"), "built\n").unwrap();
+        let archive = scratch.path.join("archive.out");
+        let options = encode_options(archive.clone(), &root);
+        encode_paths(&OsFs, &[root], Mode::Plain, &options).unwrap();
+        let archived = fs::read_to_string(&archive).unwrap();
+        assert!(archived.contains("====kept.txt|"));
+        assert!(!archived.contains("built"));
+    }
+}
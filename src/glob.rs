@@ -0,0 +1,112 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A shell-style pattern over `/`-separated paths relative to the walk root: `*` matches
+/// within one path component, `**` across components and `?` any one character.
+///
+/// A pattern without a `/` matches a file or directory name at any depth, like `target`;
+/// one with a `/` matches from the root, like `src/generated` or `/build`.
+pub struct Glob {
+    pattern: Vec<char>,
+    anchored: bool,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Glob {
+        let pattern = pattern.trim_start_matches("./").trim_end_matches('/');
+        let anchored = pattern.contains('/');
+        Glob {
+            pattern: pattern.trim_start_matches('/').chars().collect(),
+            anchored,
+        }
+    }
+
+    /// Whether `relative`, or any directory it is in, matches the pattern.
+    pub fn matches_path(&self, relative: &str) -> bool {
+        let relative = relative.trim_start_matches("./");
+        if !self.anchored {
+            return relative
+                .split('/')
+                .any(|component| matches(&self.pattern, &component.chars().collect::<Vec<_>>()));
+        }
+        let text: Vec<char> = relative.chars().collect();
+        // Every prefix that ends at a component boundary names a directory on the way.
+        (1..=text.len())
+            .filter(|end| *end == text.len() || text[*end] == '/')
+            .any(|end| matches(&self.pattern, &text[..end]))
+    }
+}
+
+/// A `.mitoignore` or `.mitoinclude` file: one `Glob` per line, with blank lines and `#`
+/// comments skipped. A line starting with `!` negates the pattern, and the last line that
+/// matches a path decides, as in `.gitignore`.
+pub struct PatternFile {
+    // Each pattern as written, whether it is negated, and the glob itself.
+    rules: Vec<(String, bool, Glob)>,
+}
+
+impl PatternFile {
+    pub fn parse(text: &str) -> PatternFile {
+        let rules = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (negated, pattern) = match line.strip_prefix('!') {
+                    Some(pattern) => (true, pattern),
+                    None => (false, line),
+                };
+                (line.to_string(), negated, Glob::new(pattern))
+            })
+            .collect();
+        PatternFile { rules }
+    }
+
+    /// Reads the pattern file at `path`, or `None` if there isn't one.
+    pub fn load(path: &Path) -> io::Result<Option<PatternFile>> {
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(Some(PatternFile::parse(&text))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The line that decides `relative` matches, or `None` if no line does or the deciding
+    /// line is negated.
+    pub fn matched(&self, relative: &str) -> Option<&str> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(_, _, glob)| glob.matches_path(relative))
+            .filter(|(_, negated, _)| !negated)
+            .map(|(line, _, _)| line.as_str())
+    }
+}
+
+fn matches(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            // `**/` also matches no directories at all.
+            if rest.first() == Some(&'/') && matches(&rest[1..], text) {
+                return true;
+            }
+            (0..=text.len()).any(|i| matches(rest, &text[i..]))
+        }
+        Some('*') => {
+            for i in 0..=text.len() {
+                if matches(&pattern[1..], &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == '/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => !text.is_empty() && text[0] != '/' && matches(&pattern[1..], &text[1..]),
+        Some(c) => text.first() == Some(c) && matches(&pattern[1..], &text[1..]),
+    }
+}
@@ -0,0 +1,174 @@
+use std::io::Read;
+
+use flate2::read::ZlibDecoder;
+
+use crate::{Alphabet, HashScope, Mode};
+
+/// Starts the first line of every archive, directly followed by the format version.
+pub const MAGIC: &str = "MITO/";
+pub const VERSION: u32 = 2;
+
+/// How much of a file `is_archive` needs to recognize it.
+pub const SNIFF_LEN: usize = 1024;
+
+/// The first line of an archive, e.g. `MITO/2 mode=base64`. Compressed archives carry it
+/// inside the compressed stream.
+pub struct Header {
+    pub version: u32,
+    pub mode: Mode,
+    pub hash_scope: HashScope,
+    // The alphabet of the base64 in the archive, entry bodies and `--text` alike.
+    pub alphabet: Alphabet,
+    // The directory that was encoded, as an absolute path. Only ever shown and compared,
+    // never used to place entries, whose paths stay relative.
+    pub root: Option<String>,
+}
+
+/// `s` with `%`, spaces and line breaks written as `%25`, `%20`, `%0D` and `%0A`, so it fits
+/// in one header field.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '%' => escaped.push_str("%25"),
+            ' ' => escaped.push_str("%20"),
+            '\r' => escaped.push_str("%0D"),
+            '\n' => escaped.push_str("%0A"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(s: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(at) = rest.find('%') {
+        unescaped.push_str(&rest[..at]);
+        unescaped.push(match rest.get(at + 1..at + 3)? {
+            "25" => '%',
+            "20" => ' ',
+            "0D" | "0d" => '\r',
+            "0A" | "0a" => '\n',
+            _ => return None,
+        });
+        rest = &rest[at + 3..];
+    }
+    unescaped.push_str(rest);
+    Some(unescaped)
+}
+
+impl Header {
+    pub fn new(mode: Mode, hash_scope: HashScope) -> Header {
+        Header {
+            version: VERSION,
+            mode,
+            hash_scope,
+            alphabet: Alphabet::Standard,
+            root: None,
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        let mut line = format!("{}{} mode={}", MAGIC, self.version, self.mode);
+        // Archives hashing content only, the default, don't say so.
+        if self.hash_scope != HashScope::Content {
+            line.push_str(&format!(" hash={}", self.hash_scope.name()));
+        }
+        if self.alphabet != Alphabet::Standard {
+            line.push_str(&format!(" base64={}", self.alphabet.name()));
+        }
+        if let Some(root) = &self.root {
+            line.push_str(&format!(" root={}", escape(root)));
+        }
+        line.push('\n');
+        line
+    }
+
+    /// Parses a line starting with `MAGIC`, or says what is wrong with it. Unknown
+    /// `key=value` fields are ignored so newer archives stay readable.
+    pub fn parse(line: &[u8]) -> Result<Header, String> {
+        let line = std::str::from_utf8(line).map_err(|_| "header is not UTF-8".to_string())?;
+        let mut fields = line.trim_end().split(' ');
+        let version = fields
+            .next()
+            .and_then(|magic| magic.strip_prefix(MAGIC))
+            .and_then(|version| version.parse().ok())
+            .ok_or_else(|| format!("expected `{}<version>`", MAGIC))?;
+        let mut mode = None;
+        let mut hash_scope = HashScope::Content;
+        let mut alphabet = Alphabet::Standard;
+        let mut root = None;
+        for field in fields {
+            if let Some(name) = field.strip_prefix("mode=") {
+                mode = Some(
+                    name.parse()
+                        .map_err(|_| format!("unknown mode `{}`", name))?,
+                );
+            } else if let Some(name) = field.strip_prefix("hash=") {
+                hash_scope =
+                    HashScope::parse(name).map_err(|_| format!("unknown hash scope `{}`", name))?;
+            } else if let Some(name) = field.strip_prefix("base64=") {
+                alphabet = Alphabet::parse(name)
+                    .ok_or_else(|| format!("unknown base64 alphabet `{}`", name))?;
+            } else if let Some(path) = field.strip_prefix("root=") {
+                root =
+                    Some(unescape(path).ok_or_else(|| format!("invalid escape in `{}`", field))?);
+            }
+        }
+        Ok(Header {
+            version,
+            mode: mode.ok_or("missing `mode=`")?,
+            hash_scope,
+            alphabet,
+            root,
+        })
+    }
+}
+
+/// The mode of the archive `prefix` (its first `SNIFF_LEN` bytes) starts, looking through the
+/// zlib (and base64) wrapping of the compressed modes. `None` if it isn't a mito archive.
+pub fn detect_mode(prefix: &[u8]) -> Option<Mode> {
+    let starts_with_magic = |inflated: &mut dyn Read| {
+        let mut magic = [0; MAGIC.len()];
+        let mut filled = 0;
+        // The prefix cuts the stream short, so stop at the first error rather than fail.
+        while filled < magic.len() {
+            match inflated.read(&mut magic[filled..]) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => filled += n,
+            }
+        }
+        magic[..filled] == *MAGIC.as_bytes()
+    };
+    if prefix.starts_with(MAGIC.as_bytes()) {
+        let line = match prefix.iter().position(|b| *b == b'\n') {
+            Some(end) => &prefix[..end],
+            // A header longer than the prefix, with a long `root=`, is cut in its last field.
+            None => prefix.rsplitn(2, |b| *b == b' ').last().unwrap_or(prefix),
+        };
+        return Header::parse(line).ok().map(|header| header.mode);
+    }
+    if starts_with_magic(&mut ZlibDecoder::new(prefix)) {
+        return Some(Mode::CompressedBinary);
+    }
+    // `--wrap` breaks the base64 into lines.
+    let unwrapped: Vec<u8> = prefix
+        .iter()
+        .copied()
+        .filter(|b| !matches!(b, b'\n' | b'\r'))
+        .collect();
+    let whole = &unwrapped[..unwrapped.len() / 4 * 4];
+    let alphabet = Alphabet::of(whole).unwrap_or(Alphabet::Standard);
+    match base64::decode_config(whole, alphabet.config()) {
+        Ok(compressed) if starts_with_magic(&mut ZlibDecoder::new(&compressed[..])) => {
+            Some(Mode::CompressedTxt)
+        }
+        _ => None,
+    }
+}
+
+/// Whether `prefix`, the first `SNIFF_LEN` bytes of a file, starts a mito archive in any mode.
+pub fn is_archive(prefix: &[u8]) -> bool {
+    prefix.starts_with(MAGIC.as_bytes()) || detect_mode(prefix).is_some()
+}
@@ -0,0 +1,48 @@
+// Ctrl-C. The first SIGINT only sets a flag, which encode checks between entries and decode
+// between lines, so each stops at a point it can clean up after: the temporary archive is
+// removed when it is dropped uncommitted, and a half-extracted file is removed by decode. A
+// second SIGINT kills the process as usual, for when something hangs.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The exit status after an interrupt, 128 plus `SIGINT` as shells report it.
+pub const EXIT_CODE: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Catches the next SIGINT instead of dying of it. Without a call, nothing changes.
+#[cfg(unix)]
+pub fn install() {
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe, and
+    // `action` is fully initialized before `sigaction` reads it.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // Back to the default after the first one.
+        action.sa_flags = libc::SA_RESETHAND;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install() {}
+
+/// Whether SIGINT was caught.
+pub fn requested() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Fails with `ErrorKind::Interrupted` once SIGINT was caught.
+pub fn check() -> io::Result<()> {
+    if requested() {
+        return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+    }
+    Ok(())
+}
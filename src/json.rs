@@ -0,0 +1,216 @@
+/// `s` as a quoted JSON string.
+pub fn string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c < ' ' => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A parsed JSON value. Numbers keep their text, so that integers of any size survive.
+pub enum Value {
+    Null,
+    // No manifest member is a boolean yet; they parse all the same.
+    #[allow(dead_code)]
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The member `key` of an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Number(n) => n.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+/// Parses `s`, which must hold one JSON value and nothing else but whitespace.
+pub fn parse(s: &str) -> Result<Value, String> {
+    let mut parser = Parser {
+        bytes: s.as_bytes(),
+        at: 0,
+    };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.at < parser.bytes.len() {
+        return Err(parser.error("trailing characters"));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, what: &str) -> String {
+        format!("{} at byte {}", what, self.at)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.bytes.get(self.at) {
+            self.at += 1;
+        }
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<(), String> {
+        if self.bytes[self.at..].starts_with(literal.as_bytes()) {
+            self.at += literal.len();
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", literal)))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.bytes.get(self.at) {
+            Some(b'n') => self.expect("null").map(|_| Value::Null),
+            Some(b't') => self.expect("true").map(|_| Value::Bool(true)),
+            Some(b'f') => self.expect("false").map(|_| Value::Bool(false)),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'[') => {
+                self.at += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.at) == Some(&b']') {
+                    self.at += 1;
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.bytes.get(self.at) {
+                        Some(b',') => self.at += 1,
+                        Some(b']') => {
+                            self.at += 1;
+                            return Ok(Value::Array(items));
+                        }
+                        _ => return Err(self.error("expected `,` or `]`")),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.at += 1;
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.at) == Some(&b'}') {
+                    self.at += 1;
+                    return Ok(Value::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    if self.bytes.get(self.at) != Some(&b'"') {
+                        return Err(self.error("expected a member name"));
+                    }
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(":")?;
+                    members.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.bytes.get(self.at) {
+                        Some(b',') => self.at += 1,
+                        Some(b'}') => {
+                            self.at += 1;
+                            return Ok(Value::Object(members));
+                        }
+                        _ => return Err(self.error("expected `,` or `}`")),
+                    }
+                }
+            }
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.at;
+                while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') =
+                    self.bytes.get(self.at)
+                {
+                    self.at += 1;
+                }
+                let number = String::from_utf8_lossy(&self.bytes[start..self.at]).into_owned();
+                Ok(Value::Number(number))
+            }
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    /// A string, the parser at its opening quote.
+    fn string(&mut self) -> Result<String, String> {
+        self.at += 1;
+        let mut s = Vec::new();
+        loop {
+            match self.bytes.get(self.at) {
+                None => return Err(self.error("unterminated string")),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    let escaped = match self.bytes.get(self.at + 1) {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            let hex = self
+                                .bytes
+                                .get(self.at + 2..self.at + 6)
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                .ok_or_else(|| self.error("invalid `\\u` escape"))?;
+                            self.at += 4;
+                            // Surrogate pairs aren't put back together; mito writes none.
+                            char::from_u32(hex).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    let mut buf = [0; 4];
+                    s.extend_from_slice(escaped.encode_utf8(&mut buf).as_bytes());
+                    self.at += 2;
+                }
+                Some(&b) => {
+                    s.push(b);
+                    self.at += 1;
+                }
+            }
+        }
+        self.at += 1;
+        String::from_utf8(s).map_err(|_| self.error("string is not UTF-8"))
+    }
+}
@@ -0,0 +1,231 @@
+//! Archives a directory tree into a single text (or compressed) file and extracts it again.
+//!
+//! The `mito` binary is a thin command-line front end over [`encode`] and [`decode`];
+//! [`writer::ArchiveWriter`] builds archives from sources other than a directory.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::Hasher;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+mod atomic;
+mod btime;
+pub mod chunks;
+pub mod cli;
+pub mod completions;
+pub mod concat;
+pub mod config;
+pub mod decode;
+pub mod diff;
+pub mod encode;
+pub mod glob;
+pub mod header;
+pub mod interrupt;
+mod json;
+mod lines;
+pub mod log;
+pub mod native;
+mod owner;
+mod perms;
+pub mod pool;
+pub mod selftest;
+pub mod separator;
+mod space;
+mod sparse;
+mod spill;
+pub mod stack;
+mod tar;
+mod trailer;
+pub mod vfs;
+pub mod volumes;
+pub mod writer;
+mod zip;
+
+/// `path` without `.` components or repeated separators, as it is stored, so the walk
+/// starting at `.` stores `src/main.rs` rather than `./src/main.rs`.
+pub(crate) fn normalize_stored(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+/// The archive `encode` writes, and `decode` reads, by default.
+pub const ENCODE_OUTPUT: &str = "out.out";
+
+/// How an archive is encoded. Decoding needs the mode the archive was encoded with.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    Plain,
+    Base64,
+    Auto,
+    CompressedTxt,
+    CompressedBinary,
+}
+
+impl FromStr for Mode {
+    type Err = io::Error;
+
+    /// Parses a mode by the name of its flag, without the dashes.
+    fn from_str(s: &str) -> io::Result<Mode> {
+        match s {
+            "plain" => Ok(Mode::Plain),
+            "base64" => Ok(Mode::Base64),
+            "auto" => Ok(Mode::Auto),
+            "binary" => Ok(Mode::CompressedBinary),
+            "text" => Ok(Mode::CompressedTxt),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "not support {}, available modes are plain|base64|auto|binary|text",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Mode::Plain => "plain",
+            Mode::Base64 => "base64",
+            Mode::Auto => "auto",
+            Mode::CompressedBinary => "binary",
+            Mode::CompressedTxt => "text",
+        })
+    }
+}
+
+/// How a single entry's body is stored.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Plain,
+    Base64,
+}
+
+impl Encoding {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Encoding::Plain => "plain",
+            Encoding::Base64 => "base64",
+        }
+    }
+
+    pub(crate) fn parse(s: &str) -> Option<Encoding> {
+        match s {
+            "plain" => Some(Encoding::Plain),
+            "base64" => Some(Encoding::Base64),
+            _ => None,
+        }
+    }
+
+    /// Entries without an `enc` field use the archive's mode.
+    pub(crate) fn for_mode(mode: &Mode) -> Encoding {
+        match mode {
+            Mode::Plain => Encoding::Plain,
+            _ => Encoding::Base64,
+        }
+    }
+}
+
+/// What an entry's recorded hash covers.
+#[derive(Clone, Copy, PartialEq)]
+pub enum HashScope {
+    Content,
+    // The stored path too, so a moved file no longer matches its old entry.
+    PathContent,
+}
+
+impl HashScope {
+    pub fn parse(s: &str) -> io::Result<HashScope> {
+        match s {
+            "content" => Ok(HashScope::Content),
+            "path-content" => Ok(HashScope::PathContent),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "not support {}, available hash scopes are content|path-content",
+                    s
+                ),
+            )),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HashScope::Content => "content",
+            HashScope::PathContent => "path-content",
+        }
+    }
+
+    /// A hasher that has already taken in what `self` covers besides the content.
+    pub(crate) fn hasher(self, stored: &str) -> DefaultHasher {
+        let mut hasher = DefaultHasher::new();
+        if self == HashScope::PathContent {
+            hasher.write(stored.as_bytes());
+            hasher.write(&[0]);
+        }
+        hasher
+    }
+}
+
+/// The alphabet an archive's base64 is written in: the standard one, with `+` and `/`, or
+/// the URL and filename safe one, with `-` and `_`, under `--base64-url`. Both pad with `=`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Alphabet {
+    Standard,
+    UrlSafe,
+}
+
+impl Alphabet {
+    pub fn name(self) -> &'static str {
+        match self {
+            Alphabet::Standard => "standard",
+            Alphabet::UrlSafe => "url",
+        }
+    }
+
+    pub(crate) fn parse(s: &str) -> Option<Alphabet> {
+        match s {
+            "standard" => Some(Alphabet::Standard),
+            "url" => Some(Alphabet::UrlSafe),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn config(self) -> base64::Config {
+        match self {
+            Alphabet::Standard => base64::STANDARD,
+            Alphabet::UrlSafe => base64::URL_SAFE,
+        }
+    }
+
+    /// The alphabet `encoded` must be in, going by the first character only one of them has.
+    /// `None` when it has none, and reads the same in both.
+    pub(crate) fn of(encoded: &[u8]) -> Option<Alphabet> {
+        encoded.iter().find_map(|b| match b {
+            b'+' | b'/' => Some(Alphabet::Standard),
+            b'-' | b'_' => Some(Alphabet::UrlSafe),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_paths_lose_dot_components() {
+        let stored = |path: &str| {
+            normalize_stored(Path::new(path))
+                .to_string_lossy()
+                .into_owned()
+        };
+        assert_eq!(stored("./src/main.rs"), "src/main.rs");
+        assert_eq!(stored("a/./b//c"), "a/b/c");
+        assert_eq!(stored("./a/./b/."), "a/b");
+    }
+}
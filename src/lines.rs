@@ -0,0 +1,95 @@
+use std::io::{self, Read};
+
+/// Splits a reader into `\n`-terminated lines while reading it in fixed-size chunks,
+/// carrying a partial line over from one chunk to the next so a line (and therefore a
+/// separator) is found no matter where the chunk boundaries fall.
+pub struct LineReader<R> {
+    inner: R,
+    chunk: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl<R: Read> LineReader<R> {
+    pub fn with_chunk_size(inner: R, chunk_size: usize) -> LineReader<R> {
+        LineReader {
+            inner,
+            chunk: vec![0; chunk_size.max(1)],
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// Reads the next line, including its `\n` unless it is the last line of the input,
+    /// into `line`. Returns `false` once the input is exhausted.
+    pub fn next_line(&mut self, line: &mut Vec<u8>) -> io::Result<bool> {
+        line.clear();
+        loop {
+            let pending = &self.chunk[self.start..self.end];
+            if let Some(i) = pending.iter().position(|b| *b == b'\n') {
+                line.extend_from_slice(&pending[..=i]);
+                self.start += i + 1;
+                return Ok(true);
+            }
+            line.extend_from_slice(pending);
+            self.start = self.end;
+
+            let n = match self.inner.read(&mut self.chunk) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if n == 0 {
+                return Ok(!line.is_empty());
+            }
+            self.start = 0;
+            self.end = n;
+        }
+    }
+
+    /// Reads up to `max` bytes, whatever they are, into `bytes`. Returns `false` once the
+    /// input is exhausted.
+    pub fn next_bytes(&mut self, max: u64, bytes: &mut Vec<u8>) -> io::Result<bool> {
+        bytes.clear();
+        while self.start == self.end {
+            let n = match self.inner.read(&mut self.chunk) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if n == 0 {
+                return Ok(false);
+            }
+            self.start = 0;
+            self.end = n;
+        }
+        let n = ((self.end - self.start) as u64).min(max) as usize;
+        bytes.extend_from_slice(&self.chunk[self.start..self.start + n]);
+        self.start += n;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::separator;
+
+    #[test]
+    fn separators_split_across_tiny_chunks_are_found() {
+        let archive: &[u8] = b"MITO/2 mode=plain\n====a.txt|1|size=6====\nhello\n\
+            ====dir/b.txt|2|size=0====\n\n====c|3|size=4====\nlast";
+        let expected: Vec<&[u8]> = archive.split_inclusive(|&b| b == b'\n').collect();
+        for chunk_size in 1..=8 {
+            let mut lines = LineReader::with_chunk_size(archive, chunk_size);
+            let mut line = Vec::new();
+            let mut read = Vec::new();
+            while lines.next_line(&mut line).unwrap() {
+                read.push(line.clone());
+            }
+            assert_eq!(read, expected, "chunks of {} bytes", chunk_size);
+            let separators = read.iter().filter(|l| separator::is_separator(l)).count();
+            assert_eq!(separators, 3, "chunks of {} bytes", chunk_size);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::json;
+
+/// `-v` logs every archived or extracted file.
+pub const FILES: usize = 1;
+/// `-vv` also logs every ignore decision.
+pub const DECISIONS: usize = 2;
+
+static VERBOSITY: AtomicUsize = AtomicUsize::new(0);
+
+// `--log-file`, which takes every message whatever the verbosity.
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+static LOG_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+static LOGGING: AtomicBool = AtomicBool::new(false);
+
+pub fn set_verbosity(level: usize) {
+    VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+pub fn enabled(level: usize) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= level
+}
+
+/// Also writes every warning and verbose message to `path`, one JSON object per line.
+pub fn set_log_file(path: &Path) -> io::Result<()> {
+    let file = File::create(path)?;
+    *LOG_FILE.lock().unwrap() = Some(file);
+    *LOG_PATH.lock().unwrap() = Some(path.to_path_buf());
+    LOGGING.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Where `set_log_file` logs to, which `encode` doesn't archive like its output.
+pub fn file_path() -> Option<PathBuf> {
+    LOG_PATH.lock().unwrap().clone()
+}
+
+/// Appends `{"time":...,"level":...,"message":...}` to the log file, if there is one. A log
+/// file that can't be written to doesn't stop the run.
+fn record(level: &str, msg: &str) {
+    if let Some(file) = LOG_FILE.lock().unwrap().as_mut() {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let _ = writeln!(
+            file,
+            "{{\"time\":{},\"level\":{},\"message\":{}}}",
+            time,
+            json::string(level),
+            json::string(msg)
+        );
+    }
+}
+
+/// Prints `msg` to stderr when running at `level` or above, and logs it to `--log-file`.
+pub fn verbose(level: usize, msg: impl FnOnce() -> String) {
+    let console = enabled(level);
+    if !(console || LOGGING.load(Ordering::Relaxed)) {
+        return;
+    }
+    let msg = msg();
+    record(
+        if level >= DECISIONS {
+            "decision"
+        } else {
+            "file"
+        },
+        &msg,
+    );
+    if console {
+        eprintln!("{}", msg);
+    }
+}
+
+/// Prints `warning: msg` to stderr, and logs it to `--log-file`.
+pub fn warn(msg: impl AsRef<str>) {
+    let msg = msg.as_ref();
+    record("warning", msg);
+    eprintln!("warning: {}", msg);
+}
@@ -1,210 +1,672 @@
-use std::collections::hash_map::DefaultHasher;
 use std::env;
-use std::fs::{self, create_dir_all, DirEntry, File};
-use std::hash::Hasher;
+use std::fs;
 use std::io::{self, Read, Write};
-use std::path::{Component, Path};
-
-use base64::decode;
-use flate2::read::ZlibDecoder;
-use flate2::write::ZlibEncoder;
-use flate2::Compression;
-
-fn visit_dirs(dir: &Path, cb: &mut dyn FnMut(&DirEntry)) -> io::Result<()> {
-    if dir.is_dir() {
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() {
-                visit_dirs(&path, cb)?;
-            } else {
-                cb(&entry);
-            }
-        }
-    }
-    Ok(())
-}
+use std::path::{Path, PathBuf};
+use std::process;
 
-fn file_sep(path: &Path, hash: &str) -> String {
-    format!("===={}|{}====\n", path.to_string_lossy(), hash)
-}
+use mito::cli::{self, counted, format_size, parse_size, parse_time, Args, COMMANDS};
+use mito::decode::{
+    decode_dir, decode_to_zip, header_file, index_file, list_matching, read_recorded,
+    recorded_mode, recorded_to_json, verify, verify_with_progress, Conflict, DecodeOptions,
+    OnDuplicate, Restore, VerifyOptions,
+};
+use mito::encode::{
+    analyze_dir, encode_dir, encode_listed, encode_paths, index_dir, parse_files_from,
+    CaseCollision, EncodeOptions, OutputFormat, Symlinks, STDOUT,
+};
+use mito::native::ReservedNames;
+use mito::vfs::OsFs;
+use mito::volumes::Volumes;
+use mito::writer::{Codec, COMPRESSION_LEVEL, DEFAULT_AUTO_SAMPLE};
+use mito::{
+    chunks, completions, concat, config, diff, header, interrupt, log, pool, selftest, volumes,
+    Alphabet, HashScope, Mode, ENCODE_OUTPUT,
+};
 
-const ENCODE_OUTPUT: &str = "out.out";
+const TAR_OUTPUT: &str = "out.tar";
+const TAR_GZ_OUTPUT: &str = "out.tar.gz";
 const DECODE_OUTPUT: &str = "output";
-const IGNORED_FILE_DIR: [&str; 6] = [
-    ".git",
-    "Cargo.lock",
-    "target",
-    "node_modules",
-    ENCODE_OUTPUT,
-    DECODE_OUTPUT,
-];
-
-enum Mode {
-    Plain,
-    Base64,
-    CompressedTxt,
-    CompressedBinary,
+
+const MODE_FLAGS: &[&str] = &["--plain", "--base64", "--auto", "--binary", "--text"];
+
+const DEFAULT_TOP: usize = 10;
+
+fn main() {
+    interrupt::install();
+    match run() {
+        Ok(()) => {}
+        // Cleaned up after already, so only the exit status is left to report it.
+        Err(_) if interrupt::requested() => {
+            eprintln!("interrupted");
+            process::exit(interrupt::EXIT_CODE);
+        }
+        Err(e) => {
+            eprintln!("mito: {}", e);
+            let usage = cli::is_usage_error(&e);
+            process::exit(if usage { cli::USAGE_EXIT_CODE } else { 1 });
+        }
+    }
 }
 
-fn main() -> io::Result<()> {
+fn run() -> io::Result<()> {
     let mut args = env::args();
-
     args.next();
-    let command = args.next();
-    let command = command.as_deref();
-
-    let mode = args.next();
-    let mode = mode.as_deref().unwrap_or("--plain");
-
-    let mode = match mode {
-        "--plain" => Mode::Plain,
-        "--base64" => Mode::Base64,
-        "--binary" => Mode::CompressedBinary,
-        "--text" => Mode::CompressedTxt,
-        _ => {
-            panic!(
-                "not support {}, available option are --[plain|base64|binary|text]",
-                mode
-            );
+    let mut args = Args::parse(args)?;
+    // Config files and `MITO_*` variables only fill in flags the command line leaves out.
+    if let Some(command) = args.command.clone() {
+        if COMMANDS.iter().any(|c| c.name == command) {
+            args = args.with_defaults(config::defaults(&command)?);
         }
-    };
+    }
+    let command = args.command.as_deref();
+    log::set_verbosity(args.count("-v") + 2 * args.count("-vv"));
+    if let Some(path) = args.value("--log-file") {
+        log::set_log_file(path.as_ref())?;
+    }
+
+    let mode: Mode = args.last_of(MODE_FLAGS).unwrap_or("--plain")[2..].parse()?;
 
     if let Some("encode") = command {
-        encode_dir(".".as_ref(), mode)?;
+        if args.has("--index") && matches!(mode, Mode::CompressedBinary | Mode::CompressedTxt) {
+            log::warn("--index has no effect on compressed archives");
+        }
+        if args.has("--min-ratio") && !matches!(mode, Mode::CompressedBinary | Mode::CompressedTxt)
+        {
+            log::warn("--min-ratio has no effect on archives that aren't compressed");
+        }
+        let options = encode_options(&args)?;
+        let chunk_manifest = args.value("--chunk-manifest");
+        if chunk_manifest.is_some() && options.output == Path::new(STDOUT) {
+            return Err(cli::usage_error(
+                "--chunk-manifest cuts the archive it wrote into chunks, so it needs an --output file",
+            ));
+        }
+        if let Some(list) = args.value("--files-from") {
+            if !args.positional.is_empty() {
+                return Err(cli::usage_error(
+                    "--files-from takes the paths to encode from its list, not the command line",
+                ));
+            }
+            let list = if list == "-" {
+                let mut list = Vec::new();
+                io::stdin().read_to_end(&mut list)?;
+                list
+            } else {
+                fs::read(list)?
+            };
+            let listed = parse_files_from(&list, args.has("--null"))?;
+            encode_listed(&OsFs, &listed, mode, &options)?;
+        } else if args.positional.is_empty() {
+            encode_dir(".".as_ref(), mode, &options)?;
+        } else {
+            let sources: Vec<PathBuf> = args.positional.iter().map(PathBuf::from).collect();
+            encode_paths(&OsFs, &sources, mode, &options)?;
+        }
+        if let Some(manifest) = chunk_manifest {
+            let dir = match args.value("--chunks") {
+                Some(dir) => PathBuf::from(dir),
+                None => chunks::default_dir(manifest.as_ref()),
+            };
+            let chunk_size = match args.value("--chunk-size") {
+                Some(size) => match parse_size(size)? {
+                    0 => return Err(cli::usage_error("--chunk-size must be at least one byte")),
+                    size => size,
+                },
+                None => chunks::DEFAULT_CHUNK_SIZE,
+            };
+            let (cut, written) =
+                chunks::write_chunks(&options.output, manifest.as_ref(), &dir, chunk_size)?;
+            eprintln!(
+                "cut {} into {} chunks, {} of them new in {}",
+                options.output.display(),
+                cut.chunks.len(),
+                written,
+                dir.display()
+            );
+        }
+    } else if let Some("completions") = command {
+        let shell = args.positional.first().map_or("", String::as_str);
+        print!("{}", completions::generate(shell)?);
+    } else if let Some("analyze") = command {
+        let dir = args.positional.first().map_or(".", String::as_str);
+        let top = match args.value("--top") {
+            Some(top) => top
+                .parse()
+                .map_err(|_| cli::usage_error(format!("invalid --top `{}`", top)))?,
+            None => DEFAULT_TOP,
+        };
+        analyze_dir(dir.as_ref(), &encode_options(&args)?, top)?;
+    } else if let Some("list") = command {
+        let archive = args
+            .positional
+            .first()
+            .map_or(ENCODE_OUTPUT, String::as_str);
+        let (file, mode) = open_archive(&args, archive, mode)?;
+        let paths = list_matching(file, mode, hash_prefix(&args)?)?;
+        // `--null` separates paths like `find -print0`, for `xargs -0`.
+        let delimiter = if args.has("--null") { b'\0' } else { b'\n' };
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        for path in paths {
+            out.write_all(path.as_bytes())?;
+            out.write_all(&[delimiter])?;
+        }
+    } else if let Some("diff") = command {
+        let (old, new) = match args.positional.as_slice() {
+            [old, new] => (old, new),
+            _ => {
+                return Err(cli::usage_error(
+                    "diff compares two archives: mito diff OLD NEW",
+                ))
+            }
+        };
+        // Without a mode flag each archive's mode comes from its header.
+        let forced = args.last_of(MODE_FLAGS).map(|_| mode);
+        let compat = compat(&args)?;
+        let old = index_file(old.as_ref(), forced, compat)?;
+        let new = index_file(new.as_ref(), forced, compat)?;
+        if old.hash_scope != new.hash_scope {
+            log::warn(format!(
+                "the archives hash {} and {}, so every entry in both will show as modified",
+                old.hash_scope.name(),
+                new.hash_scope.name()
+            ));
+        }
+        let mut changes = diff::diff(&old, &new);
+        if args.has("--detect-renames") {
+            if old.hash_scope == HashScope::PathContent || new.hash_scope == HashScope::PathContent
+            {
+                log::warn(
+                    "entries hashed path-content change hash when they move, so no renames can be detected",
+                );
+            }
+            let renames = diff::detect_renames(&old, &new, &mut changes);
+            if args.has("--json") {
+                println!("{}", diff::to_json_with_renames(&changes, &renames));
+            } else {
+                // Renames are listed in path order among the rest, under their old path.
+                let mut lines: Vec<_> = changes
+                    .iter()
+                    .map(|(change, path)| (*path, format!("{} {}", change.code(), path)))
+                    .chain(
+                        renames
+                            .iter()
+                            .map(|(from, to)| (*from, format!("R {} {}", from, to))),
+                    )
+                    .collect();
+                lines.sort();
+                for (_, line) in lines {
+                    println!("{}", line);
+                }
+            }
+        } else if args.has("--json") {
+            println!("{}", diff::to_json(&changes));
+        } else {
+            for (change, path) in changes {
+                println!("{} {}", change.code(), path);
+            }
+        }
     } else if let Some("decode") = command {
-        decode_dir(".".as_ref(), mode)?;
+        let reserved_names = match args.value("--reserved-names") {
+            Some(strategy) => ReservedNames::parse(strategy)?,
+            None => ReservedNames::platform_default(),
+        };
+        let options = DecodeOptions {
+            dest: args.value("--dest").unwrap_or(DECODE_OUTPUT).into(),
+            strip_components: match args.value("--strip-components") {
+                Some(n) => n
+                    .parse()
+                    .map_err(|_| cli::usage_error(format!("invalid --strip-components `{}`", n)))?,
+                None => 0,
+            },
+            reserved_names,
+            flatten: args.has("--flatten"),
+            only: args
+                .values("--only")
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            hash_prefix: hash_prefix(&args)?.map(String::from),
+            restore: restore(&args)?,
+            space_check: !args.has("--no-space-check"),
+            allow_external_symlinks: args.has("--allow-external-symlinks"),
+            touch_only: args.has("--touch-only"),
+            dedup_links: args.has("--dedup-links"),
+            max_entries: max_entries(&args)?,
+            volumes: args.has("--volumes"),
+            compat: compat(&args)?,
+            conflict: match args.value("--conflict") {
+                Some(strategy) => Conflict::parse(strategy)?,
+                None => Conflict::Error,
+            },
+            on_duplicate: on_duplicate(&args)?,
+            best_effort: args.has("--best-effort"),
+            strict: args.has("--strict"),
+            expect_root: args.value("--expect-root").map(PathBuf::from),
+        };
+        let archive = args
+            .positional
+            .first()
+            .map_or(ENCODE_OUTPUT, String::as_str);
+        match args.value("--to-zip") {
+            Some(zip) => decode_to_zip(archive.as_ref(), mode, &options, zip.as_ref())?,
+            None => decode_dir(archive.as_ref(), mode, &options)?,
+        }
+    } else if let (Some("verify"), true) = (command, args.has("--list-only")) {
+        let checking = [
+            "--against",
+            "--fast",
+            "--keep-going",
+            "--stop-on-first",
+            "--progress",
+        ];
+        if let Some(flag) = checking.iter().find(|flag| args.has(flag)) {
+            return Err(cli::usage_error(format!(
+                "--list-only checks nothing, so it doesn't take {}",
+                flag
+            )));
+        }
+        let archive = args
+            .positional
+            .first()
+            .map_or(ENCODE_OUTPUT, String::as_str);
+        let (file, mode) = open_archive(&args, archive, mode)?;
+        let recorded = read_recorded(file, mode, compat(&args)?)?;
+        if args.has("--json") {
+            println!("{}", recorded_to_json(&recorded));
+        } else {
+            for entry in &recorded {
+                let size = entry.size.map_or("-".to_string(), |size| size.to_string());
+                println!("{}\t{}\t{}", entry.hash, size, entry.path);
+            }
+            eprintln!(
+                "listed {} as recorded; --list-only hashed nothing, run verify without it to check them",
+                counted(recorded.len(), "entry", "entries")
+            );
+        }
+    } else if let (Some("verify"), Some(dir)) = (command, args.value("--against")) {
+        let archive = args
+            .positional
+            .first()
+            .map_or(ENCODE_OUTPUT, String::as_str);
+        let forced = args.last_of(MODE_FLAGS).map(|_| mode);
+        let archived = index_file(archive.as_ref(), forced, compat(&args)?)?;
+        // The archive is left out of the tree like encode leaves out its output.
+        let options = EncodeOptions {
+            output: archive.into(),
+            ..encode_options(&args)?
+        };
+        let live = index_dir(&OsFs, dir.as_ref(), archived.hash_scope, &options)?;
+        let changes = diff::diff(&archived, &live);
+        if args.has("--json") {
+            println!("{}", diff::to_json(&changes));
+        } else {
+            for (change, path) in &changes {
+                println!("{} {}", change.code(), path);
+            }
+        }
+        if !changes.is_empty() {
+            process::exit(1);
+        }
+    } else if let Some("verify") = command {
+        let archive = args
+            .positional
+            .first()
+            .map_or(ENCODE_OUTPUT, String::as_str);
+        let options = VerifyOptions {
+            compat: compat(&args)?,
+            on_duplicate: on_duplicate(&args)?,
+            fast: args.has("--fast"),
+            stop_on_first: args.has("--stop-on-first"),
+            keep_going: args.has("--keep-going"),
+        };
+        let (file, mode) = open_archive(&args, archive, mode)?;
+        let verified = if args.has("--progress") {
+            let total = file.size()?;
+            let mut shown = None;
+            let verified = verify_with_progress(file, mode, &options, &mut |read| {
+                // Redrawn only when the percentage changes, on one line of stderr.
+                let percent = (read * 100).checked_div(total).unwrap_or(100);
+                if shown != Some(percent) {
+                    shown = Some(percent);
+                    eprint!(
+                        "\rverifying {} of {} ({}%)",
+                        format_size(read),
+                        format_size(total),
+                        percent
+                    );
+                }
+            });
+            eprintln!();
+            verified?
+        } else {
+            verify(file, mode, &options)?
+        };
+        if args.has("--json") {
+            println!("{}", verified.to_json());
+        } else if args.has("--fast") {
+            // Entries without a recorded bodyhash can only be checked the slow way.
+            println!(
+                "verified {}, {} of them by their stored bytes only",
+                counted(verified.entries, "entry", "entries"),
+                verified.stored_only
+            );
+        } else {
+            println!("verified {}", counted(verified.entries, "entry", "entries"));
+        }
+        if verified.duplicates > 0 && !args.has("--json") {
+            println!(
+                "{} the stored path of an earlier one",
+                counted(verified.duplicates, "entry repeats", "entries repeat")
+            );
+        }
+        if !verified.failures.is_empty() {
+            if !args.has("--json") {
+                for failure in &verified.failures {
+                    println!("failed: {}", failure.reason);
+                }
+                println!(
+                    "{} of {} failed verification",
+                    verified.failures.len(),
+                    counted(verified.entries, "entry", "entries")
+                );
+            }
+            process::exit(1);
+        }
+    } else if let Some("info") = command {
+        let archive = args
+            .positional
+            .first()
+            .map_or(ENCODE_OUTPUT, String::as_str);
+        // Without a mode flag, the header says which mode to read it in.
+        let mode = args.last_of(MODE_FLAGS).map(|_| mode);
+        match header_file(archive.as_ref(), mode)? {
+            Some(header) => {
+                println!("mode     {}", header.mode);
+                println!("version  {}", header.version);
+                println!("hash     {}", header.hash_scope.name());
+                println!("base64   {}", header.alphabet.name());
+                match &header.root {
+                    Some(root) => println!("root     {}", root),
+                    None => println!("root     not recorded"),
+                }
+            }
+            None => println!("no header, a format version 0 archive"),
+        }
+    } else if let Some("assemble") = command {
+        let manifest = args.value("--chunk-manifest").ok_or_else(|| {
+            cli::usage_error("assemble needs the --chunk-manifest of the archive to put together")
+        })?;
+        let dir = match args.value("--chunks") {
+            Some(dir) => PathBuf::from(dir),
+            None => chunks::default_dir(manifest.as_ref()),
+        };
+        let output = args.value("--output").unwrap_or(ENCODE_OUTPUT);
+        let assembled = chunks::assemble(manifest.as_ref(), &dir, output.as_ref())?;
+        println!(
+            "assembled {} from {} chunks, {}",
+            output,
+            assembled.chunks.len(),
+            format_size(assembled.size)
+        );
+    } else if let Some("cat-archive") = command {
+        let inputs: Vec<PathBuf> = args.positional.iter().map(PathBuf::from).collect();
+        let output = args.value("--output").unwrap_or(ENCODE_OUTPUT);
+        concat::cat_archives(&inputs, output.as_ref())?;
+    } else if let Some("selftest") = command {
+        let mut failed = false;
+        for (mode, result) in selftest::run()? {
+            match result {
+                Ok(()) => println!("{}: pass", mode),
+                Err(e) => {
+                    println!("{}: FAIL, {}", mode, e);
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            process::exit(1);
+        }
     } else {
-        eprintln!("command is `decode` or `encode`")
+        // A typo'd command must not look like it succeeded.
+        if let Some(command) = command {
+            eprintln!("unknown command `{}`", command);
+        }
+        eprint!("{}", cli::usage());
+        process::exit(cli::USAGE_EXIT_CODE);
     }
 
     Ok(())
 }
 
-fn create_file_sep(path: &Path, buffer: &[u8]) -> String {
-    let mut hasher = DefaultHasher::new();
-    hasher.write(buffer);
-    file_sep(path, &hasher.finish().to_string())
+/// The format version `--compat` reads headerless archives as.
+fn compat(args: &Args) -> io::Result<Option<u32>> {
+    match args.value("--compat") {
+        Some(version) => match version.parse() {
+            Ok(version) if version <= header::VERSION => Ok(Some(version)),
+            _ => Err(cli::usage_error(format!(
+                "invalid --compat `{}`, expected a format version up to {}",
+                version,
+                header::VERSION
+            ))),
+        },
+        None => Ok(None),
+    }
 }
 
-fn encode_dir(path: &Path, mode: Mode) -> io::Result<()> {
-    let mut out_file = File::create(ENCODE_OUTPUT)?;
+/// Opens `archive` for list and verify, with the mode its header records if that isn't `mode`.
+fn open_archive(args: &Args, archive: &str, mode: Mode) -> io::Result<(Volumes, Mode)> {
+    let mut file = volumes::open(archive.as_ref(), args.has("--volumes"))?;
+    let mode = recorded_mode(&mut file, mode, archive.as_ref())?;
+    Ok((file, mode))
+}
 
-    let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+/// `--max-entries`, a count of at least one.
+fn max_entries(args: &Args) -> io::Result<Option<usize>> {
+    match args.value("--max-entries") {
+        Some(n) => match n.parse() {
+            Ok(n) if n > 0 => Ok(Some(n)),
+            _ => Err(cli::usage_error(format!(
+                "invalid --max-entries `{}`, expected a positive count",
+                n
+            ))),
+        },
+        None => Ok(None),
+    }
+}
 
-    visit_dirs(path, &mut |entry| {
-        let path = entry.path();
-        let ignored = path.components().any(|component| {
-            if let Component::Normal(normal) = component {
-                // return normal.to_string_lossy() == ".git"
-                return IGNORED_FILE_DIR
-                    .iter()
-                    .any(|p| *p == normal.to_string_lossy());
-            }
-            false
-        });
-        if !ignored {
-            let mut file = File::open(entry.path()).unwrap();
-            let mut buffer = Vec::new();
-            file.read_to_end(&mut buffer).unwrap();
-
-            let file_sep = create_file_sep(&entry.path(), &buffer);
-
-            match mode {
-                Mode::Plain => {
-                    out_file.write_all(file_sep.as_bytes()).unwrap();
-                    out_file.write_all(&buffer).unwrap();
-                    out_file.write_all(b"\n").unwrap();
-                }
-                Mode::Base64 => {
-                    out_file.write_all(file_sep.as_bytes()).unwrap();
-                    let base64_str = base64::encode(buffer);
-                    out_file.write_all(base64_str.as_bytes()).unwrap();
-                    out_file.write_all(b"\n").unwrap();
-                }
-                Mode::CompressedBinary | Mode::CompressedTxt => {
-                    e.write_all(file_sep.as_bytes()).unwrap();
-                    let base64_str = base64::encode(&buffer);
-                    e.write_all(base64_str.as_bytes()).unwrap();
-                    e.write_all(b"\n").unwrap();
-                }
-            }
-        }
-    })?;
+fn on_duplicate(args: &Args) -> io::Result<OnDuplicate> {
+    match args.value("--on-duplicate") {
+        Some(strategy) => OnDuplicate::parse(strategy),
+        None => Ok(OnDuplicate::LastWins),
+    }
+}
 
-    match mode {
-        Mode::CompressedBinary => {
-            let compressed = e.finish().unwrap();
-            out_file.write_all(&compressed).unwrap();
-        }
-        Mode::CompressedTxt => {
-            let compressed = e.finish().unwrap();
-            out_file
-                .write_all(base64::encode(&compressed).as_bytes())
-                .unwrap();
-        }
-        _ => {}
+/// What `decode` restores: `--restore`, with `--preserve-ownership` adding the owner to it.
+fn restore(args: &Args) -> io::Result<Option<Restore>> {
+    let restore = args.value("--restore").map(Restore::parse).transpose()?;
+    if !args.has("--preserve-ownership") {
+        return Ok(restore);
     }
-    Ok(())
+    let mut restore = restore.unwrap_or_else(Restore::all);
+    restore.owner = true;
+    Ok(Some(restore))
 }
 
-fn decode_dir(path: &Path, mode: Mode) -> io::Result<()> {
-    let mut file = File::open(path.to_owned().join(ENCODE_OUTPUT))?;
-
-    let buffer = match mode {
-        Mode::CompressedBinary => {
-            let mut z = ZlibDecoder::new(file);
-            let mut s = String::new();
-            z.read_to_string(&mut s)?;
-            s
-        }
-        Mode::CompressedTxt => {
-            let mut buffer = String::new();
-            file.read_to_string(&mut buffer)?;
-            let buffer = decode(buffer).unwrap();
-            let mut z = ZlibDecoder::new(&buffer[..]);
-            let mut s = String::new();
-            z.read_to_string(&mut s)?;
-            s
-        }
-        _ => {
-            let mut buffer = String::new();
-            file.read_to_string(&mut buffer)?;
-            buffer
+/// `--hash-prefix`, checked to be 1 to 16 hex digits, what a 64-bit hash can start with.
+fn hash_prefix(args: &Args) -> io::Result<Option<&str>> {
+    match args.value("--hash-prefix") {
+        Some(prefix)
+            if prefix.is_empty()
+                || prefix.len() > 16
+                || !prefix.chars().all(|c| c.is_ascii_hexdigit()) =>
+        {
+            Err(cli::usage_error(format!(
+                "invalid --hash-prefix `{}`, expected 1 to 16 hex digits",
+                prefix
+            )))
         }
+        prefix => Ok(prefix),
+    }
+}
+
+/// A `--wrap` width: whole groups of four base64 characters, so that every line of a
+/// wrapped body decodes on its own.
+fn parse_wrap(width: &str) -> io::Result<usize> {
+    match width.parse() {
+        Ok(width) if width > 0 && width % 4 == 0 => Ok(width),
+        _ => Err(cli::usage_error(format!(
+            "invalid --wrap `{}`, expected a multiple of 4 such as 76",
+            width
+        ))),
+    }
+}
+
+fn parse_min_ratio(percent: &str) -> io::Result<f64> {
+    match percent.trim_end_matches('%').parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+        _ => Err(cli::usage_error(format!(
+            "invalid --min-ratio `{}`, expected a percentage from 0 to 100",
+            percent
+        ))),
+    }
+}
+
+fn encode_options(args: &Args) -> io::Result<EncodeOptions> {
+    // `--clamp-mtime` wins over `SOURCE_DATE_EPOCH`; either only affects recorded mtimes.
+    let clamp = args
+        .value("--clamp-mtime")
+        .map(String::from)
+        .or_else(|| env::var("SOURCE_DATE_EPOCH").ok());
+    let clamp_mtime = match clamp {
+        Some(clamp) => Some(clamp.trim().parse::<u64>().map_err(|_| {
+            cli::usage_error(format!(
+                "invalid mtime clamp `{}`, expected a unix timestamp",
+                clamp
+            ))
+        })?),
+        None => None,
+    };
+    if args.value("--clamp-mtime").is_some()
+        && !args.has("--preserve-mtime")
+        && !args.has("--preserve-btime")
+    {
+        log::warn("--clamp-mtime has no effect without --preserve-mtime or --preserve-btime");
+    }
+    let output_format = match args.value("--output-format") {
+        Some(format) => OutputFormat::parse(format)?,
+        None => OutputFormat::Mito,
+    };
+    let default_output = match output_format {
+        OutputFormat::Mito => ENCODE_OUTPUT,
+        OutputFormat::Tar => TAR_OUTPUT,
+        OutputFormat::TarGz => TAR_GZ_OUTPUT,
     };
-    let mut buffer: &str = &buffer;
-
-    let mut output_file = None;
-    loop {
-        if buffer.is_empty() {
-            break;
-        }
-        let split = buffer
-            .find('\n')
-            .map(|i| i + 1)
-            .unwrap_or_else(|| buffer.len());
-        let (line, rest) = buffer.split_at(split);
-        buffer = rest;
-
-        if line.starts_with("====") && line.ends_with("====\n") {
-            let path_hash = &line[4..line.len() - 5];
-            let path = path_hash.split('|').next().unwrap();
-            let target = Path::new(&format!("./{}", DECODE_OUTPUT)).join(path);
-            create_dir_all(target.parent().unwrap())?;
-            output_file = Some(File::create(target)?);
-        } else if let Some(output) = output_file.as_mut() {
-            match mode {
-                Mode::Plain => {
-                    output.write_all(line.as_bytes())?;
+    Ok(EncodeOptions {
+        output: args.value("--output").unwrap_or(default_output).into(),
+        temp_dir: args.value("--temp-dir").map(PathBuf::from),
+        json: args.has("--json"),
+        modified_since: args.value("--modified-since").map(parse_time).transpose()?,
+        order_from: args.value("--order-from").map(PathBuf::from),
+        relative_to: args.value("--relative-to").map(PathBuf::from),
+        case_collision: args
+            .value("--case-collision")
+            .map(CaseCollision::parse)
+            .transpose()?,
+        preserve_mtime: args.has("--preserve-mtime"),
+        preserve_btime: args.has("--preserve-btime"),
+        clamp_mtime,
+        threads: match args.value("--threads") {
+            Some(threads) => match threads.parse() {
+                Ok(threads) if threads > 0 => threads,
+                _ => {
+                    return Err(cli::usage_error(format!(
+                        "invalid --threads `{}`, expected a positive number",
+                        threads
+                    )))
                 }
+            },
+            None => pool::default_threads(),
+        },
+        output_format,
+        auto_sample: match args.value("--auto-sample") {
+            Some(sample) => parse_size(sample)? as usize,
+            None => DEFAULT_AUTO_SAMPLE,
+        },
+        exclude_larger_than: args
+            .value("--exclude-larger-than")
+            .map(parse_size)
+            .transpose()?,
+        exclude_smaller_than: args
+            .value("--exclude-smaller-than")
+            .map(parse_size)
+            .transpose()?,
+        include_archives: args.has("--include-archives"),
+        strict: args.has("--strict"),
+        max_memory: args.value("--max-memory").map(parse_size).transpose()?,
+        record_root: args.has("--record-root"),
+        wrap: args.value("--wrap").map(parse_wrap).transpose()?,
+        min_ratio: args.value("--min-ratio").map(parse_min_ratio).transpose()?,
+        ignore_errors: args.has("--ignore-errors"),
+        max_entries: max_entries(args)?,
+        level: match args.value("--level") {
+            Some(level) => match level.parse() {
+                Ok(level) if level <= 9 => level,
                 _ => {
-                    let decoded = base64::decode(&line[..line.len() - 1]).unwrap();
-                    output.write_all(&decoded)?;
+                    return Err(cli::usage_error(format!(
+                        "invalid --level `{}`, expected 0 to 9",
+                        level
+                    )))
                 }
-            }
-        }
-    }
-
-    Ok(())
+            },
+            None => COMPRESSION_LEVEL,
+        },
+        index: args.has("--index"),
+        codec_for: args
+            .values("--codec-for")
+            .into_iter()
+            .map(|rule| match rule.rsplit_once('=') {
+                Some((pattern, codec)) => Ok((pattern.to_string(), Codec::parse(codec)?)),
+                None => Err(cli::usage_error(format!(
+                    "invalid --codec-for `{}`, expected PATTERN=CODEC",
+                    rule
+                ))),
+            })
+            .collect::<io::Result<_>>()?,
+        no_compress_ext: args
+            .value("--no-compress-ext")
+            .unwrap_or_default()
+            .split(',')
+            .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+            .filter(|ext| !ext.is_empty())
+            .collect(),
+        preserve_ownership: args.has("--preserve-ownership"),
+        preserve_perms: args.has("--preserve-perms"),
+        stored_hash: args.has("--stored-hash"),
+        sparse: args.has("--sparse"),
+        alphabet: if args.has("--base64-url") {
+            Alphabet::UrlSafe
+        } else {
+            Alphabet::Standard
+        },
+        exclude: args
+            .values("--exclude")
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        exclude_from: args
+            .values("--exclude-from")
+            .into_iter()
+            .map(PathBuf::from)
+            .collect(),
+        exclude_vcs: args.has("--exclude-vcs"),
+        symlinks: match args.value("--symlinks") {
+            Some(symlinks) => Symlinks::parse(symlinks)?,
+            None => Symlinks::Follow,
+        },
+        hash_scope: match args.value("--hash-scope") {
+            Some(scope) => HashScope::parse(scope)?,
+            None => HashScope::Content,
+        },
+    })
 }
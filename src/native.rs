@@ -0,0 +1,266 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::log;
+
+// Paths longer than this need the `\\?\` prefix to be opened on Windows.
+#[cfg(windows)]
+const MAX_PATH: usize = 260;
+
+// How many symlinks `link_leaves` follows in one chain before taking it as escaping.
+const MAX_LINK_HOPS: usize = 40;
+
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// What to do with a stored path component that Windows cannot represent.
+#[derive(Clone, Copy)]
+pub enum ReservedNames {
+    Escape,
+    Error,
+    Keep,
+}
+
+impl ReservedNames {
+    pub fn parse(s: &str) -> io::Result<ReservedNames> {
+        match s {
+            "escape" => Ok(ReservedNames::Escape),
+            "error" => Ok(ReservedNames::Error),
+            "keep" => Ok(ReservedNames::Keep),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "not support {}, available strategies are escape|error|keep",
+                    s
+                ),
+            )),
+        }
+    }
+
+    /// Reserved names only matter when extracting onto Windows.
+    pub fn platform_default() -> ReservedNames {
+        if cfg!(windows) {
+            ReservedNames::Escape
+        } else {
+            ReservedNames::Keep
+        }
+    }
+}
+
+/// A stored path that was written somewhere else on disk.
+pub struct Remap {
+    pub stored: String,
+    pub native: PathBuf,
+}
+
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name);
+    RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem))
+        || name.ends_with('.')
+        || name.ends_with(' ')
+        || name
+            .chars()
+            .any(|c| c < ' ' || matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*'))
+}
+
+fn escape(name: &str) -> String {
+    let mut escaped: String = name
+        .chars()
+        .map(|c| {
+            if c < ' ' || matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    while escaped.ends_with('.') || escaped.ends_with(' ') {
+        escaped.pop();
+        escaped.push('_');
+    }
+    let stem_len = escaped.find('.').unwrap_or(escaped.len());
+    if RESERVED_NAMES
+        .iter()
+        .any(|r| r.eq_ignore_ascii_case(&escaped[..stem_len]))
+    {
+        escaped.insert(stem_len, '_');
+    }
+    escaped
+}
+
+pub(crate) fn split_stored(stored: &str) -> impl Iterator<Item = &str> {
+    // Archives made on Windows store `\` separators, which are an ordinary character elsewhere.
+    stored
+        .split(move |c| c == '/' || (cfg!(windows) && c == '\\'))
+        .filter(|c| !c.is_empty() && *c != ".")
+}
+
+/// Translates a stored path into a native path under `root`, dropping its first `strip`
+/// components, escaping components Windows cannot represent according to `strategy` and
+/// recording every change in `remaps`. Returns `None` if stripping leaves nothing.
+pub fn native_path(
+    root: &Path,
+    stored: &str,
+    strip: usize,
+    strategy: ReservedNames,
+    remaps: &mut Vec<Remap>,
+) -> io::Result<Option<PathBuf>> {
+    let components: Vec<_> = split_stored(stored).skip(strip).collect();
+    if components.is_empty() {
+        log::warn(format!(
+            "skipped `{}`, nothing is left after stripping {} components",
+            stored, strip
+        ));
+        return Ok(None);
+    }
+    // Checked after stripping, so the result stays under `root` whatever was dropped.
+    if components.contains(&"..") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("`{}` points outside the destination", stored),
+        ));
+    }
+
+    let mut target = root.to_path_buf();
+    let mut remapped = false;
+    for component in components {
+        match strategy {
+            ReservedNames::Escape if is_reserved(component) => {
+                target.push(escape(component));
+                remapped = true;
+            }
+            ReservedNames::Error if is_reserved(component) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("`{}` contains the reserved name `{}`", stored, component),
+                ));
+            }
+            _ => target.push(component),
+        }
+    }
+    if remapped {
+        log::warn(format!("remapped {} -> {}", stored, target.display()));
+        remaps.push(Remap {
+            stored: stored.to_string(),
+            native: target.clone(),
+        });
+    }
+    long_path(target).map(Some)
+}
+
+#[cfg(windows)]
+fn long_path(target: PathBuf) -> io::Result<PathBuf> {
+    if target.as_os_str().len() < MAX_PATH {
+        return Ok(target);
+    }
+    let absolute = std::env::current_dir()?.join(target);
+    let mut prefixed = std::ffi::OsString::from(r"\\?\");
+    prefixed.push(absolute.as_os_str());
+    Ok(PathBuf::from(prefixed))
+}
+
+#[cfg(not(windows))]
+fn long_path(target: PathBuf) -> io::Result<PathBuf> {
+    Ok(target)
+}
+
+/// `dir/name.ext` with `-n` inserted before the extension, e.g. `dir/name-1.ext`.
+pub fn numbered(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, n),
+    };
+    path.with_file_name(name)
+}
+
+/// Whether a symlink in `dir`, a directory relative to the root of a tree, would point
+/// outside the tree with `target`. Absolute targets always do. Only the path is looked at;
+/// symlinks the target passes through aren't followed.
+pub(crate) fn link_escapes(dir: &Path, target: &Path) -> bool {
+    if target.has_root() {
+        return true;
+    }
+    let mut depth = dir
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .count();
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir if depth == 0 => return true,
+            Component::ParentDir => depth -= 1,
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) => return true,
+        }
+    }
+    false
+}
+
+/// Whether a symlink in `dir` pointing to `target` leads outside `root` once the symlinks on
+/// disk that it passes through are followed, like `x -> y/..` next to `y -> .`. `root` and
+/// `dir` are canonical. Components that don't exist yet are taken as written.
+pub(crate) fn link_leaves(root: &Path, dir: &Path, target: &Path) -> bool {
+    !dir.starts_with(root) || resolve_within(root, dir.to_path_buf(), target, 0).is_none()
+}
+
+/// Where `target` leads from the directory `at`, or `None` once it leaves `root`.
+fn resolve_within(root: &Path, mut at: PathBuf, target: &Path, hops: usize) -> Option<PathBuf> {
+    if target.has_root() || hops > MAX_LINK_HOPS {
+        return None;
+    }
+    for component in target.components() {
+        match component {
+            Component::Normal(name) => {
+                let next = at.join(name);
+                at = match fs::read_link(&next) {
+                    Ok(link) => resolve_within(root, at, &link, hops + 1)?,
+                    Err(_) => next,
+                };
+            }
+            Component::ParentDir if at == root => return None,
+            Component::ParentDir => {
+                at.pop();
+            }
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(at)
+}
+
+/// The first directory above `path`, below `root`, that is a symlink on disk. Nothing is
+/// extracted through one, wherever it points.
+pub(crate) fn symlink_ancestor(root: &Path, path: &Path) -> Option<PathBuf> {
+    let below = path.strip_prefix(root).ok()?.parent()?;
+    let mut at = root.to_path_buf();
+    for component in below.components() {
+        at.push(component);
+        if fs::symlink_metadata(&at).is_ok_and(|m| m.file_type().is_symlink()) {
+            return Some(at);
+        }
+    }
+    None
+}
+
+/// Creates a symlink at `link` pointing to `target`.
+#[cfg(unix)]
+pub(crate) fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+pub(crate) fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn symlink(_: &Path, link: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("can't create symlink {} on this platform", link.display()),
+    ))
+}
@@ -0,0 +1,39 @@
+// Numeric file ownership, recorded and restored under `--preserve-ownership`. Only unix
+// has it; elsewhere nothing is recorded or restored.
+
+use std::fs::{File, Metadata};
+use std::io;
+
+/// The `(uid, gid)` owning a file.
+#[cfg(unix)]
+pub fn of(metadata: &Metadata) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.uid(), metadata.gid()))
+}
+
+#[cfg(not(unix))]
+pub fn of(_: &Metadata) -> Option<(u32, u32)> {
+    None
+}
+
+/// Whether this process may give files away, which takes root.
+#[cfg(unix)]
+pub fn can_restore() -> bool {
+    // SAFETY: `geteuid` has no preconditions and can't fail.
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+pub fn can_restore() -> bool {
+    false
+}
+
+#[cfg(unix)]
+pub fn restore(file: &File, (uid, gid): (u32, u32)) -> io::Result<()> {
+    std::os::unix::fs::fchown(file, Some(uid), Some(gid))
+}
+
+#[cfg(not(unix))]
+pub fn restore(_: &File, _: (u32, u32)) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,28 @@
+// Permission bits, recorded under `--preserve-perms` and restored on decode. Only unix has
+// them; elsewhere nothing is recorded or restored.
+
+use std::fs::{File, Metadata};
+use std::io;
+
+/// The permission bits of a file, setuid, setgid and sticky included.
+#[cfg(unix)]
+pub fn of(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub fn of(_: &Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+pub fn restore(file: &File, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn restore(_: &File, _: u32) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use std::io;
+use std::thread;
+
+/// Number of worker threads when `--threads` isn't given: one per logical CPU.
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Runs `work` on every item using up to `threads` threads, handing the results to `sink`
+/// in the original item order, so the output is identical whatever the thread count.
+/// With `threads` of 1 everything runs sequentially on the calling thread.
+///
+/// Items are processed in batches of `threads` so at most that many results are held in
+/// memory at once.
+pub fn for_each_ordered<T, R, W, S>(
+    items: Vec<T>,
+    threads: usize,
+    work: W,
+    mut sink: S,
+) -> io::Result<()>
+where
+    T: Send,
+    R: Send,
+    W: Fn(T) -> R + Sync,
+    S: FnMut(R) -> io::Result<()>,
+{
+    if threads <= 1 {
+        for item in items {
+            sink(work(item))?;
+        }
+        return Ok(());
+    }
+
+    let mut items = items.into_iter().peekable();
+    while items.peek().is_some() {
+        let batch: Vec<T> = items.by_ref().take(threads).collect();
+        let results: Vec<R> = thread::scope(|scope| {
+            let handles: Vec<_> = batch
+                .into_iter()
+                .map(|item| scope.spawn(|| work(item)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("worker thread panicked"))
+                .collect()
+        });
+        for result in results {
+            sink(result)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,197 @@
+// `mito selftest`: encodes and decodes a small fixture in every mode and compares the result
+// byte for byte, to tell whether this build works on this machine. The fixture holds the
+// kinds of content that have broken round trips before.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::decode::{decode_dir, Conflict, DecodeOptions, OnDuplicate};
+use crate::encode::{encode_paths, CaseCollision, EncodeOptions, OutputFormat, Symlinks};
+use crate::native::ReservedNames;
+use crate::vfs::OsFs;
+use crate::writer::{COMPRESSION_LEVEL, DEFAULT_AUTO_SAMPLE};
+use crate::{Alphabet, HashScope, Mode};
+
+const MODES: [Mode; 5] = [
+    Mode::Plain,
+    Mode::Base64,
+    Mode::Auto,
+    Mode::CompressedBinary,
+    Mode::CompressedTxt,
+];
+
+/// The fixture, as stored paths and their content.
+fn fixture() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("empty", Vec::new()),
+        ("binary.dat", (0..=255).chain((0..=255).rev()).collect()),
+        ("ünïcödé/名前.txt", "naïve café\n日本語\n".into()),
+        // A body line that has the shape of an entry separator, the last one without a
+        // newline of its own.
+        (
+            "separator.txt",
+            "before\n====fake.txt|123====\nafter\n====fake.txt|456====".into(),
+        ),
+        ("no-newline.txt", "no newline at the end".into()),
+        ("blank-lines.txt", "\n\n\n".into()),
+        // Mostly zeros, written with holes where the filesystem has them.
+        ("sparse.img", {
+            let mut image = vec![0; 1024 * 1024];
+            image[300_000..300_005].copy_from_slice(b"data\n");
+            image
+        }),
+    ]
+}
+
+// The block size holes are made in.
+const BLOCK: usize = 4096;
+
+/// Writes `content` to `path`, seeking over its all-zero blocks so they become holes.
+fn write_sparse(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    for block in content.chunks(BLOCK) {
+        if block.iter().all(|&b| b == 0) {
+            file.seek(SeekFrom::Current(block.len() as i64))?;
+        } else {
+            file.write_all(block)?;
+        }
+    }
+    file.set_len(content.len() as u64)
+}
+
+/// A scratch directory under the system temporary directory, removed when dropped. Tests
+/// use it too, each under its own `name`.
+pub(crate) struct Scratch {
+    pub(crate) path: PathBuf,
+}
+
+impl Scratch {
+    pub(crate) fn create(name: &str) -> io::Result<Scratch> {
+        let path = env::temp_dir().join(format!("mito-{}-{}", name, process::id()));
+        // Left over from an earlier run that reused this pid.
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path)?;
+        Ok(Scratch { path })
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+pub(crate) fn encode_options(output: PathBuf, relative_to: &Path) -> EncodeOptions {
+    EncodeOptions {
+        output,
+        temp_dir: None,
+        preserve_mtime: false,
+        preserve_btime: false,
+        preserve_ownership: false,
+        preserve_perms: false,
+        stored_hash: true,
+        sparse: true,
+        alphabet: Alphabet::Standard,
+        clamp_mtime: None,
+        threads: 1,
+        order_from: None,
+        relative_to: Some(relative_to.to_path_buf()),
+        case_collision: Some(CaseCollision::Error),
+        output_format: OutputFormat::Mito,
+        auto_sample: DEFAULT_AUTO_SAMPLE,
+        hash_scope: HashScope::Content,
+        level: COMPRESSION_LEVEL,
+        index: false,
+        exclude: Vec::new(),
+        exclude_from: Vec::new(),
+        exclude_vcs: false,
+        symlinks: Symlinks::Follow,
+        codec_for: Vec::new(),
+        no_compress_ext: Vec::new(),
+        exclude_larger_than: None,
+        exclude_smaller_than: None,
+        modified_since: None,
+        include_archives: false,
+        strict: false,
+        max_memory: None,
+        record_root: false,
+        wrap: None,
+        min_ratio: None,
+        ignore_errors: false,
+        max_entries: None,
+        json: false,
+    }
+}
+
+pub(crate) fn decode_options(dest: PathBuf) -> DecodeOptions {
+    DecodeOptions {
+        dest,
+        strip_components: 0,
+        flatten: false,
+        reserved_names: ReservedNames::platform_default(),
+        conflict: Conflict::Error,
+        on_duplicate: OnDuplicate::Error,
+        best_effort: false,
+        strict: true,
+        only: Vec::new(),
+        hash_prefix: None,
+        compat: None,
+        restore: None,
+        space_check: false,
+        allow_external_symlinks: false,
+        touch_only: false,
+        expect_root: None,
+        dedup_links: false,
+        max_entries: None,
+        volumes: false,
+    }
+}
+
+/// Round-trips the fixture in `mode` under `scratch`, describing the first difference.
+fn round_trip(scratch: &Path, src: &Path, mode: Mode) -> io::Result<Result<(), String>> {
+    let archive = scratch.join(format!("{}.out", mode));
+    let dest = scratch.join(format!("{}.decoded", mode));
+    let sources = [src.to_path_buf()];
+    encode_paths(&OsFs, &sources, mode, &encode_options(archive.clone(), src))?;
+    decode_dir(&archive, mode, &decode_options(dest.clone()))?;
+    for (path, content) in fixture() {
+        match fs::read(dest.join(path)) {
+            Ok(decoded) if decoded == content => {}
+            Ok(decoded) => {
+                return Ok(Err(format!(
+                    "{} decoded to {} bytes that differ from its {}",
+                    path,
+                    decoded.len(),
+                    content.len()
+                )))
+            }
+            Err(e) => return Ok(Err(format!("{} wasn't decoded: {}", path, e))),
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// Round-trips the fixture in every mode, with what went wrong in each one that failed.
+pub fn run() -> io::Result<Vec<(Mode, Result<(), String>)>> {
+    let scratch = Scratch::create("selftest")?;
+    let src = scratch.path.join("fixture");
+    for (path, content) in fixture() {
+        let path = src.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_sparse(&path, &content)?;
+    }
+    let mut results = Vec::new();
+    for mode in MODES.iter().copied() {
+        let result = match round_trip(&scratch.path, &src, mode) {
+            Ok(result) => result,
+            Err(e) => Err(e.to_string()),
+        };
+        results.push((mode, result));
+    }
+    Ok(results)
+}
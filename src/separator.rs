@@ -0,0 +1,427 @@
+// The line that starts every entry:
+//
+//     separator = "====" path "|" hash *( "|" key "=" value ) "====" LF
+//
+// `hash` is the entry's 64-bit hash in decimal. The known fields, in the order they are
+// written, are `enc=plain|base64`, `size=<bytes>`, `holes=<offset>:<len>,...`, the runs of
+// zeros left out of the body of a `--sparse` entry, `codec=deflate`, `section=raw`, a body
+// stored outside the compressed stream under `--no-compress-ext`, `mtime=<seconds>`,
+// `btime=<seconds>`, the birth time recorded under `--preserve-btime`, `perms=<octal>`,
+// `uid=<n>`, `gid=<n>`, `symlink=<target>` and `bodyhash=<hash>`, the hash of the body as
+// stored, before it is decoded; a reader skips keys it doesn't know. From
+// format version 2 on, `%`, `|`, CR and LF in the path and in values are written as `%25`,
+// `%7C`, `%0D` and `%0A`, so any path fits on one line without being mistaken for a field.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::header;
+use crate::writer::Codec;
+use crate::Encoding;
+
+const OPEN: &str = "====";
+const CLOSE: &str = "====\n";
+
+/// The first format version whose separators are escaped.
+const ESCAPED_SINCE: u32 = 2;
+
+/// An entry's separator line, `====path|hash|key=value...====`.
+#[derive(Clone)]
+pub struct Separator {
+    pub path: String,
+    pub hash: String,
+    // `None` when the entry uses the archive's mode.
+    pub encoding: Option<Encoding>,
+    pub size: Option<u64>,
+    // `(offset, len)` of each hole left out of the body, in order.
+    pub holes: Vec<(u64, u64)>,
+    pub codec: Codec,
+    // The body is `size` bytes in the raw section that follows, see `stack::SectionReader`.
+    pub raw: bool,
+    pub mtime: Option<u64>,
+    // Birth time in seconds, see `btime`.
+    pub btime: Option<u64>,
+    pub perms: Option<u32>,
+    pub owner: Option<(u32, u32)>,
+    pub link: Option<String>,
+    // Hash of the body bytes as they are in the archive, in decimal like `hash`.
+    pub body_hash: Option<String>,
+}
+
+/// What is wrong with a line that looked like a separator.
+#[derive(Debug)]
+pub struct ParseError {
+    message: String,
+}
+
+impl ParseError {
+    fn new(message: String) -> ParseError {
+        ParseError { message }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(e: ParseError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Whether `line`, with its newline, has the shape of a separator.
+pub fn is_separator(line: &[u8]) -> bool {
+    line.len() >= OPEN.len() + CLOSE.len()
+        && line.starts_with(OPEN.as_bytes())
+        && line.ends_with(CLOSE.as_bytes())
+}
+
+/// Parses `line` if it is a separator, with the rules of format `version`.
+pub(crate) fn from_line(line: &[u8], version: u32) -> Result<Option<Separator>, ParseError> {
+    if !is_separator(line) {
+        return Ok(None);
+    }
+    let text = std::str::from_utf8(line)
+        .map_err(|e| ParseError::new(format!("entry separator is not valid UTF-8: {}", e)))?;
+    Separator::parse_version(text, version).map(Some)
+}
+
+impl Separator {
+    /// A separator for `path` with no hash and no fields yet.
+    pub fn new(path: impl Into<String>) -> Separator {
+        Separator {
+            path: path.into(),
+            hash: String::new(),
+            encoding: None,
+            size: None,
+            holes: Vec::new(),
+            codec: Codec::Store,
+            raw: false,
+            mtime: None,
+            btime: None,
+            perms: None,
+            owner: None,
+            link: None,
+            body_hash: None,
+        }
+    }
+
+    /// Parses a separator line, with or without its newline, as this version writes it.
+    pub fn parse(line: &str) -> Result<Separator, ParseError> {
+        Separator::parse_version(line, header::VERSION)
+    }
+
+    /// Parses a separator line with the rules of format `version`. Versions newer than this
+    /// build read as the newest it knows.
+    pub fn parse_version(line: &str, version: u32) -> Result<Separator, ParseError> {
+        let body = line
+            .strip_suffix('\n')
+            .unwrap_or(line)
+            .strip_prefix(OPEN)
+            .and_then(|body| body.strip_suffix(OPEN))
+            .ok_or_else(|| ParseError::new(format!("expected `{}path|hash{}`", OPEN, OPEN)))?;
+        let value_of = |s: &str| {
+            if version >= ESCAPED_SINCE {
+                unescape(s)
+            } else {
+                Ok(s.to_string())
+            }
+        };
+        let mut fields = body.split('|');
+        let mut separator = Separator::new(value_of(fields.next().unwrap_or_default())?);
+        separator.hash = fields.next().unwrap_or_default().to_string();
+        let mut known = Vec::new();
+        for field in fields {
+            match field.split_once('=') {
+                Some((key, value)) => known.push((key, value_of(value)?)),
+                None if version == 0 => known.push((field, String::new())),
+                // Not a field at all; later versions might give it a meaning.
+                None => {}
+            }
+        }
+        match version {
+            0 => separator.parse_fields_v0(known)?,
+            _ => separator.parse_fields_v1(known)?,
+        }
+        Ok(separator)
+    }
+
+    /// Headerless archives, from before the header: only `enc=` and `mtime=` existed, so
+    /// anything else means the archive isn't really version 0.
+    fn parse_fields_v0(&mut self, fields: Vec<(&str, String)>) -> Result<(), ParseError> {
+        for (key, value) in fields {
+            if !self.parse_known_field(key, &value)? {
+                let field = if value.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}={}", key, value)
+                };
+                return Err(ParseError::new(format!(
+                    "unknown field `{}` for {} in an archive without a header, try --compat {}",
+                    field,
+                    self.path,
+                    header::VERSION
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// `MITO/1` and later archives add `size=`, `holes=`, `codec=`, `section=`, `btime=`,
+    /// `perms=`, `uid=`, `gid=`, `symlink=` and `bodyhash=`, and ignore unknown fields so that
+    /// later additions stay readable. `MITO/2` escapes them.
+    fn parse_fields_v1(&mut self, fields: Vec<(&str, String)>) -> Result<(), ParseError> {
+        let (mut uid, mut gid) = (None, None);
+        for (key, value) in fields {
+            if self.parse_known_field(key, &value)? {
+                continue;
+            }
+            match key {
+                "size" => self.size = Some(self.parse_number(key, &value)?),
+                "holes" => self.holes = self.parse_holes(&value)?,
+                "codec" => {
+                    self.codec = Codec::parse(&value).map_err(|_| {
+                        ParseError::new(format!("unknown codec `{}` for {}", value, self.path))
+                    })?
+                }
+                "section" => {
+                    if value != "raw" {
+                        return Err(ParseError::new(format!(
+                            "unknown section `{}` for {}",
+                            value, self.path
+                        )));
+                    }
+                    self.raw = true;
+                }
+                "btime" => self.btime = Some(self.parse_number(key, &value)?),
+                "perms" => {
+                    self.perms = Some(u32::from_str_radix(&value, 8).map_err(|_| {
+                        ParseError::new(format!("invalid perms `{}` for {}", value, self.path))
+                    })?)
+                }
+                "uid" => uid = Some(self.parse_number(key, &value)?),
+                "gid" => gid = Some(self.parse_number(key, &value)?),
+                "symlink" => self.link = Some(value),
+                "bodyhash" => self.body_hash = Some(value),
+                _ => {}
+            }
+        }
+        self.owner = uid.zip(gid);
+        Ok(())
+    }
+
+    /// Sets the field `key` stands for if every version knows it, or returns that it isn't.
+    fn parse_known_field(&mut self, key: &str, value: &str) -> Result<bool, ParseError> {
+        match key {
+            "mtime" => self.mtime = Some(self.parse_number(key, value)?),
+            "enc" => {
+                self.encoding = Some(Encoding::parse(value).ok_or_else(|| {
+                    ParseError::new(format!("unknown encoding `{}` for {}", value, self.path))
+                })?)
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn parse_number<T: std::str::FromStr>(&self, key: &str, value: &str) -> Result<T, ParseError> {
+        value
+            .parse()
+            .map_err(|_| ParseError::new(format!("invalid {} `{}` for {}", key, value, self.path)))
+    }
+
+    fn parse_holes(&self, value: &str) -> Result<Vec<(u64, u64)>, ParseError> {
+        let invalid = || ParseError::new(format!("invalid holes `{}` for {}", value, self.path));
+        let mut holes: Vec<(u64, u64)> = Vec::new();
+        for hole in value.split(',').filter(|hole| !hole.is_empty()) {
+            let (offset, len) = hole.split_once(':').ok_or_else(invalid)?;
+            let hole = (
+                offset.parse().map_err(|_| invalid())?,
+                len.parse().map_err(|_| invalid())?,
+            );
+            // Out of order or overlapping holes can't be put back by seeking forward.
+            if holes
+                .last()
+                .is_some_and(|&(start, len)| hole.0 < start + len)
+            {
+                return Err(invalid());
+            }
+            holes.push(hole);
+        }
+        Ok(holes)
+    }
+
+    /// The line `self` is written as, with its newline, in the current format version.
+    pub fn to_line(&self) -> String {
+        let mut line = format!("{}{}|{}", OPEN, escape(&self.path), self.hash);
+        let mut field = |key: &str, value: &str| {
+            line.push_str(&format!("|{}={}", key, escape(value)));
+        };
+        if let Some(encoding) = self.encoding {
+            field("enc", encoding.name());
+        }
+        if let Some(size) = self.size {
+            field("size", &size.to_string());
+        }
+        if !self.holes.is_empty() {
+            let holes: Vec<_> = self
+                .holes
+                .iter()
+                .map(|(offset, len)| format!("{}:{}", offset, len))
+                .collect();
+            field("holes", &holes.join(","));
+        }
+        if self.codec != Codec::Store {
+            field("codec", self.codec.name());
+        }
+        if self.raw {
+            field("section", "raw");
+        }
+        if let Some(mtime) = self.mtime {
+            field("mtime", &mtime.to_string());
+        }
+        if let Some(btime) = self.btime {
+            field("btime", &btime.to_string());
+        }
+        if let Some(perms) = self.perms {
+            field("perms", &format!("{:o}", perms));
+        }
+        if let Some((uid, gid)) = self.owner {
+            field("uid", &uid.to_string());
+            field("gid", &gid.to_string());
+        }
+        if let Some(link) = &self.link {
+            field("symlink", link);
+        }
+        if let Some(body_hash) = &self.body_hash {
+            field("bodyhash", body_hash);
+        }
+        line.push_str(CLOSE);
+        line
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '%' => escaped.push_str("%25"),
+            '|' => escaped.push_str("%7C"),
+            '\r' => escaped.push_str("%0D"),
+            '\n' => escaped.push_str("%0A"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(s: &str) -> Result<String, ParseError> {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(at) = rest.find('%') {
+        unescaped.push_str(&rest[..at]);
+        let c = match rest.get(at + 1..at + 3) {
+            Some("25") => '%',
+            Some("7C") | Some("7c") => '|',
+            Some("0D") | Some("0d") => '\r',
+            Some("0A") | Some("0a") => '\n',
+            _ => {
+                return Err(ParseError::new(format!(
+                    "invalid escape in separator field `{}`",
+                    s
+                )))
+            }
+        };
+        unescaped.push(c);
+        rest = &rest[at + 3..];
+    }
+    unescaped.push_str(rest);
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_of(line: &str) -> String {
+        match Separator::parse(line) {
+            Ok(_) => panic!("`{}` parsed", line),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn every_field_round_trips() {
+        let mut separator = Separator::new("dir/a|b%c\r\n.txt");
+        separator.hash = "123".to_string();
+        separator.encoding = Some(Encoding::Base64);
+        separator.size = Some(4096);
+        separator.holes = vec![(0, 512), (1024, 512)];
+        separator.codec = Codec::Deflate;
+        separator.raw = true;
+        separator.mtime = Some(1);
+        separator.btime = Some(2);
+        separator.perms = Some(0o755);
+        separator.owner = Some((1000, 100));
+        separator.link = Some("../x|y".to_string());
+        separator.body_hash = Some("456".to_string());
+        let line = separator.to_line();
+        assert!(is_separator(line.as_bytes()));
+        assert_eq!(line.matches('\n').count(), 1);
+        let parsed = Separator::parse(&line).unwrap();
+        assert_eq!(parsed.to_line(), line);
+        assert_eq!(parsed.path, separator.path);
+        assert_eq!(parsed.link, separator.link);
+        assert_eq!(parsed.holes, separator.holes);
+        assert_eq!(parsed.owner, separator.owner);
+    }
+
+    #[test]
+    fn lines_that_arent_separators() {
+        assert!(from_line(b"plain body\n", 2).unwrap().is_none());
+        assert!(from_line(b"====a|1====", 2).unwrap().is_none());
+        assert!(from_line(b"========\n", 2).unwrap().is_some());
+        assert!(from_line(b"====\xff|1====\n", 2).is_err());
+        assert!(error_of("====a|1").contains("expected"));
+    }
+
+    #[test]
+    fn malformed_fields() {
+        assert!(error_of("====a|1|size=-1====").contains("invalid size `-1`"));
+        assert!(error_of("====a|1|mtime=soon====").contains("invalid mtime"));
+        assert!(error_of("====a|1|enc=hex====").contains("unknown encoding `hex`"));
+        assert!(error_of("====a|1|codec=lz4====").contains("unknown codec `lz4`"));
+        assert!(error_of("====a|1|section=cooked====").contains("unknown section"));
+        assert!(error_of("====a|1|perms=999====").contains("invalid perms"));
+        assert!(error_of("====a|1|holes=5====").contains("invalid holes"));
+        // Out of order, and overlapping.
+        assert!(error_of("====a|1|holes=10:5,0:5====").contains("invalid holes"));
+        assert!(error_of("====a|1|holes=0:10,5:5====").contains("invalid holes"));
+        assert!(error_of("====a%zz|1====").contains("invalid escape"));
+        assert!(error_of("====a|1|symlink=%2====").contains("invalid escape"));
+    }
+
+    #[test]
+    fn fields_by_version() {
+        // Unknown fields are skipped from version 1 on, and refused before it.
+        assert!(Separator::parse("====a|1|future=yes|loose====").is_ok());
+        let e = Separator::parse_version("====a|1|size=3====", 0)
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("--compat"), "{}", e);
+        let v0 = Separator::parse_version("====a|1|enc=base64|mtime=7====", 0).unwrap();
+        assert!(v0.encoding == Some(Encoding::Base64) && v0.mtime == Some(7));
+        // Escapes are only read from version 2 on.
+        assert_eq!(
+            Separator::parse_version("====a%7Cb|1====", 1).unwrap().path,
+            "a%7Cb"
+        );
+        assert_eq!(Separator::parse("====a%7cb|1====").unwrap().path, "a|b");
+    }
+}
@@ -0,0 +1,33 @@
+// Free space on the filesystem decode extracts to, checked before writing anything.
+
+use std::io;
+use std::path::Path;
+
+/// Bytes available to this user on the filesystem holding `path`, or the nearest existing
+/// directory above it. `None` where it can't be queried.
+#[cfg(unix)]
+pub fn available(path: &Path) -> io::Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let existing = path
+        .ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or_else(|| Path::new("."));
+    let c_path = CString::new(existing.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `c_path` is NUL-terminated and `stats` is only read after `statvfs` filled it in.
+    let stats = unsafe {
+        if libc::statvfs(c_path.as_ptr(), stats.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stats.assume_init()
+    };
+    Ok(Some(stats.f_bavail as u64 * stats.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+pub fn available(_: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
@@ -0,0 +1,92 @@
+// Holes in sparse files, skipped under `--sparse` and recreated on decode. Only Linux can
+// find them, with `SEEK_HOLE`; elsewhere, and on filesystems without holes, every file reads
+// as having none and is stored in full.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+/// The holes of `file` as `(offset, len)`, in order.
+#[cfg(target_os = "linux")]
+pub fn holes(file: &File) -> io::Result<Vec<(u64, u64)>> {
+    use std::os::unix::io::AsRawFd;
+    let len = file.metadata()?.len();
+    let fd = file.as_raw_fd();
+    let mut holes = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        // SAFETY: `fd` belongs to `file`, which outlives the call; `lseek` only moves its
+        // position.
+        let hole = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_HOLE) };
+        if hole < 0 {
+            // `EINVAL` is a filesystem that doesn't report holes.
+            return Ok(Vec::new());
+        }
+        let hole = hole as u64;
+        if hole >= len {
+            break;
+        }
+        // SAFETY: as above.
+        let data = unsafe { libc::lseek(fd, hole as libc::off_t, libc::SEEK_DATA) };
+        let end = if data >= 0 {
+            data as u64
+        } else if io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO) {
+            // No data after the hole: it runs to the end of the file.
+            len
+        } else {
+            return Ok(Vec::new());
+        };
+        holes.push((hole, end - hole));
+        offset = end;
+    }
+    Ok(holes)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn holes(_: &File) -> io::Result<Vec<(u64, u64)>> {
+    Ok(Vec::new())
+}
+
+/// Copies `reader` to `out` to the end, leaving out the bytes in `holes`.
+pub fn copy_data(
+    reader: &mut dyn Read,
+    holes: &[(u64, u64)],
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let mut offset = 0;
+    for &(start, len) in holes {
+        io::copy(&mut Read::take(&mut *reader, start - offset), out)?;
+        io::copy(&mut Read::take(&mut *reader, len), &mut io::sink())?;
+        offset = start + len;
+    }
+    io::copy(reader, out)?;
+    Ok(())
+}
+
+/// The length of the content `data` stands for with `holes` put back in.
+pub fn content_len(data: &[u8], holes: &[(u64, u64)]) -> u64 {
+    data.len() as u64 + holes.iter().map(|&(_, len)| len).sum::<u64>()
+}
+
+/// Feeds `write` `len` zeros, a chunk at a time rather than all at once.
+pub fn for_each_zeros(len: u64, mut write: impl FnMut(&[u8])) {
+    let zeros = [0u8; 64 * 1024];
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(zeros.len() as u64) as usize;
+        write(&zeros[..n]);
+        left -= n as u64;
+    }
+}
+
+/// Feeds `write` the content `data` stands for with `holes` put back in, in order.
+pub fn for_each_chunk(data: &[u8], holes: &[(u64, u64)], mut write: impl FnMut(&[u8])) {
+    let (mut offset, mut data) = (0, data);
+    for &(start, len) in holes {
+        let (before, rest) = data.split_at(((start - offset) as usize).min(data.len()));
+        write(before);
+        data = rest;
+        for_each_zeros(len, &mut write);
+        offset = start + len;
+    }
+    write(data);
+}
@@ -0,0 +1,227 @@
+// `--max-memory`: a cap on the entry buffers encode holds at once, across `--threads`. A
+// buffer that would take memory past the cap is written to a temporary file instead and read
+// back through a read-only mapping, whose pages the kernel can drop and reload from the file
+// rather than keep resident. Without a mapping, off unix, buffers stay in memory.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::log;
+
+/// How much the buffers of one encode may hold in memory, and where they spill to.
+pub(crate) struct Budget {
+    cap: u64,
+    used: AtomicU64,
+    dir: PathBuf,
+    // Numbers the spill files of this process.
+    spilled: AtomicUsize,
+}
+
+impl Budget {
+    pub(crate) fn new(cap: u64, dir: &Path) -> Arc<Budget> {
+        Arc::new(Budget {
+            cap,
+            used: AtomicU64::new(0),
+            dir: dir.to_path_buf(),
+            spilled: AtomicUsize::new(0),
+        })
+    }
+
+    /// Takes `len` bytes of the cap, or returns `false` if that would exceed it.
+    fn reserve(&self, len: u64) -> bool {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                used.checked_add(len).filter(|&total| total <= self.cap)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, len: u64) {
+        self.used.fetch_sub(len, Ordering::SeqCst);
+    }
+
+    /// A new file to spill to, already unlinked so that nothing is left behind however
+    /// encode ends.
+    #[cfg(unix)]
+    fn spill_file(&self) -> io::Result<File> {
+        let n = self.spilled.fetch_add(1, Ordering::SeqCst);
+        let path = self
+            .dir
+            .join(format!(".mito-spill-{}-{}", process::id(), n));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        fs::remove_file(&path)?;
+        Ok(file)
+    }
+}
+
+/// Bytes held in memory, counted against a `Budget` if they came out of one, or in a
+/// spill file.
+pub(crate) enum Buffer {
+    Memory {
+        bytes: Vec<u8>,
+        budget: Option<(Arc<Budget>, u64)>,
+    },
+    #[cfg(unix)]
+    Mapped(Mapping),
+}
+
+impl Buffer {
+    /// `bytes`, outside any budget.
+    pub(crate) fn new(bytes: Vec<u8>) -> Buffer {
+        Buffer::Memory {
+            bytes,
+            budget: None,
+        }
+    }
+
+    /// Collects what `fill` writes, about `estimate` bytes, in memory if `budget` has room
+    /// for it and in a spill file otherwise. `what` names the buffer in the verbose log.
+    pub(crate) fn write_with(
+        estimate: u64,
+        budget: Option<&Arc<Budget>>,
+        what: impl FnOnce() -> String,
+        fill: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+    ) -> io::Result<Buffer> {
+        let budget = match budget {
+            Some(budget) if estimate > 0 && !budget.reserve(estimate) => {
+                log::verbose(log::DECISIONS, || {
+                    format!(
+                        "spilled {} to disk, its {} bytes would go past --max-memory",
+                        what(),
+                        estimate
+                    )
+                });
+                return Buffer::spill(budget, fill);
+            }
+            Some(budget) => Some((Arc::clone(budget), estimate)),
+            None => None,
+        };
+        let mut bytes = Vec::with_capacity(estimate as usize);
+        fill(&mut bytes)?;
+        Ok(Buffer::Memory { bytes, budget })
+    }
+
+    #[cfg(unix)]
+    fn spill(
+        budget: &Budget,
+        fill: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+    ) -> io::Result<Buffer> {
+        let mut file = io::BufWriter::new(budget.spill_file()?);
+        fill(&mut file)?;
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+        Ok(match Mapping::new(file)? {
+            Some(mapping) => Buffer::Mapped(mapping),
+            None => Buffer::new(Vec::new()),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn spill(
+        _: &Budget,
+        fill: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+    ) -> io::Result<Buffer> {
+        let mut bytes = Vec::new();
+        fill(&mut bytes)?;
+        Ok(Buffer::new(bytes))
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Buffer::Memory { bytes, .. } => bytes,
+            #[cfg(unix)]
+            Buffer::Mapped(mapping) => mapping,
+        }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Buffer::Memory {
+            budget: Some((budget, len)),
+            ..
+        } = self
+        {
+            budget.release(*len);
+        }
+    }
+}
+
+/// A spill file mapped read-only into memory.
+#[cfg(unix)]
+pub(crate) struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+    // Kept open for as long as it is mapped.
+    _file: File,
+}
+
+// SAFETY: the mapping is read-only and owned by `Mapping` alone, like a `Vec<u8>`.
+#[cfg(unix)]
+unsafe impl Send for Mapping {}
+#[cfg(unix)]
+unsafe impl Sync for Mapping {}
+
+#[cfg(unix)]
+impl Mapping {
+    /// Maps all of `file`, or returns `None` if it is empty, which can't be mapped.
+    fn new(file: File) -> io::Result<Option<Mapping>> {
+        use std::os::unix::io::AsRawFd;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+        // SAFETY: `file` is open for reading and stays open for the life of the mapping,
+        // and nothing else writes to it: it is unlinked and private to this process.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(Mapping {
+            ptr,
+            len,
+            _file: file,
+        }))
+    }
+}
+
+#[cfg(unix)]
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `ptr` is a live read-only mapping of `len` bytes until `drop`.
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and `len` are the mapping made in `new`, unmapped only here.
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
@@ -0,0 +1,455 @@
+// `Read` and `Write` adapters that each mode's archive-level encoding is stacked from, so
+// `Mode::CompressedTxt` is `ZlibEncoder<Base64Writer<W>>` on the way out and
+// `SectionReader<Base64Reader<R>>` on the way back.
+//
+// The compressed modes are one zlib stream, unless `--no-compress-ext` stored bodies outside
+// it. Then the stream ends after each such entry's separator, the body follows as a raw
+// section, and a new stream picks up with the rest:
+//
+//     archive = stream *( raw stream )
+//     raw     = "MITORAW " len LF len*OCTET
+//
+// `len` is in decimal. For `Mode::CompressedTxt` all of it is inside the base64. Read back to
+// back, the streams and sections are the archive as the uncompressed modes would write it;
+// decode tells a raw body from lines by the `section=raw` in its separator.
+
+use std::io::{self, BufRead, Read, Write};
+
+use base64::write::EncoderWriter;
+use flate2::bufread::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use crate::{Alphabet, Mode};
+
+/// Base64 encodes everything written to it into `W`, with the standard alphabet and padding.
+/// Only complete after `finish`, which writes the last partial group.
+pub struct Base64Writer<W: Write> {
+    inner: EncoderWriter<Wrapped<W>>,
+}
+
+impl<W: Write> Base64Writer<W> {
+    pub fn new(inner: W) -> Base64Writer<W> {
+        Base64Writer::with_alphabet(inner, Alphabet::Standard)
+    }
+
+    pub fn with_alphabet(inner: W, alphabet: Alphabet) -> Base64Writer<W> {
+        Base64Writer::with_wrap(inner, alphabet, None)
+    }
+
+    /// Like `with_alphabet`, breaking the base64 into lines of `wrap` characters, see
+    /// `--wrap`. The last line has no newline of its own.
+    pub fn with_wrap(inner: W, alphabet: Alphabet, wrap: Option<usize>) -> Base64Writer<W> {
+        let wrapped = Wrapped {
+            inner,
+            width: wrap,
+            column: 0,
+        };
+        Base64Writer {
+            inner: EncoderWriter::new(wrapped, alphabet.config()),
+        }
+    }
+
+    /// Writes the final group and its padding, and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        Ok(self.inner.finish()?.inner)
+    }
+}
+
+/// Passes what is written on to `W`, starting a new line every `width` bytes.
+struct Wrapped<W: Write> {
+    inner: W,
+    width: Option<usize>,
+    // Bytes on the current line so far.
+    column: usize,
+}
+
+impl<W: Write> Write for Wrapped<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let width = match self.width {
+            Some(width) => width,
+            None => return self.inner.write(buf),
+        };
+        // All of `buf` at once: the base64 encoder takes a short write for no progress.
+        let mut rest = buf;
+        while !rest.is_empty() {
+            // The newline only goes in once more follows it.
+            if self.column == width {
+                self.inner.write_all(b"\n")?;
+                self.column = 0;
+            }
+            let (line, after) = rest.split_at(rest.len().min(width - self.column));
+            self.inner.write_all(line)?;
+            self.column += line.len();
+            rest = after;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for Base64Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// How much base64 `Base64Reader` reads from its input at a time.
+const BASE64_CHUNK: usize = 8 * 1024;
+
+/// Reads the bytes that the standard base64 read from `R` stands for, skipping the line breaks
+/// of `--wrap`. Invalid base64 is an `InvalidData` error.
+pub struct Base64Reader<'a, R: Read> {
+    inner: &'a mut R,
+    // `None` until the input shows which alphabet it is in, when either is accepted.
+    alphabet: Option<Alphabet>,
+    // Input read but not decoded yet, a partial group of four.
+    pending: Vec<u8>,
+    decoded: Vec<u8>,
+    consumed: usize,
+    eof: bool,
+}
+
+impl<'a, R: Read> Base64Reader<'a, R> {
+    pub fn new(inner: &'a mut R) -> Base64Reader<'a, R> {
+        Base64Reader::with_alphabet(inner, Some(Alphabet::Standard))
+    }
+
+    /// Reads base64 in `alphabet`, or with `None` in whichever alphabet the input turns out
+    /// to be in; a character of the other one after that is invalid.
+    pub fn with_alphabet(inner: &'a mut R, alphabet: Option<Alphabet>) -> Base64Reader<'a, R> {
+        Base64Reader {
+            inner,
+            alphabet,
+            pending: Vec::new(),
+            decoded: Vec::new(),
+            consumed: 0,
+            eof: false,
+        }
+    }
+
+    /// Decodes the next chunk of input into `decoded`.
+    fn fill(&mut self) -> io::Result<()> {
+        let start = self.pending.len();
+        self.pending.resize(start + BASE64_CHUNK, 0);
+        let read = self.inner.read(&mut self.pending[start..]);
+        let n = read.inspect_err(|_| self.pending.truncate(start))?;
+        self.pending.truncate(start + n);
+        self.eof = n == 0;
+        let mut kept = start;
+        for i in start..self.pending.len() {
+            if !matches!(self.pending[i], b'\n' | b'\r') {
+                self.pending[kept] = self.pending[i];
+                kept += 1;
+            }
+        }
+        self.pending.truncate(kept);
+        // Whole groups only, except at the end, where the last one may be short.
+        let whole = if self.eof {
+            self.pending.len()
+        } else {
+            self.pending.len() / 4 * 4
+        };
+        let groups = &self.pending[..whole];
+        self.alphabet = self.alphabet.or_else(|| Alphabet::of(groups));
+        let config = self.alphabet.unwrap_or(Alphabet::Standard).config();
+        self.decoded.clear();
+        self.consumed = 0;
+        base64::decode_config_buf(groups, config, &mut self.decoded)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.pending.drain(..whole);
+        Ok(())
+    }
+}
+
+impl<'a, R: Read> Read for Base64Reader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.consumed == self.decoded.len() {
+            if self.eof {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let n = buf.len().min(self.decoded.len() - self.consumed);
+        buf[..n].copy_from_slice(&self.decoded[self.consumed..self.consumed + n]);
+        self.consumed += n;
+        Ok(n)
+    }
+}
+
+/// The writers a mode stacks on top of the output: none for the uncompressed modes, zlib for
+/// `Mode::CompressedBinary`, and zlib into base64 for `Mode::CompressedTxt`.
+pub enum ModeWriter<W: Write> {
+    Identity(W),
+    Zlib(ZlibEncoder<W>),
+    // Boxed for the base64 encoder's buffer.
+    Base64Zlib(Box<ZlibEncoder<Base64Writer<W>>>),
+}
+
+impl<W: Write> ModeWriter<W> {
+    pub fn new(out: W, mode: Mode, level: Compression) -> ModeWriter<W> {
+        ModeWriter::with_alphabet(out, mode, level, Alphabet::Standard)
+    }
+
+    /// Like `new`, with `Mode::CompressedTxt` written in `alphabet`.
+    pub fn with_alphabet(
+        out: W,
+        mode: Mode,
+        level: Compression,
+        alphabet: Alphabet,
+    ) -> ModeWriter<W> {
+        ModeWriter::with_wrap(out, mode, level, alphabet, None)
+    }
+
+    /// Like `with_alphabet`, with the base64 of `Mode::CompressedTxt` broken into lines of
+    /// `wrap` characters.
+    pub fn with_wrap(
+        out: W,
+        mode: Mode,
+        level: Compression,
+        alphabet: Alphabet,
+        wrap: Option<usize>,
+    ) -> ModeWriter<W> {
+        match mode {
+            Mode::CompressedBinary => ModeWriter::Zlib(ZlibEncoder::new(out, level)),
+            Mode::CompressedTxt => ModeWriter::Base64Zlib(Box::new(ZlibEncoder::new(
+                Base64Writer::with_wrap(out, alphabet, wrap),
+                level,
+            ))),
+            Mode::Plain | Mode::Base64 | Mode::Auto => ModeWriter::Identity(out),
+        }
+    }
+
+    /// Flushes what the layers have produced so far to the output, without flushing the
+    /// compressor itself, which would change the compressed bytes.
+    pub fn flush_output(&mut self) -> io::Result<()> {
+        match self {
+            ModeWriter::Identity(out) => out.flush(),
+            ModeWriter::Zlib(compressed) => compressed.get_mut().flush(),
+            ModeWriter::Base64Zlib(compressed) => compressed.get_mut().flush(),
+        }
+    }
+
+    /// Ends the compressed stream, writes `bytes` after it as a raw section, and starts a
+    /// new stream at `level` for what follows.
+    pub fn raw_section(self, bytes: &[u8], level: Compression) -> io::Result<ModeWriter<W>> {
+        let section = |out: &mut dyn Write| {
+            writeln!(out, "{}{}", RAW_SECTION, bytes.len())?;
+            out.write_all(bytes)
+        };
+        match self {
+            ModeWriter::Identity(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "raw sections are only in compressed archives",
+            )),
+            ModeWriter::Zlib(compressed) => {
+                let mut out = compressed.finish()?;
+                section(&mut out)?;
+                Ok(ModeWriter::Zlib(ZlibEncoder::new(out, level)))
+            }
+            ModeWriter::Base64Zlib(compressed) => {
+                let mut out = compressed.finish()?;
+                section(&mut out)?;
+                Ok(ModeWriter::Base64Zlib(Box::new(ZlibEncoder::new(
+                    out, level,
+                ))))
+            }
+        }
+    }
+
+    /// Finishes every layer, innermost last, and returns the output.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            ModeWriter::Identity(out) => Ok(out),
+            ModeWriter::Zlib(compressed) => compressed.finish(),
+            ModeWriter::Base64Zlib(compressed) => compressed.finish()?.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for ModeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ModeWriter::Identity(out) => out.write(buf),
+            ModeWriter::Zlib(compressed) => compressed.write(buf),
+            ModeWriter::Base64Zlib(compressed) => compressed.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ModeWriter::Identity(out) => out.flush(),
+            ModeWriter::Zlib(compressed) => compressed.flush(),
+            ModeWriter::Base64Zlib(compressed) => compressed.flush(),
+        }
+    }
+}
+
+/// Starts a raw section, followed by its length.
+pub const RAW_SECTION: &str = "MITORAW ";
+
+// The longest length line a raw section can start with.
+const MAX_SECTION_LINE: usize = 32;
+
+enum Section<R> {
+    Compressed(ZlibDecoder<R>),
+    Raw(io::Take<R>),
+    End,
+}
+
+/// Reads the streams and raw sections of a compressed archive from `R` back to back. Bytes
+/// after a stream that don't start a raw section are ignored, like anything after the one
+/// stream of an archive without sections.
+pub struct SectionReader<R: BufRead> {
+    section: Section<R>,
+}
+
+impl<R: BufRead> SectionReader<R> {
+    pub fn new(inner: R) -> SectionReader<R> {
+        SectionReader {
+            section: Section::Compressed(ZlibDecoder::new(inner)),
+        }
+    }
+}
+
+/// Reads the line that starts a raw section, or `None` if `inner` doesn't continue with one.
+fn section_len(inner: &mut impl BufRead) -> io::Result<Option<u64>> {
+    let mut line = Vec::new();
+    Read::take(&mut *inner, MAX_SECTION_LINE as u64).read_until(b'\n', &mut line)?;
+    let len = match line.strip_prefix(RAW_SECTION.as_bytes()) {
+        Some(len) => len,
+        None => return Ok(None),
+    };
+    std::str::from_utf8(len)
+        .ok()
+        .and_then(|len| len.strip_suffix('\n'))
+        .and_then(|len| len.parse().ok())
+        .map(Some)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid raw section length"))
+}
+
+impl<R: BufRead> Read for SectionReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = match &mut self.section {
+                Section::Compressed(stream) => stream.read(buf)?,
+                Section::Raw(raw) => raw.read(buf)?,
+                Section::End => return Ok(0),
+            };
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            self.section = match std::mem::replace(&mut self.section, Section::End) {
+                Section::Compressed(stream) => {
+                    let mut inner = stream.into_inner();
+                    match section_len(&mut inner)? {
+                        Some(len) => Section::Raw(inner.take(len)),
+                        None => Section::End,
+                    }
+                }
+                Section::Raw(raw) if raw.limit() > 0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "raw section is cut short",
+                    ))
+                }
+                Section::Raw(raw) => Section::Compressed(ZlibDecoder::new(raw.into_inner())),
+                Section::End => Section::End,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT: &[u8] = b"hello, adapters\n";
+
+    /// Reads all of `reader` a few bytes at a time.
+    fn read_slowly(mut reader: impl Read) -> io::Result<Vec<u8>> {
+        let (mut read, mut buf) = (Vec::new(), [0; 3]);
+        loop {
+            match reader.read(&mut buf)? {
+                0 => return Ok(read),
+                n => read.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn base64_writer_encodes_and_wraps() {
+        let mut writer = Base64Writer::new(Vec::new());
+        for byte in CONTENT.chunks(5) {
+            writer.write_all(byte).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), base64::encode(CONTENT).as_bytes());
+
+        let mut writer = Base64Writer::with_wrap(Vec::new(), Alphabet::Standard, Some(8));
+        writer.write_all(CONTENT).unwrap();
+        let wrapped = String::from_utf8(writer.finish().unwrap()).unwrap();
+        assert!(wrapped.lines().all(|line| line.len() <= 8));
+        assert!(!wrapped.ends_with('\n'));
+        assert_eq!(wrapped.replace('\n', ""), base64::encode(CONTENT));
+    }
+
+    #[test]
+    fn base64_reader_decodes_across_line_breaks() {
+        let encoded = base64::encode(CONTENT);
+        let (head, tail) = encoded.split_at(6);
+        let mut input = format!("{}\r\n{}\n", head, tail).into_bytes();
+        let decoded = read_slowly(Base64Reader::new(&mut &input[..])).unwrap();
+        assert_eq!(decoded, CONTENT);
+
+        let url_safe = base64::encode_config([0xfb, 0xff], base64::URL_SAFE);
+        let decoded = read_slowly(Base64Reader::with_alphabet(&mut url_safe.as_bytes(), None));
+        assert_eq!(decoded.unwrap(), [0xfb, 0xff]);
+
+        input.insert(2, b'!');
+        let e = read_slowly(Base64Reader::new(&mut &input[..])).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn identity_writer_passes_bytes_through() {
+        let mut writer = ModeWriter::new(Vec::new(), Mode::Plain, Compression::new(6));
+        writer.write_all(CONTENT).unwrap();
+        assert!(writer.raw_section(b"raw", Compression::new(6)).is_err());
+    }
+
+    #[test]
+    fn section_reader_joins_streams_and_raw_sections() {
+        let level = Compression::new(6);
+        let mut writer = ModeWriter::new(Vec::new(), Mode::CompressedBinary, level);
+        writer.write_all(b"before ").unwrap();
+        let mut writer = writer.raw_section(b"raw\nbytes", level).unwrap();
+        writer.write_all(b" after").unwrap();
+        let archive = writer.finish().unwrap();
+        let read = read_slowly(SectionReader::new(&archive[..])).unwrap();
+        assert_eq!(read, b"before raw\nbytes after");
+
+        let cut = &archive[..archive.len() - 12];
+        assert!(read_slowly(SectionReader::new(cut)).is_err());
+    }
+
+    #[test]
+    fn text_mode_is_zlib_inside_base64() {
+        let mut writer = ModeWriter::new(Vec::new(), Mode::CompressedTxt, Compression::new(6));
+        writer.write_all(CONTENT).unwrap();
+        let archive = writer.finish().unwrap();
+        assert!(archive
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || b"+/=".contains(b)));
+        let mut input = &archive[..];
+        let base64 = Base64Reader::new(&mut input);
+        let read = read_slowly(SectionReader::new(io::BufReader::new(base64))).unwrap();
+        assert_eq!(read, CONTENT);
+    }
+}
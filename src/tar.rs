@@ -0,0 +1,136 @@
+use std::io::{self, Write};
+
+const BLOCK: usize = 512;
+
+/// Writes a ustar archive, falling back to GNU long-name entries for paths that don't fit.
+pub struct TarWriter<W: Write> {
+    out: W,
+}
+
+/// Writes `value` to a numeric header field: zero-padded octal, leaving room for the
+/// terminating NUL, or GNU base-256 for a value too large for that, like a size of 8 GiB or
+/// more. Fails on one too large for the field either way.
+fn octal(field: &mut [u8], value: u64) -> io::Result<()> {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    if digits.len() < field.len() {
+        field[..digits.len()].copy_from_slice(digits.as_bytes());
+        return Ok(());
+    }
+    // Big-endian after a first byte with its high bit set.
+    let bytes = value.to_be_bytes();
+    let room = field.len() - 1;
+    let skip = bytes.len().saturating_sub(room);
+    if bytes[..skip].iter().any(|&b| b != 0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} doesn't fit in a {}-byte tar header field",
+                value,
+                field.len()
+            ),
+        ));
+    }
+    field.fill(0);
+    field[0] = 0x80;
+    let start = field.len() - (bytes.len() - skip);
+    field[start..].copy_from_slice(&bytes[skip..]);
+    Ok(())
+}
+
+fn header(
+    name: &[u8],
+    prefix: &[u8],
+    size: u64,
+    mode: u32,
+    mtime: u64,
+    kind: u8,
+) -> io::Result<[u8; BLOCK]> {
+    let mut header = [0u8; BLOCK];
+    header[..name.len()].copy_from_slice(name);
+    octal(&mut header[100..108], u64::from(mode))?;
+    octal(&mut header[108..116], 0)?;
+    octal(&mut header[116..124], 0)?;
+    octal(&mut header[124..136], size)?;
+    octal(&mut header[136..148], mtime)?;
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix);
+
+    // The checksum is computed with its own field set to spaces.
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|b| u32::from(*b)).sum();
+    let digits = format!("{:06o}\0 ", checksum);
+    header[148..156].copy_from_slice(digits.as_bytes());
+    Ok(header)
+}
+
+/// Splits `path` into ustar's 155-byte prefix and 100-byte name at a `/`, if possible.
+fn split_ustar(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    if path.len() <= 100 {
+        return Some((&[], path));
+    }
+    path.iter()
+        .enumerate()
+        .filter(|(i, b)| **b == b'/' && *i <= 155 && path.len() - i - 1 <= 100)
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .next()
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(out: W) -> TarWriter<W> {
+        TarWriter { out }
+    }
+
+    fn write_padded(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        let padding = (BLOCK - data.len() % BLOCK) % BLOCK;
+        self.out.write_all(&[0u8; BLOCK][..padding])
+    }
+
+    /// Appends a regular file stored at `path` (using `/` separators).
+    pub fn append(&mut self, path: &str, data: &[u8], mode: u32, mtime: u64) -> io::Result<()> {
+        let path = path.as_bytes();
+        let (prefix, name) = match split_ustar(path) {
+            Some(split) => split,
+            None => {
+                let mut long_name = path.to_vec();
+                long_name.push(0);
+                let link = header(b"././@LongLink", &[], long_name.len() as u64, 0, 0, b'L')?;
+                self.out.write_all(&link)?;
+                self.write_padded(&long_name)?;
+                (&[][..], &path[..100])
+            }
+        };
+        let header = header(name, prefix, data.len() as u64, mode, mtime, b'0')?;
+        self.out.write_all(&header)?;
+        self.write_padded(data)
+    }
+
+    /// Writes the two zero blocks that end an archive and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(&[0u8; BLOCK * 2])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn large_values_are_written_in_base_256() {
+        let mut field = [0u8; 12];
+        octal(&mut field, 0o777).unwrap();
+        assert_eq!(&field, b"00000000777\0");
+        let size = 8 << 30;
+        octal(&mut field, size).unwrap();
+        assert_eq!(field[0], 0x80);
+        let mut value = [0u8; 8];
+        value.copy_from_slice(&field[4..]);
+        assert_eq!(u64::from_be_bytes(value), size);
+        assert!(field[1..4].iter().all(|&b| b == 0));
+        assert!(octal(&mut [0u8; 8], u64::MAX).is_err());
+    }
+}
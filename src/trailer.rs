@@ -0,0 +1,114 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Starts the optional index at the end of an uncompressed archive. Decoding stops here.
+pub const MARKER: &str = "====MITO-INDEX====\n";
+
+/// Ends the archive after the index: the tag, then the index length as 16 digits.
+const TAIL_TAG: &str = "MITOIDX ";
+const TAIL_LEN: usize = TAIL_TAG.len() + 16 + 1;
+
+/// Where one entry, separator through closing newline, sits in the archive.
+pub struct Located {
+    pub path: String,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// The index and its tail, one `offset len path` line per entry.
+pub fn to_bytes(entries: &[Located]) -> Vec<u8> {
+    let mut index = MARKER.to_string();
+    for entry in entries {
+        index.push_str(&format!("{} {} {}\n", entry.offset, entry.len, entry.path));
+    }
+    let tail = format!("{}{:016}\n", TAIL_TAG, index.len());
+    index.push_str(&tail);
+    index.into_bytes()
+}
+
+fn parse(index: &[u8]) -> Option<Vec<Located>> {
+    let index = std::str::from_utf8(index).ok()?.strip_prefix(MARKER)?;
+    index
+        .lines()
+        .map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            Some(Located {
+                offset: fields.next()?.parse().ok()?,
+                len: fields.next()?.parse().ok()?,
+                path: fields.next()?.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Reads the index at the end of `file` and seeks back to the start. `None` if the archive
+/// has no index, and an error if `file` can't seek, like a pipe.
+pub fn read(file: &mut (impl Read + Seek)) -> io::Result<Option<Vec<Located>>> {
+    Ok(find(file)?.map(|(_, index)| index))
+}
+
+/// Like `read`, with the offset the index starts at, where the entries end.
+pub fn find(file: &mut (impl Read + Seek)) -> io::Result<Option<(u64, Vec<Located>)>> {
+    let size = file.seek(SeekFrom::End(0))?;
+    let mut found = None;
+    if size >= TAIL_LEN as u64 {
+        file.seek(SeekFrom::End(-(TAIL_LEN as i64)))?;
+        let mut tail = [0; TAIL_LEN];
+        file.read_exact(&mut tail)?;
+        let index_len = std::str::from_utf8(&tail)
+            .ok()
+            .and_then(|tail| tail.strip_prefix(TAIL_TAG))
+            .and_then(|len| len.trim_end().parse::<u64>().ok())
+            .filter(|len| len + TAIL_LEN as u64 <= size);
+        if let Some(index_len) = index_len {
+            let start = size - TAIL_LEN as u64 - index_len;
+            file.seek(SeekFrom::Start(start))?;
+            let mut index = vec![0; index_len as usize];
+            file.read_exact(&mut index)?;
+            found = parse(&index).map(|index| (start, index));
+        }
+    }
+    file.seek(SeekFrom::Start(0))?;
+    Ok(found)
+}
+
+/// Reads the given `(offset, len)` ranges of `inner` one after another, seeking between them.
+pub struct Ranges<R> {
+    inner: R,
+    ranges: VecDeque<(u64, u64)>,
+    left: u64,
+}
+
+impl<R: Read + Seek> Ranges<R> {
+    pub fn new(inner: R, ranges: impl IntoIterator<Item = (u64, u64)>) -> Ranges<R> {
+        Ranges {
+            inner,
+            ranges: ranges.into_iter().collect(),
+            left: 0,
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for Ranges<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.left == 0 {
+            match self.ranges.pop_front() {
+                Some((offset, len)) => {
+                    self.inner.seek(SeekFrom::Start(offset))?;
+                    self.left = len;
+                }
+                None => return Ok(0),
+            }
+        }
+        let want = self.left.min(buf.len() as u64) as usize;
+        let n = self.inner.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "archive is shorter than its index says",
+            ));
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
@@ -0,0 +1,307 @@
+// The filesystem `encode` walks and reads, behind a trait so that the walk can run over
+// something other than the real disk: `OsFs` for the command line, `MemoryFs` for synthetic
+// trees.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::{btime, owner, perms, sparse};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// What the walk needs to know about a path.
+pub struct Metadata {
+    pub kind: FileKind,
+    pub len: u64,
+    // Modification time in whole seconds since the epoch, where the platform has one.
+    pub mtime: Option<u64>,
+    // Birth time in whole seconds since the epoch, where the platform and filesystem keep one.
+    pub btime: Option<u64>,
+    // `(uid, gid)`, on unix only.
+    pub owner: Option<(u32, u32)>,
+    // Permission bits, on unix only.
+    pub perms: Option<u32>,
+}
+
+/// One entry of a directory listing. `kind` describes the entry itself, so a symlink is
+/// `FileKind::Symlink` whatever it points to.
+pub struct DirEntry {
+    pub path: PathBuf,
+    pub kind: FileKind,
+}
+
+impl DirEntry {
+    pub fn file_name(&self) -> &std::ffi::OsStr {
+        self.path.file_name().unwrap_or_default()
+    }
+}
+
+/// Read-only access to a tree. `Sync` so that entries can be read on several `--threads`.
+pub trait VfsReader: Sync {
+    /// The entries of `dir`, in no particular order.
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<DirEntry>>;
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>>;
+
+    /// The metadata of `path`, following symlinks.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Where the symlink at `path` points.
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// The holes of the file at `path` as `(offset, len)`, in order. None where the tree
+    /// can't tell.
+    fn holes(&self, path: &Path) -> io::Result<Vec<(u64, u64)>> {
+        let _ = path;
+        Ok(Vec::new())
+    }
+}
+
+/// The real filesystem.
+pub struct OsFs;
+
+fn kind_of(file_type: fs::FileType) -> FileKind {
+    if file_type.is_symlink() {
+        FileKind::Symlink
+    } else if file_type.is_dir() {
+        FileKind::Dir
+    } else {
+        FileKind::File
+    }
+}
+
+impl VfsReader for OsFs {
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        fs::read_dir(dir)?
+            .map(|entry| {
+                let entry = entry?;
+                Ok(DirEntry {
+                    path: entry.path(),
+                    kind: kind_of(entry.file_type()?),
+                })
+            })
+            .collect()
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = fs::metadata(path)?;
+        Ok(Metadata {
+            kind: kind_of(metadata.file_type()),
+            len: metadata.len(),
+            mtime: metadata
+                .modified()
+                .ok()
+                .map(|mtime| mtime.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())),
+            btime: btime::of(&metadata),
+            owner: owner::of(&metadata),
+            perms: perms::of(&metadata),
+        })
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    fn holes(&self, path: &Path) -> io::Result<Vec<(u64, u64)>> {
+        sparse::holes(&File::open(path)?)
+    }
+}
+
+enum Node {
+    File { content: Vec<u8>, mtime: u64 },
+    Dir,
+    Symlink(PathBuf),
+}
+
+/// A tree held in memory, built up with `add_file`, `add_dir` and `add_symlink`. Paths are
+/// relative to its root, which `.` and the empty path both name; parent directories are
+/// created as needed.
+#[derive(Default)]
+pub struct MemoryFs {
+    nodes: BTreeMap<PathBuf, Node>,
+}
+
+// Symlinks followed in a row before giving up, as for a loop.
+const MAX_LINKS: usize = 40;
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} doesn't exist", path.display()),
+    )
+}
+
+/// `path` without `.` components, with `..` taking off the component before it.
+fn lexical(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+            Component::ParentDir => {
+                normal.pop();
+            }
+            Component::Normal(name) => normal.push(name),
+        }
+    }
+    normal
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    pub fn add_file(
+        &mut self,
+        path: impl AsRef<Path>,
+        content: impl Into<Vec<u8>>,
+        mtime: u64,
+    ) -> &mut MemoryFs {
+        let content = content.into();
+        self.insert(path.as_ref(), Node::File { content, mtime })
+    }
+
+    pub fn add_dir(&mut self, path: impl AsRef<Path>) -> &mut MemoryFs {
+        self.insert(path.as_ref(), Node::Dir)
+    }
+
+    pub fn add_symlink(
+        &mut self,
+        path: impl AsRef<Path>,
+        target: impl AsRef<Path>,
+    ) -> &mut MemoryFs {
+        let target = target.as_ref().to_path_buf();
+        self.insert(path.as_ref(), Node::Symlink(target))
+    }
+
+    fn insert(&mut self, path: &Path, node: Node) -> &mut MemoryFs {
+        let path = lexical(path);
+        for dir in path.ancestors().skip(1) {
+            if !dir.as_os_str().is_empty() {
+                self.nodes.insert(dir.to_path_buf(), Node::Dir);
+            }
+        }
+        self.nodes.insert(path, node);
+        self
+    }
+
+    /// The path `path` names with every symlink in it followed, and its node. The root is a
+    /// directory without a node of its own.
+    fn resolve(&self, path: &Path) -> io::Result<(PathBuf, Option<&Node>)> {
+        let names = |path: &Path| -> VecDeque<OsString> {
+            path.components()
+                .map(|component| component.as_os_str().to_os_string())
+                .collect()
+        };
+        let mut rest = names(&lexical(path));
+        let mut resolved = PathBuf::new();
+        let mut links = 0;
+        while let Some(name) = rest.pop_front() {
+            let next = resolved.join(&name);
+            match self.nodes.get(&next) {
+                Some(Node::Symlink(target)) => {
+                    links += 1;
+                    if links > MAX_LINKS {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("too many levels of symlinks at {}", path.display()),
+                        ));
+                    }
+                    // Start over from the root with the target in place of the link.
+                    let mut target = names(&lexical(&resolved.join(target)));
+                    target.append(&mut rest);
+                    rest = target;
+                    resolved = PathBuf::new();
+                }
+                Some(_) => resolved = next,
+                None => return Err(not_found(path)),
+            }
+        }
+        let node = self.nodes.get(&resolved);
+        Ok((resolved, node))
+    }
+}
+
+fn kind(node: &Node) -> FileKind {
+    match node {
+        Node::File { .. } => FileKind::File,
+        Node::Dir => FileKind::Dir,
+        Node::Symlink(_) => FileKind::Symlink,
+    }
+}
+
+impl VfsReader for MemoryFs {
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<DirEntry>> {
+        let (resolved, node) = self.resolve(dir)?;
+        if !matches!(node, None | Some(Node::Dir)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} isn't a directory", dir.display()),
+            ));
+        }
+        Ok(self
+            .nodes
+            .iter()
+            .filter(|(path, _)| path.parent() == Some(&resolved))
+            .map(|(path, node)| DirEntry {
+                path: dir.join(path.file_name().unwrap_or_default()),
+                kind: kind(node),
+            })
+            .collect())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        match self.resolve(path)?.1 {
+            Some(Node::File { content, .. }) => Ok(Box::new(&content[..])),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} isn't a file", path.display()),
+            )),
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let (kind, len, mtime) = match self.resolve(path)?.1 {
+            Some(Node::File { content, mtime }) => {
+                (FileKind::File, content.len() as u64, Some(*mtime))
+            }
+            _ => (FileKind::Dir, 0, None),
+        };
+        Ok(Metadata {
+            kind,
+            len,
+            mtime,
+            btime: None,
+            owner: None,
+            perms: None,
+        })
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        let (dir, _) = self.resolve(path.parent().unwrap_or_else(|| Path::new("")))?;
+        match self
+            .nodes
+            .get(&dir.join(path.file_name().unwrap_or_default()))
+        {
+            Some(Node::Symlink(target)) => Ok(target.clone()),
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} isn't a symlink", path.display()),
+            )),
+            None => Err(not_found(path)),
+        }
+    }
+}
@@ -0,0 +1,311 @@
+// Archives cut into numbered volumes, `out.out.001`, `out.out.002` and so on, as `split -d`
+// or a transfer with a size limit leaves them. The volumes are read one after another as a
+// single archive, so a cut can fall anywhere, in the middle of an entry or of a compressed
+// stream. Under `--volumes`, naming the first volume pulls in the ones after it from the same
+// directory; a quoted glob names them all. A file that starts with an archive header of its
+// own isn't part of the archive before it, and ends the volumes there.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::glob::Glob;
+use crate::header;
+use crate::log;
+
+/// The volumes of an archive read as one file. An archive that isn't split is its only
+/// volume, and reads exactly like the file.
+pub struct Volumes {
+    // Every volume with its length, in order.
+    volumes: Vec<(PathBuf, u64)>,
+    current: usize,
+    file: File,
+    // Where in the whole archive reading has got to, across volumes.
+    pos: u64,
+}
+
+/// Opens the archive at `path`, with the volumes that follow it if `split` and it is the
+/// first of several, see [`find`].
+pub fn open(path: &Path, split: bool) -> io::Result<Volumes> {
+    let paths = find(path, split)?;
+    if let [only] = paths.as_slice() {
+        // Not split: errors and lengths are those of the file, which may be a pipe.
+        let file = File::open(only)?;
+        return Ok(Volumes {
+            volumes: vec![(only.clone(), 0)],
+            current: 0,
+            file,
+            pos: 0,
+        });
+    }
+    log::verbose(log::DECISIONS, || {
+        format!(
+            "reading {} volumes, {} to {}",
+            paths.len(),
+            paths[0].display(),
+            paths[paths.len() - 1].display()
+        )
+    });
+    let volumes = paths
+        .into_iter()
+        .map(|path| {
+            let len = fs::metadata(&path).map_err(|e| named(&path, e))?.len();
+            Ok((path, len))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let file = File::open(&volumes[0].0).map_err(|e| named(&volumes[0].0, e))?;
+    Ok(Volumes {
+        volumes,
+        current: 0,
+        file,
+        pos: 0,
+    })
+}
+
+fn named(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+/// `name` as a volume, `NAME.NNN` with at least two digits: its stem and number.
+fn volume_number(name: &str) -> Option<(&str, u64)> {
+    let (stem, digits) = name.rsplit_once('.')?;
+    if stem.is_empty() || digits.len() < 2 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((stem, digits.parse().ok()?))
+}
+
+/// The files in `dir`, by name.
+fn names_in(dir: &Path) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    Ok(names)
+}
+
+/// Whether the file at `path` starts with an archive header, so it is an archive rather than
+/// the rest of one.
+fn starts_archive(path: &Path) -> io::Result<bool> {
+    let mut magic = Vec::new();
+    File::open(path)
+        .map_err(|e| named(path, e))?
+        .take(header::MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    Ok(magic == header::MAGIC.as_bytes())
+}
+
+/// The files `path` stands for, in order. With `split`, the first of several `NAME.NNN`
+/// volumes, numbered with as many digits, stands for all of them. A name with `*` or `?` that
+/// doesn't exist as it is matches files next to it, which must be the volumes of one archive,
+/// ordered by number. Anything else is `path` alone. Fails on a gap in the numbering, and on
+/// a volume past the first. The volumes end before one that starts an archive of its own.
+pub fn find(path: &Path, split: bool) -> io::Result<Vec<PathBuf>> {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Ok(vec![path.to_path_buf()]),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let pattern = name.contains(['*', '?']) && !path.exists();
+    let mut volumes: Vec<(u64, String)> = if pattern {
+        let glob = Glob::new(&name);
+        let mut matched: Vec<_> = names_in(dir)?
+            .into_iter()
+            .filter(|candidate| glob.matches_path(candidate))
+            .collect();
+        if matched.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no volumes match {}", path.display()),
+            ));
+        }
+        // Volumes first, so the stem comes from one and the error names the stray file.
+        matched.sort();
+        matched.sort_by_key(|candidate| volume_number(candidate).is_none());
+        let stem = volume_number(&matched[0]).map(|(stem, _)| stem.to_string());
+        let mut volumes = Vec::new();
+        for candidate in &matched {
+            match (volume_number(candidate), &stem) {
+                (Some((other, n)), Some(stem)) if other == stem => {
+                    volumes.push((n, candidate.clone()))
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "{} matches {}, which isn't a volume NAME.NNN of {}",
+                            path.display(),
+                            candidate,
+                            stem.as_deref().unwrap_or("an archive")
+                        ),
+                    ))
+                }
+            }
+        }
+        volumes
+    } else {
+        let (stem, number) = match volume_number(&name) {
+            Some(volume) if split && path.is_file() => volume,
+            _ => return Ok(vec![path.to_path_buf()]),
+        };
+        let width = name.len() - stem.len() - 1;
+        let volumes: Vec<_> = names_in(dir)?
+            .into_iter()
+            .filter_map(|candidate| {
+                let (other, n) = volume_number(&candidate)?;
+                let same = other == stem && candidate.len() - other.len() - 1 == width;
+                same.then_some((n, candidate))
+            })
+            .collect();
+        if let Some((_, first)) = volumes.iter().filter(|(n, _)| *n < number).min() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} isn't the first volume, {} comes before it",
+                    path.display(),
+                    path.with_file_name(first).display()
+                ),
+            ));
+        }
+        volumes
+    };
+    volumes.sort();
+    for pair in volumes.windows(2) {
+        let ((before, earlier), (after, later)) = (&pair[0], &pair[1]);
+        if after - before > 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "volume {} of the archive is missing, between {} and {}",
+                    before + 1,
+                    path.with_file_name(earlier).display(),
+                    path.with_file_name(later).display()
+                ),
+            ));
+        }
+    }
+    let mut paths: Vec<_> = volumes
+        .into_iter()
+        .map(|(_, name)| path.with_file_name(name))
+        .collect();
+    if let Some(at) = (1..paths.len()).find(|&i| starts_archive(&paths[i]).unwrap_or(false)) {
+        log::warn(format!(
+            "{} starts an archive of its own, so it and the files after it aren't read as volumes of {}",
+            paths[at].display(),
+            paths[0].display()
+        ));
+        paths.truncate(at);
+    }
+    Ok(paths)
+}
+
+impl Volumes {
+    fn split(&self) -> bool {
+        self.volumes.len() > 1
+    }
+
+    /// The length of the whole archive.
+    pub fn size(&self) -> io::Result<u64> {
+        if !self.split() {
+            return Ok(self.file.metadata()?.len());
+        }
+        Ok(self.volumes.iter().map(|(_, len)| len).sum())
+    }
+
+    /// Whether the archive is in regular files, which can be read more than once.
+    pub fn is_file(&self) -> io::Result<bool> {
+        if !self.split() {
+            return Ok(self.file.metadata()?.is_file());
+        }
+        Ok(true)
+    }
+
+    fn open_volume(&mut self, i: usize) -> io::Result<()> {
+        let path = &self.volumes[i].0;
+        self.file = File::open(path).map_err(|e| named(path, e))?;
+        self.current = i;
+        Ok(())
+    }
+}
+
+impl Read for Volumes {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.file.read(buf)?;
+            if n > 0 || buf.is_empty() || self.current + 1 == self.volumes.len() {
+                self.pos += n as u64;
+                return Ok(n);
+            }
+            self.open_volume(self.current + 1)?;
+        }
+    }
+}
+
+impl Seek for Volumes {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if !self.split() {
+            self.pos = self.file.seek(pos)?;
+            return Ok(self.pos);
+        }
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.size()?.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the archive",
+            )
+        })?;
+        // Past the end is in the last volume, like seeking past the end of a file.
+        let mut start = 0;
+        let mut volume = self.volumes.len() - 1;
+        for (i, (_, len)) in self.volumes.iter().enumerate() {
+            if target < start + len {
+                volume = i;
+                break;
+            }
+            start += len;
+        }
+        if volume == self.volumes.len() - 1 {
+            start = self.volumes[..volume].iter().map(|(_, len)| len).sum();
+        }
+        if volume != self.current {
+            self.open_volume(volume)?;
+        }
+        self.file.seek(SeekFrom::Start(target - start))?;
+        self.pos = target;
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::Scratch;
+
+    #[test]
+    fn numbered_archives_are_volumes_only_when_asked_and_headerless() {
+        let scratch = Scratch::create("volumes").unwrap();
+        let first = scratch.path.join("snap.01");
+        fs::write(&first, "MITO/2 mode=plain\n====a|1|size=2==").unwrap();
+        fs::write(scratch.path.join("snap.02"), "==\nab\n").unwrap();
+        fs::write(scratch.path.join("snap.03"), "MITO/2 mode=plain\n").unwrap();
+        assert_eq!(find(&first, false).unwrap(), vec![first.clone()]);
+        let volumes = find(&first, true).unwrap();
+        assert_eq!(volumes, [first.clone(), scratch.path.join("snap.02")]);
+        let mut read = String::new();
+        open(&first, true)
+            .unwrap()
+            .read_to_string(&mut read)
+            .unwrap();
+        assert_eq!(read, "MITO/2 mode=plain\n====a|1|size=2====\nab\n");
+    }
+}
@@ -0,0 +1,465 @@
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use crate::header::{self, Header};
+use crate::separator::{self, Separator};
+use crate::spill::{Budget, Buffer};
+use crate::stack::{Base64Writer, ModeWriter};
+use crate::trailer::{self, Located};
+use crate::{normalize_stored, sparse, Alphabet, Encoding, HashScope, Mode};
+
+// Fixed explicitly rather than relying on `Compression::default()` staying the same.
+// `--level` overrides it.
+pub const COMPRESSION_LEVEL: u32 = 6;
+
+/// How much of each entry `Mode::Auto` inspects when deciding between plain and base64.
+pub const DEFAULT_AUTO_SAMPLE: usize = 8 * 1024;
+
+/// Treats `buffer` as binary if its first `sample` bytes contain a NUL or invalid UTF-8.
+fn looks_binary(buffer: &[u8], sample: usize) -> bool {
+    let sampled = &buffer[..buffer.len().min(sample)];
+    if sampled.contains(&0) {
+        return true;
+    }
+    match std::str::from_utf8(sampled) {
+        Ok(_) => false,
+        // The sample may end in the middle of a multi-byte character.
+        Err(e) => e.error_len().is_some() || sampled.len() == buffer.len(),
+    }
+}
+
+/// Whether a line of `buffer` would read back as a separator or an archive header, which a
+/// plain body can't hold. The last line counts with the newline the writer appends after it.
+fn has_separator_line(buffer: &[u8]) -> bool {
+    buffer.split_inclusive(|&b| b == b'\n').any(|line| {
+        let line = if line.ends_with(b"\n") {
+            Cow::Borrowed(line)
+        } else {
+            Cow::Owned([line, b"\n"].concat())
+        };
+        separator::is_separator(&line)
+            || (line.starts_with(header::MAGIC.as_bytes()) && Header::parse(&line).is_ok())
+    })
+}
+
+/// How `ArchiveWriter` encodes and hashes entries.
+#[derive(Clone, Copy)]
+pub struct WriterOptions {
+    // How much of each entry `Mode::Auto` inspects.
+    pub auto_sample: usize,
+    pub hash_scope: HashScope,
+    // zlib level of the compressed modes, 0-9.
+    pub level: u32,
+    // Append an index of entry offsets, in the uncompressed modes only.
+    pub index: bool,
+    // Record a hash of each entry's stored bytes too, for `verify --fast`.
+    pub stored_hash: bool,
+    // Flush the output after every entry, so a reader at the other end of a pipe sees each
+    // one as soon as it is written. Compressed modes pass on what zlib has produced so far.
+    pub flush_entries: bool,
+    // The alphabet of entry bodies and of `Mode::CompressedTxt`, recorded in the header.
+    pub alphabet: Alphabet,
+    // Break base64 into lines of this many characters, a multiple of 4, see `--wrap`.
+    pub wrap: Option<usize>,
+}
+
+impl Default for WriterOptions {
+    fn default() -> WriterOptions {
+        WriterOptions {
+            auto_sample: DEFAULT_AUTO_SAMPLE,
+            hash_scope: HashScope::Content,
+            level: COMPRESSION_LEVEL,
+            index: false,
+            stored_hash: false,
+            flush_entries: false,
+            alphabet: Alphabet::Standard,
+            wrap: None,
+        }
+    }
+}
+
+/// How one entry's content is packed before it is encoded, recorded as `codec=` when it
+/// isn't `Store`. Only the uncompressed modes pick a codec per entry; `Mode::CompressedBinary`
+/// and `Mode::CompressedTxt` compress the whole archive instead.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Codec {
+    Store,
+    Deflate,
+}
+
+impl Codec {
+    pub fn parse(s: &str) -> io::Result<Codec> {
+        match s {
+            "store" => Ok(Codec::Store),
+            "deflate" => Ok(Codec::Deflate),
+            "zstd" => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "zstd isn't available in this build, available codecs are store|deflate",
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("not support {}, available codecs are store|deflate", s),
+            )),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Store => "store",
+            Codec::Deflate => "deflate",
+        }
+    }
+}
+
+/// An entry's separator and framed body, ready to be written to the archive.
+pub(crate) struct Prepared {
+    path: String,
+    separator: String,
+    body: Buffer,
+    // The body goes in a raw section rather than the compressed stream.
+    raw: bool,
+    // Binary content in a `Mode::Plain` archive, which then isn't text any more.
+    binary: bool,
+    // The body's length before it was base64 encoded.
+    packed_len: u64,
+}
+
+impl Prepared {
+    /// The stored path.
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Whether the entry makes a plain archive binary.
+    pub(crate) fn binary(&self) -> bool {
+        self.binary
+    }
+
+    /// How many bytes the entry adds to the archive's stream, before any compression.
+    pub(crate) fn len(&self) -> u64 {
+        (self.separator.len() + self.body.len() + 1) as u64
+    }
+
+    /// Roughly how many bytes the entry would take in a plain archive, its body as it is.
+    pub(crate) fn plain_len(&self) -> u64 {
+        self.separator.len() as u64 + self.packed_len + 1
+    }
+}
+
+/// Hashes, packs with `codec` and encodes one entry stored as `stored`. `fields` carries
+/// the separator fields that don't come from the content, like `mtime=`; the rest are
+/// filled in here. With `holes=`, `buffer` is the content with the holes left out. With
+/// `section=raw`, the content is kept as it is for a raw section, where the mode has them.
+/// The packed and encoded copies count against `budget`, if there is one.
+pub(crate) fn prepare(
+    stored: &Path,
+    buffer: Buffer,
+    mode: Mode,
+    options: WriterOptions,
+    codec: Codec,
+    fields: Separator,
+    budget: Option<&Arc<Budget>>,
+) -> io::Result<Prepared> {
+    let encoding = match mode {
+        // Packed content is binary, so it is always base64 encoded, and so is sparse content,
+        // whose body starts over after each hole.
+        Mode::Plain | Mode::Auto if codec != Codec::Store || !fields.holes.is_empty() => {
+            Some(Encoding::Base64)
+        }
+        Mode::Auto if looks_binary(&buffer, options.auto_sample) => Some(Encoding::Base64),
+        // Base64, so that decode doesn't take the line for the start of another entry.
+        Mode::Plain | Mode::Auto if has_separator_line(&buffer) => Some(Encoding::Base64),
+        Mode::Auto => Some(Encoding::Plain),
+        _ => None,
+    };
+    // Only the compressed modes have raw sections, and a raw body can't be packed or sparse.
+    let binary = mode == Mode::Plain && looks_binary(&buffer, options.auto_sample);
+    let compressed = matches!(mode, Mode::CompressedBinary | Mode::CompressedTxt);
+    let raw = fields.raw && compressed && codec == Codec::Store && fields.holes.is_empty();
+    let stored = normalize_stored(stored).to_string_lossy().into_owned();
+    let mut hasher = options.hash_scope.hasher(&stored);
+    // The hash and size are of the content, holes and all.
+    sparse::for_each_chunk(&buffer, &fields.holes, |chunk| hasher.write(chunk));
+    let mut separator = Separator {
+        path: stored,
+        hash: hasher.finish().to_string(),
+        encoding,
+        // Recorded so decode can check for free space before extracting anything.
+        size: Some(sparse::content_len(&buffer, &fields.holes)),
+        codec,
+        raw,
+        ..fields
+    };
+    let packed = match codec {
+        Codec::Store => buffer,
+        Codec::Deflate => {
+            let what = || format!("the deflated {}", separator.path);
+            let packed = Buffer::write_with(buffer.len() as u64, budget, what, |out| {
+                let mut compressed = ZlibEncoder::new(out, Compression::new(options.level));
+                compressed.write_all(&buffer)?;
+                compressed.finish()?;
+                Ok(())
+            })?;
+            drop(buffer);
+            packed
+        }
+    };
+    let packed_len = packed.len() as u64;
+    let body = match encoding.unwrap_or_else(|| Encoding::for_mode(&mode)) {
+        Encoding::Base64 if !raw => {
+            let what = || format!("the base64 of {}", separator.path);
+            let len = (packed.len() as u64).div_ceil(3) * 4;
+            Buffer::write_with(len, budget, what, |out| {
+                let mut encoded = Base64Writer::with_wrap(out, options.alphabet, options.wrap);
+                encoded.write_all(&packed)?;
+                encoded.finish()?;
+                Ok(())
+            })?
+        }
+        _ => packed,
+    };
+    if options.stored_hash {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(&body);
+        separator.body_hash = Some(hasher.finish().to_string());
+    }
+    Ok(Prepared {
+        separator: separator.to_line(),
+        path: separator.path,
+        body,
+        raw,
+        binary,
+        packed_len,
+    })
+}
+
+/// Waits out a `W` that refuses writes with `WouldBlock`, like a nonblocking pipe that is
+/// full, so that `write_all` over it slows down to the reader's pace instead of failing.
+pub struct BlockingWriter<W: Write> {
+    inner: W,
+}
+
+// How long to wait before retrying a write that would block.
+const RETRY_DELAY: Duration = Duration::from_millis(1);
+
+impl<W: Write> BlockingWriter<W> {
+    pub fn new(inner: W) -> BlockingWriter<W> {
+        BlockingWriter { inner }
+    }
+
+    fn retry<T>(&mut self, mut op: impl FnMut(&mut W) -> io::Result<T>) -> io::Result<T> {
+        loop {
+            match op(&mut self.inner) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(RETRY_DELAY),
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for BlockingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.retry(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.retry(|inner| inner.flush())
+    }
+}
+
+/// Writes a mito archive entry by entry, from any source rather than a directory walk.
+///
+/// The header is written on creation and every `add` frames, hashes and encodes one entry.
+/// Every mode streams to `out` as entries are added, so only the entry being written is held
+/// in memory; the compressed modes are only complete after `finish`. With
+/// `WriterOptions::index`, the uncompressed modes end with an index of where every entry
+/// starts, so a single entry can be read without scanning the archive.
+pub struct ArchiveWriter<W: Write> {
+    // Only `None` while a raw section is being written.
+    sink: Option<ModeWriter<W>>,
+    mode: Mode,
+    options: WriterOptions,
+    // Bytes written to an uncompressed archive so far, and where each entry went.
+    written: u64,
+    index: Vec<Located>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(out: W, mode: Mode) -> io::Result<ArchiveWriter<W>> {
+        ArchiveWriter::with_options(out, mode, WriterOptions::default())
+    }
+
+    pub fn with_options(
+        out: W,
+        mode: Mode,
+        options: WriterOptions,
+    ) -> io::Result<ArchiveWriter<W>> {
+        ArchiveWriter::with_root(out, mode, options, None)
+    }
+
+    /// Like `with_options`, recording `root` in the header as the directory that was encoded.
+    pub fn with_root(
+        out: W,
+        mode: Mode,
+        options: WriterOptions,
+        root: Option<String>,
+    ) -> io::Result<ArchiveWriter<W>> {
+        let level = Compression::new(options.level);
+        let sink = ModeWriter::with_wrap(out, mode, level, options.alphabet, options.wrap);
+        let mut writer = ArchiveWriter {
+            sink: Some(sink),
+            mode,
+            options,
+            written: 0,
+            index: Vec::new(),
+        };
+        let mut header = Header::new(mode, options.hash_scope);
+        header.alphabet = options.alphabet;
+        header.root = root;
+        writer.write_all(header.to_line().as_bytes())?;
+        Ok(writer)
+    }
+
+    fn sink(&mut self) -> &mut ModeWriter<W> {
+        self.sink.as_mut().expect("a raw section failed to write")
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if let ModeWriter::Identity(_) = self.sink() {
+            self.written += bytes.len() as u64;
+        }
+        self.sink().write_all(bytes)
+    }
+
+    /// Adds an entry stored as `path` with the content read from `reader`.
+    pub fn add(&mut self, path: &Path, reader: &mut impl Read) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer)?;
+        let prepared = prepare(
+            path,
+            Buffer::new(buffer),
+            self.mode,
+            self.options,
+            Codec::Store,
+            Separator::new(""),
+            None,
+        )?;
+        self.add_prepared(&prepared)
+    }
+
+    pub(crate) fn add_prepared(&mut self, prepared: &Prepared) -> io::Result<()> {
+        let offset = self.written;
+        self.write_all(prepared.separator.as_bytes())?;
+        if prepared.raw {
+            let sink = self.sink.take().expect("a raw section failed to write");
+            let level = Compression::new(self.options.level);
+            self.sink = Some(sink.raw_section(&prepared.body, level)?);
+        } else {
+            self.write_all(&prepared.body)?;
+        }
+        self.write_all(b"\n")?;
+        if self.options.index {
+            self.index.push(Located {
+                path: prepared.path.clone(),
+                offset,
+                len: self.written - offset,
+            });
+        }
+        if self.options.flush_entries {
+            self.sink().flush_output()?;
+        }
+        Ok(())
+    }
+
+    /// Ends the compressed stream, or writes the index, and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(ModeWriter::Identity(out)) = &mut self.sink {
+            if self.options.index {
+                out.write_all(&trailer::to_bytes(&self.index))?;
+            }
+        }
+        self.sink
+            .take()
+            .expect("a raw section failed to write")
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A slow sink: takes at most 3 bytes a call, refuses every other call with
+    /// `WouldBlock`, and counts its flushes.
+    #[derive(Default)]
+    struct Trickle {
+        received: Rc<RefCell<Vec<u8>>>,
+        calls: usize,
+        flushes: Rc<RefCell<usize>>,
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.calls += 1;
+            if self.calls.is_multiple_of(2) {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(3);
+            self.received.borrow_mut().extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            *self.flushes.borrow_mut() += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn short_and_blocked_writes_lose_nothing() {
+        let entries: [(&str, &[u8]); 2] = [("a.txt", b"first entry\n"), ("b.bin", &[0, 1, 2, 255])];
+        for mode in [Mode::Plain, Mode::CompressedBinary] {
+            let options = WriterOptions {
+                flush_entries: true,
+                ..WriterOptions::default()
+            };
+            let mut reference = ArchiveWriter::with_options(Vec::new(), mode, options).unwrap();
+            let trickle = Trickle::default();
+            let (received, flushes) = (trickle.received.clone(), trickle.flushes.clone());
+            let sink = BlockingWriter::new(trickle);
+            let mut slow = ArchiveWriter::with_options(sink, mode, options).unwrap();
+            for (i, &(path, content)) in entries.iter().enumerate() {
+                reference.add(Path::new(path), &mut &content[..]).unwrap();
+                slow.add(Path::new(path), &mut &content[..]).unwrap();
+                // Flushed after every entry, so the reader already has it.
+                assert_eq!(*flushes.borrow(), i + 1);
+                if mode == Mode::Plain {
+                    let so_far = String::from_utf8_lossy(&received.borrow()).into_owned();
+                    assert!(so_far.contains(&format!("===={}|", path)));
+                }
+            }
+            slow.finish().unwrap();
+            assert_eq!(*received.borrow(), reference.finish().unwrap());
+        }
+    }
+
+    #[test]
+    fn header_and_separator_lines_need_escaping() {
+        assert!(has_separator_line(b"before\nMITO/2 mode=plain\nafter\n"));
+        assert!(has_separator_line(b"MITO/2 mode=plain"));
+        assert!(has_separator_line(b"====a.txt|123====\n"));
+        assert!(!has_separator_line(b"MITO/ is a name\n"));
+        assert!(!has_separator_line(b"plain text\n"));
+    }
+}
@@ -0,0 +1,207 @@
+use std::io::{self, Write};
+
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL: u32 = 0x0605_4b50;
+// 2.0, the first version with deflate and directories.
+const VERSION: u16 = 20;
+// Made by unix, which says the upper half of the external attributes is a mode.
+const MADE_BY: u16 = 3 << 8 | VERSION;
+// Bit 11: names are UTF-8.
+const FLAGS: u16 = 1 << 11;
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+// The extended timestamp extra field, with just the modification time.
+const TIMESTAMP: u16 = 0x5455;
+
+const REGULAR: u32 = 0o100_000;
+const SYMLINK: u32 = 0o120_000;
+
+/// Writes a zip archive, deflating each entry unless that doesn't make it smaller. No zip64,
+/// so an archive stops at 4 GiB and 65535 entries.
+pub struct ZipWriter<W: Write> {
+    out: W,
+    level: Compression,
+    offset: u64,
+    central: Vec<u8>,
+    entries: u64,
+}
+
+fn too_large(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} needs zip64, which isn't supported", what),
+    )
+}
+
+fn crc(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+fn u32_of(value: u64, what: &str) -> io::Result<u32> {
+    if value > u64::from(u32::MAX) {
+        return Err(too_large(what));
+    }
+    Ok(value as u32)
+}
+
+/// `(year, month, day, seconds into the day)` of unix time `secs`, after Howard Hinnant's
+/// `civil_from_days`.
+fn civil(secs: u64) -> (u64, u64, u64, u64) {
+    let days = secs / 86_400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day, secs % 86_400)
+}
+
+/// `(time, date)` in MS-DOS format, in UTC. Before 1980, which DOS can't represent, and
+/// without an mtime, it is 1980-01-01 00:00.
+fn dos_time(mtime: Option<u64>) -> (u16, u16) {
+    match mtime.map(civil) {
+        Some((year, month, day, secs)) if (1980..2108).contains(&year) => {
+            let time = (secs / 3600) << 11 | (secs / 60 % 60) << 5 | (secs % 60 / 2);
+            let date = (year - 1980) << 9 | month << 5 | day;
+            (time as u16, date as u16)
+        }
+        _ => (0, 1 << 5 | 1),
+    }
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(out: W, level: Compression) -> ZipWriter<W> {
+        ZipWriter {
+            out,
+            level,
+            offset: 0,
+            central: Vec::new(),
+            entries: 0,
+        }
+    }
+
+    /// Appends a regular file named `name` (using `/` separators) with permission bits
+    /// `perms`.
+    pub fn append(
+        &mut self,
+        name: &str,
+        data: &[u8],
+        perms: u32,
+        mtime: Option<u64>,
+    ) -> io::Result<()> {
+        let mut deflated = DeflateEncoder::new(Vec::new(), self.level);
+        deflated.write_all(data)?;
+        let deflated = deflated.finish()?;
+        let (stored, method) = if deflated.len() < data.len() {
+            (&deflated[..], DEFLATED)
+        } else {
+            (data, STORED)
+        };
+        let crc = crc(data);
+        let len = data.len() as u64;
+        self.entry(name, stored, method, crc, len, REGULAR | perms, mtime)
+    }
+
+    /// Appends a symlink named `name` pointing to `target`, stored as its content the way
+    /// unix zip tools do.
+    pub fn append_symlink(
+        &mut self,
+        name: &str,
+        target: &str,
+        mtime: Option<u64>,
+    ) -> io::Result<()> {
+        let target = target.as_bytes();
+        let crc = crc(target);
+        let len = target.len() as u64;
+        self.entry(name, target, STORED, crc, len, SYMLINK | 0o777, mtime)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn entry(
+        &mut self,
+        name: &str,
+        stored: &[u8],
+        method: u16,
+        crc: u32,
+        len: u64,
+        mode: u32,
+        mtime: Option<u64>,
+    ) -> io::Result<()> {
+        let compressed = u32_of(stored.len() as u64, name)?;
+        let len = u32_of(len, name)?;
+        let offset = u32_of(self.offset, "an archive over 4 GiB")?;
+        if self.entries == u64::from(u16::MAX) {
+            return Err(too_large("an archive of more than 65535 entries"));
+        }
+        let name = name.as_bytes();
+        let (time, date) = dos_time(mtime);
+        let mut extra = Vec::new();
+        if let Some(mtime) = mtime {
+            extra.extend_from_slice(&TIMESTAMP.to_le_bytes());
+            extra.extend_from_slice(&5u16.to_le_bytes());
+            // Bit 0: the modification time is present.
+            extra.push(1);
+            extra.extend_from_slice(&(mtime.min(u64::from(u32::MAX)) as u32).to_le_bytes());
+        }
+        // What the local and the central header share, from the version needed on.
+        let mut common = Vec::new();
+        for field in &[VERSION, FLAGS, method, time, date] {
+            common.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in &[crc, compressed, len] {
+            common.extend_from_slice(&field.to_le_bytes());
+        }
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        common.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+
+        let mut local = LOCAL_HEADER.to_le_bytes().to_vec();
+        local.extend_from_slice(&common);
+        local.extend_from_slice(name);
+        local.extend_from_slice(&extra);
+        self.out.write_all(&local)?;
+        self.out.write_all(stored)?;
+        self.offset += (local.len() + stored.len()) as u64;
+
+        self.central
+            .extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+        self.central.extend_from_slice(&MADE_BY.to_le_bytes());
+        self.central.extend_from_slice(&common);
+        // No comment, on disk 0, no internal attributes.
+        self.central.extend_from_slice(&[0; 6]);
+        self.central.extend_from_slice(&(mode << 16).to_le_bytes());
+        self.central.extend_from_slice(&offset.to_le_bytes());
+        self.central.extend_from_slice(name);
+        self.central.extend_from_slice(&extra);
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Writes the central directory that ends an archive and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let offset = u32_of(self.offset, "an archive over 4 GiB")?;
+        let size = u32_of(self.central.len() as u64, "an archive over 4 GiB")?;
+        self.out.write_all(&self.central)?;
+        let mut end = END_OF_CENTRAL.to_le_bytes().to_vec();
+        // This disk and the disk the central directory starts on.
+        end.extend_from_slice(&[0; 4]);
+        end.extend_from_slice(&(self.entries as u16).to_le_bytes());
+        end.extend_from_slice(&(self.entries as u16).to_le_bytes());
+        end.extend_from_slice(&size.to_le_bytes());
+        end.extend_from_slice(&offset.to_le_bytes());
+        // No comment.
+        end.extend_from_slice(&[0; 2]);
+        self.out.write_all(&end)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
@@ -0,0 +1,40 @@
+// Runs the `mito` binary, for what only shows from outside: exit codes and stderr.
+
+use std::process::{Command, Output};
+
+fn mito(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mito"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn no_command_is_a_usage_error() {
+    let output = mito(&[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("usage: mito"));
+}
+
+#[test]
+fn unknown_command_is_a_usage_error() {
+    let output = mito(&["encdoe"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("unknown command `encdoe`"), "{}", stderr);
+}
+
+#[test]
+fn unknown_option_is_a_usage_error() {
+    let output = mito(&["decode", "--no-such-flag"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr, "mito: unknown option `--no-such-flag`\n");
+}
+
+#[test]
+fn failed_command_exits_with_1() {
+    let output = mito(&["list", "/nonexistent/archive.out"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("mito: "));
+}